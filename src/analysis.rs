@@ -0,0 +1,1182 @@
+// Copyright 2022-2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+
+use chrono::{Datelike, DateTime, Duration, Local, NaiveDate, NaiveTime, Utc, Weekday};
+use num_traits::cast::FromPrimitive;
+
+use crate::anomalies::Anomalies;
+use crate::bucketing::{add_co_online_dates, add_event_counts, build_daily_buckets, discard_inactive_online_counts, register_bucket_coverage_for_range, register_bucket_dates_for_range, remove_present_dates, SessionBucketer};
+use crate::config::{Configuration, InputFormat, LabelStyle, NormalizationBaseline, NormalizationMode, OutputFormat, PathList, RecommendationRank, RecommendationSort, SelfActivityTable, WarningsFormat};
+use crate::constants::{HOURS_PER_DAY, MILLISECONDS_PER_HOUR, MINUTES_PER_DAY, MINUTES_PER_HOUR};
+use crate::dto::{AnalysisRange, BucketValue, DateSample, EventTypeNames, Row, TimeSpan, VrcxStartStopEvent, VrcxStartStopEventType};
+use crate::error::Error;
+use crate::json::JsonValue;
+use crate::layout::BucketLayout;
+use crate::names::{user_ids_by_display_name, DisplayNames};
+use crate::profile;
+use crate::output::{bucket_output_values, DAY_BLOCK_NAMES, difference_output_values, low_confidence_flags, smooth_output_values, stale_weekdays, NumberFormat, TableStyle, ValueGrid, ValueKind};
+use crate::source::{CsvSource, EventData, EventSource, SqliteSource};
+use crate::uptime::{ClampStats, VrcxUptime};
+
+/// how far in the future an event can be dated before we suspect something is wrong, to allow for some clock skew
+const FUTURE_TIMESTAMP_TOLERANCE: Duration = Duration::minutes(5);
+
+/// when morning, afternoon, evening, and night start if `day_block_start_hours` isn't set
+const DEFAULT_DAY_BLOCK_START_HOURS: [u32; 4] = [6, 12, 18, 22];
+
+/// how every VRChat user id starts, which tells them apart from display names in `friend_ids`
+const USER_ID_PREFIX: &str = "usr_";
+
+/// a friend id allowlist, where `None` allows everyone
+type FriendIds = Option<HashSet<String>>;
+
+/// where to read VRCX events from
+#[derive(Clone)]
+pub enum Input {
+    Sqlite {
+        vrcx_db_paths: Vec<String>,
+    },
+    Csv {
+        online_offline_path: String,
+        all_events_path: Option<String>,
+    },
+}
+
+/// a series of overlapping date ranges to output a grid for each of, such as for animating how schedules shift
+#[derive(Clone)]
+pub struct SlidingWindows {
+    /// how many weeks each window covers
+    pub window_weeks: u32,
+    /// how many weeks apart consecutive windows start
+    pub step_weeks: u32,
+    /// the directory each window's grid is written to, as a numbered file
+    pub output_dir: String,
+}
+
+/// a second copy of the output with a stricter `minimum_bucket_activations`, so thinly sampled buckets are blanked out
+#[derive(Clone)]
+pub struct FilteredOutput {
+    pub output_file: String,
+    pub minimum_bucket_activations: u32,
+}
+
+/// Everything derived from the config, validated and with defaults filled in. This is built once, and can be used to run
+/// the analysis as many times as needed.
+#[derive(Clone)]
+pub struct Settings {
+    pub your_user_id: String,
+    pub input: Input,
+    /// copy the database before reading it if its write-ahead log is at least this large
+    pub wal_snapshot_threshold_bytes: Option<u64>,
+    /// what the online/offline `type` column values mean
+    pub event_type_names: EventTypeNames,
+    pub friend_ids: FriendIds,
+    /// treat `friend_ids` entries that aren't `usr_` ids as display names, to be looked up in the online/offline events
+    pub friend_ids_by_display_name: bool,
+    /// how much each friend counts toward a bucket, where friends not listed count as 1
+    pub friend_weights: HashMap<String, f64>,
+    /// old user ids mapped to the id of the account that replaced them. Every id elsewhere in the settings is already
+    /// canonical.
+    pub user_id_aliases: HashMap<String, String>,
+    /// user id to ignore regardless of `friend_ids`
+    pub excluded_user_id: Option<String>,
+    /// friend sets A and B, if we're comparing two friend sets instead of using `friend_ids`
+    pub compare_friend_ids: Option<(FriendIds, FriendIds)>,
+    /// two friends to find co-online times for, instead of using `friend_ids`. Each set holds a single id.
+    pub overlap_friend_ids: Option<(FriendIds, FriendIds)>,
+    /// a reference friend whose online dates are dropped from every bucket, so the grid only covers dates they were
+    /// absent. The set holds a single id.
+    pub absent_friend_ids: FriendIds,
+    /// error out instead of warning if a friend filter matches nobody
+    pub fail_on_unmatched_friend_ids: bool,
+    /// count this table's events instead of friend sessions, to measure your own activity
+    pub self_activity_table: Option<SelfActivityTable>,
+    pub vrcx_running_detection_threshold: Duration,
+    /// skip uptime detection and treat VRCX as running from the first event to the last
+    pub assume_always_running: bool,
+    /// how much of a bucket VRCX has to be running for, from 0 to 1, for the date to count as active
+    pub partial_bucket_threshold: f64,
+    /// if set, each bucket only keeps a sample of this many dates, and its date counts are estimated
+    pub max_tracked_dates: Option<usize>,
+    /// events this soon after the last event kept are dropped before detecting VRCX uptime
+    pub event_collapse_window: Option<Duration>,
+    pub extend_final_session_to_now: bool,
+    pub bucket_duration_minutes: u32,
+    pub align_to_hour: bool,
+    pub bucket_layout: BucketLayout,
+    pub weight_by_overlap: bool,
+    pub closed_bucket_boundaries: bool,
+    /// give an offline event with no online before it a session starting when VRCX started
+    pub infer_session_starts: bool,
+    pub normalize: NormalizationMode,
+    pub normalization_baseline: NormalizationBaseline,
+    pub analysis_range: AnalysisRange,
+    pub clamp_sessions_to_range: bool,
+    /// leave out everything from the current, incomplete calendar week
+    pub exclude_current_week: bool,
+    /// sessions longer than this are discarded
+    pub maximum_session_duration: Option<Duration>,
+    /// how far back from the latest VRCX event friend sessions ramp linearly from counting for nothing up to counting fully
+    pub recency_ramp: Option<Duration>,
+    /// weight each date by its age, halving every this long, in both the friend count and the number of dates
+    pub recency_half_life: Option<Duration>,
+    pub minimum_bucket_activations: u32,
+    pub low_confidence_threshold: Option<u32>,
+    pub smoothing_radius: Option<usize>,
+    pub no_data_returns_zero: bool,
+    pub print_statistics: bool,
+    /// below this many VRCX events, the statistics are too noisy to be worth printing
+    pub statistics_minimum_events: usize,
+    /// the start and end hour of the part of each day the uptime statistics are limited to, which may wrap past midnight
+    pub stats_hours: Option<(u32, u32)>,
+    pub print_runtime: bool,
+    pub output_format: OutputFormat,
+    /// the hour each of the morning, afternoon, evening, and night blocks starts at
+    pub day_block_start_hours: Vec<u32>,
+    /// how long a window `output_format = "highlights"` looks for
+    pub highlight_window: Duration,
+    /// how far a bucket's value can be from the first value of a run for `output_format = "ranges"` to merge them
+    pub range_tolerance: f64,
+    pub transpose: bool,
+    pub time_format_12h: bool,
+    pub label_style: LabelStyle,
+    pub decimal_separator: char,
+    pub decimal_places: Option<usize>,
+    pub split_by_month: bool,
+    /// split the history into this many contiguous date ranges, with a grid for each
+    pub chunk_count: Option<u32>,
+    pub sliding_windows: Option<SlidingWindows>,
+    /// where to write the grid instead of stdout
+    pub output_file: Option<String>,
+    /// where to write the filtered copy of the grid, if one is wanted
+    pub filtered_output: Option<FilteredOutput>,
+    /// where to write a JSON bundle of the config, statistics, and bucket grid, on top of the usual output
+    pub bundle_file: Option<String>,
+    /// the config as it was given, for the bundle
+    pub config_table: toml::Table,
+    pub recommendation_count: Option<usize>,
+    pub recommendation_weekdays: Option<Vec<Weekday>>,
+    /// buckets with a lower output value than this are never recommended
+    pub recommend_min_friends: Option<f64>,
+    /// a weekday with no VRCX activity for this long is flagged as stale and not recommended
+    pub stale_weekday_threshold: Option<Duration>,
+    pub recommendation_sort: RecommendationSort,
+    pub recommendation_rank: RecommendationRank,
+    /// where to write the iCalendar export, and the value a bucket must exceed to be included
+    pub calendar_export: Option<(String, f64)>,
+    /// where to write the per-friend iCalendar export, and the value a bucket of a friend's own grid must exceed
+    pub friend_calendar_export: Option<(String, f64)>,
+    pub verbose: bool,
+    pub warnings_format: WarningsFormat,
+    /// print generated SQL to stderr. This comes from the `--print-sql` flag rather than the config.
+    pub print_sql: bool,
+    /// cross-check session clamping against a brute-force implementation
+    pub self_check: bool,
+    /// panic on data that makes no sense, instead of skipping it with a warning
+    pub strict: bool,
+}
+
+impl Settings {
+    pub fn from_config(config: Configuration) -> Result<Self, Error> {
+        let align_to_hour = config.align_to_hour.unwrap_or(false);
+        let bucket_layout = if align_to_hour {
+            if config.bucket_duration_minutes == 0 || config.bucket_duration_minutes > MINUTES_PER_DAY {
+                return Err(Error::ConfigInvalid(format!("bucket_duration_minutes ({}) must be between 1 and {MINUTES_PER_DAY}", config.bucket_duration_minutes)));
+            }
+            BucketLayout::aligned_to_hour(config.bucket_duration_minutes)
+        } else {
+            if config.bucket_duration_minutes == 0 || !MINUTES_PER_DAY.is_multiple_of(config.bucket_duration_minutes) {
+                return Err(Error::ConfigInvalid(format!("bucket_duration_minutes ({}) does not perfectly divide a day. Set align_to_hour to allow this.", config.bucket_duration_minutes)));
+            }
+            BucketLayout::uniform(config.bucket_duration_minutes)
+        };
+        let analysis_range = AnalysisRange {
+            start: config.start_time.as_deref().map(|t| parse_config_time("start_time", t)).transpose()?,
+            end: config.end_time.as_deref().map(|t| parse_config_time("end_time", t)).transpose()?,
+        };
+        let recommendation_weekdays: Option<Vec<Weekday>> = config.recommendation_weekdays.as_ref()
+            .map(|weekdays| weekdays.iter()
+                .map(|weekday| weekday.parse::<Weekday>().map_err(|_| Error::ConfigInvalid(format!("recommendation_weekdays entry \"{weekday}\" is not a weekday"))))
+                .collect::<Result<_, _>>())
+            .transpose()?;
+        if let Some(recommend_min_friends) = config.recommend_min_friends.filter(|recommend_min_friends| !recommend_min_friends.is_finite()) {
+            return Err(Error::ConfigInvalid(format!("recommend_min_friends ({recommend_min_friends}) must be a number")));
+        }
+        // the strings VRCX itself writes
+        let online_event_types = config.online_event_types.unwrap_or_else(|| vec!["Online".to_owned()]);
+        let offline_event_types = config.offline_event_types.unwrap_or_else(|| vec!["Offline".to_owned()]);
+        if online_event_types.is_empty() || offline_event_types.is_empty() {
+            return Err(Error::ConfigInvalid("online_event_types and offline_event_types can't be empty".to_owned()));
+        }
+        if let Some(event_type) = online_event_types.iter().find(|event_type| offline_event_types.contains(event_type)) {
+            return Err(Error::ConfigInvalid(format!("\"{event_type}\" is in both online_event_types and offline_event_types")));
+        }
+        let user_id_aliases = config.user_id_aliases.unwrap_or_default();
+        if let Some((old_user_id, user_id)) = user_id_aliases.iter().find(|(_, user_id)| user_id_aliases.contains_key(*user_id)) {
+            return Err(Error::ConfigInvalid(format!("user_id_aliases maps {old_user_id} to {user_id}, which is itself an alias. Map it straight to the id it ends up at instead")));
+        }
+        // let the id lists below use either the old or new id for a migrated friend
+        let canonical_user_id = |user_id: String| user_id_aliases.get(&user_id).cloned().unwrap_or(user_id);
+        let canonical_user_ids = |user_ids: HashSet<String>| user_ids.into_iter().map(canonical_user_id).collect::<HashSet<String>>();
+        let compare_friend_ids = match (config.compare_friend_ids_a, config.compare_friend_ids_b) {
+            (Some(compare_friend_ids_a), Some(compare_friend_ids_b)) => Some((Some(canonical_user_ids(compare_friend_ids_a)), Some(canonical_user_ids(compare_friend_ids_b)))),
+            (None, None) => None,
+            _ => return Err(Error::ConfigInvalid("compare_friend_ids_a and compare_friend_ids_b must be set together".to_owned())),
+        };
+        let overlap_friend_ids = match config.overlap_friend_ids {
+            Some(overlap_friend_ids) => match <[String; 2]>::try_from(overlap_friend_ids).map(|friend_ids| friend_ids.map(canonical_user_id)) {
+                Ok([friend_id_a, friend_id_b]) if friend_id_a != friend_id_b => Some((Some(HashSet::from([friend_id_a])), Some(HashSet::from([friend_id_b])))),
+                _ => return Err(Error::ConfigInvalid("overlap_friend_ids must be exactly two different friend ids".to_owned())),
+            },
+            None => None,
+        };
+        let absent_friend_ids: FriendIds = config.absent_friend_id.map(|absent_friend_id| HashSet::from([canonical_user_id(absent_friend_id)]));
+        let friend_ids = match config.friend_ids_file.as_deref().map(read_friend_ids_file).transpose()? {
+            // both are allowlists, so combining them means allowing anyone listed in either
+            Some(file_friend_ids) => Some(config.friend_ids.unwrap_or_default().into_iter().chain(file_friend_ids).collect()),
+            None => config.friend_ids,
+        }.map(canonical_user_ids);
+        let friend_weights: HashMap<String, f64> = config.friend_weights.unwrap_or_default().into_iter()
+            .map(|(friend_id, weight)| (canonical_user_id(friend_id), weight))
+            .collect();
+        if let Some((friend_id, weight)) = friend_weights.iter().find(|(_, weight)| !weight.is_finite() || **weight < 0.0) {
+            return Err(Error::ConfigInvalid(format!("friend_weights for {friend_id} is {weight}, but weights must be zero or more")));
+        }
+        let maximum_session_duration = config.maximum_session_hours
+            .map(|hours| {
+                i64::from_f64(hours * f64::from(MILLISECONDS_PER_HOUR))
+                    .filter(|milliseconds| *milliseconds > 0)
+                    .map(Duration::milliseconds)
+                    .ok_or_else(|| Error::ConfigInvalid(format!("maximum_session_hours ({hours}) must be a positive number of hours")))
+            })
+            .transpose()?;
+        let assume_always_running = config.assume_always_running.unwrap_or(false);
+        let vrcx_running_detection_threshold_minutes = match config.vrcx_running_detection_threshold_minutes {
+            Some(vrcx_running_detection_threshold_minutes) => vrcx_running_detection_threshold_minutes,
+            // never used, as there's no uptime to detect
+            None if assume_always_running => 0,
+            None => return Err(Error::ConfigInvalid("vrcx_running_detection_threshold_minutes is required unless assume_always_running is set".to_owned())),
+        };
+        let partial_bucket_threshold = config.partial_bucket_threshold_fraction.unwrap_or(0.5);
+        if !(0.0..=1.0).contains(&partial_bucket_threshold) {
+            return Err(Error::ConfigInvalid(format!("partial_bucket_threshold_fraction ({partial_bucket_threshold}) must be from 0 to 1")));
+        }
+        let stats_hours = match config.stats_hours {
+            Some([start_hour, end_hour]) if start_hour == end_hour || start_hour >= HOURS_PER_DAY || end_hour > HOURS_PER_DAY => {
+                return Err(Error::ConfigInvalid(format!("stats_hours ([{start_hour}, {end_hour}]) must be two different hours, the first from 0 to 23 and the second from 0 to 24")));
+            }
+            Some([start_hour, end_hour]) => Some((start_hour, end_hour)),
+            None => None,
+        };
+        if let Some(max_tracked_dates_per_bucket) = config.max_tracked_dates_per_bucket {
+            if max_tracked_dates_per_bucket < 2 {
+                return Err(Error::ConfigInvalid(format!("max_tracked_dates_per_bucket ({max_tracked_dates_per_bucket}) must be at least 2")));
+            }
+            // these all need to know exactly which dates each bucket was active for, not just how many there were
+            if config.split_by_month == Some(true) || config.chunk_count.is_some() || config.window_weeks.is_some() || overlap_friend_ids.is_some() || absent_friend_ids.is_some() || config.normalize == NormalizationMode::Coverage {
+                return Err(Error::ConfigInvalid("max_tracked_dates_per_bucket can't be used with split_by_month, chunk_count, window_weeks, overlap_friend_ids, absent_friend_id, or normalize = \"coverage\"".to_owned()));
+            }
+        }
+        if config.recency_linear_weeks == Some(0) {
+            return Err(Error::ConfigInvalid("recency_linear_weeks must be at least 1".to_owned()));
+        }
+        if let Some(recency_half_life_weeks) = config.recency_half_life_weeks {
+            if recency_half_life_weeks == 0 {
+                return Err(Error::ConfigInvalid("recency_half_life_weeks must be at least 1".to_owned()));
+            }
+            if !matches!(config.normalize, NormalizationMode::Activity | NormalizationMode::Probability | NormalizationMode::Delta) {
+                return Err(Error::ConfigInvalid("recency_half_life_weeks needs normalize to be true, \"probability\", or \"delta\", as it weights the dates being normalized by".to_owned()));
+            }
+            if config.normalization_baseline.unwrap_or_default() != NormalizationBaseline::Bucket {
+                return Err(Error::ConfigInvalid("recency_half_life_weeks can't be combined with normalization_baseline, as each bucket needs its own weighted dates".to_owned()));
+            }
+            if config.recency_linear_weeks.is_some() {
+                return Err(Error::ConfigInvalid("recency_half_life_weeks can't be combined with recency_linear_weeks, which only weights the friend counts".to_owned()));
+            }
+        }
+        let calendar_export = match (config.calendar_path, config.calendar_threshold) {
+            (Some(calendar_path), Some(calendar_threshold)) => Some((calendar_path, calendar_threshold)),
+            (None, None) => None,
+            _ => return Err(Error::ConfigInvalid("calendar_path and calendar_threshold must be set together".to_owned())),
+        };
+        let friend_calendar_export = match (config.friend_calendar_path, config.friend_calendar_threshold) {
+            (Some(friend_calendar_path), Some(friend_calendar_threshold)) => {
+                if compare_friend_ids.is_some() || overlap_friend_ids.is_some() || absent_friend_ids.is_some() || config.self_activity_table.is_some() {
+                    return Err(Error::ConfigInvalid("friend_calendar_path can't be used when comparing friend sets, with overlap_friend_ids, with absent_friend_id, or with self_activity_table".to_owned()));
+                }
+                Some((friend_calendar_path, friend_calendar_threshold))
+            }
+            (None, None) => None,
+            _ => return Err(Error::ConfigInvalid("friend_calendar_path and friend_calendar_threshold must be set together".to_owned())),
+        };
+        let output_format = config.output_format.unwrap_or_default();
+        if output_format == OutputFormat::Npy && ((config.output_file.is_none() && config.window_output_dir.is_none()) || config.split_by_month == Some(true) || config.chunk_count.is_some()) {
+            return Err(Error::ConfigInvalid("output_format \"npy\" needs output_file (or window_output_dir) to be set, and can't be combined with split_by_month or chunk_count".to_owned()));
+        }
+        if let Some(chunk_count) = config.chunk_count {
+            if chunk_count < 2 {
+                return Err(Error::ConfigInvalid(format!("chunk_count ({chunk_count}) must be at least 2")));
+            }
+            if config.split_by_month == Some(true) || config.window_weeks.is_some() || output_format == OutputFormat::Friends {
+                return Err(Error::ConfigInvalid("chunk_count can't be used with split_by_month, window_weeks, or output_format \"friends\"".to_owned()));
+            }
+        }
+        let sliding_windows = match (config.window_weeks, config.window_step_weeks, config.window_output_dir) {
+            (Some(window_weeks), window_step_weeks, Some(output_dir)) => {
+                let step_weeks = window_step_weeks.unwrap_or(1);
+                if window_weeks == 0 || step_weeks == 0 {
+                    return Err(Error::ConfigInvalid("window_weeks and window_step_weeks must be at least 1".to_owned()));
+                }
+                if config.split_by_month == Some(true) || output_format == OutputFormat::Friends {
+                    return Err(Error::ConfigInvalid("window_weeks can't be used with split_by_month or output_format \"friends\"".to_owned()));
+                }
+                if config.output_file.is_some() {
+                    return Err(Error::ConfigInvalid("output_file can't be used with window_weeks, as each window is written to window_output_dir".to_owned()));
+                }
+                Some(SlidingWindows { window_weeks, step_weeks, output_dir })
+            }
+            (None, None, None) => None,
+            _ => return Err(Error::ConfigInvalid("window_weeks and window_output_dir must be set together, and window_step_weeks needs them both".to_owned())),
+        };
+        let filtered_output = match (config.filtered_output_file, config.filtered_minimum_bucket_activations) {
+            (Some(output_file), Some(minimum_bucket_activations)) => {
+                if sliding_windows.is_some() {
+                    return Err(Error::ConfigInvalid("filtered_output_file can't be used with window_weeks".to_owned()));
+                }
+                if matches!(output_format, OutputFormat::Raw | OutputFormat::Blocks | OutputFormat::Friends) {
+                    return Err(Error::ConfigInvalid("filtered_output_file can't be used with output_format \"raw\", \"blocks\", or \"friends\", as they don't use minimum_bucket_activations".to_owned()));
+                }
+                if config.output_file.as_ref() == Some(&output_file) {
+                    return Err(Error::ConfigInvalid("filtered_output_file must be different from output_file".to_owned()));
+                }
+                Some(FilteredOutput { output_file, minimum_bucket_activations: minimum_bucket_activations.max(1) })
+            }
+            (None, None) => None,
+            _ => return Err(Error::ConfigInvalid("filtered_output_file and filtered_minimum_bucket_activations must be set together".to_owned())),
+        };
+        if output_format == OutputFormat::Raw && compare_friend_ids.is_some() {
+            return Err(Error::ConfigInvalid("output_format \"raw\" can't be used when comparing friend sets, as a difference of raw counts is meaningless".to_owned()));
+        }
+        if output_format == OutputFormat::Stddev && compare_friend_ids.is_some() {
+            return Err(Error::ConfigInvalid("output_format \"stddev\" can't be used when comparing friend sets".to_owned()));
+        }
+        if output_format == OutputFormat::Reliability && compare_friend_ids.is_some() {
+            return Err(Error::ConfigInvalid("output_format \"reliability\" can't be used when comparing friend sets".to_owned()));
+        }
+        if output_format == OutputFormat::Friends && (compare_friend_ids.is_some() || config.split_by_month == Some(true)) {
+            return Err(Error::ConfigInvalid("output_format \"friends\" can't be used when comparing friend sets or splitting by month".to_owned()));
+        }
+        if config.self_activity_table.is_some() && (compare_friend_ids.is_some() || output_format == OutputFormat::Friends) {
+            return Err(Error::ConfigInvalid("self_activity_table can't be used when comparing friend sets or with output_format \"friends\"".to_owned()));
+        }
+        if overlap_friend_ids.is_some() && (compare_friend_ids.is_some() || config.self_activity_table.is_some() || output_format == OutputFormat::Friends) {
+            return Err(Error::ConfigInvalid("overlap_friend_ids can't be used when comparing friend sets, with self_activity_table, or with output_format \"friends\"".to_owned()));
+        }
+        if absent_friend_ids.is_some() && (compare_friend_ids.is_some() || overlap_friend_ids.is_some() || config.self_activity_table.is_some() || output_format == OutputFormat::Friends) {
+            return Err(Error::ConfigInvalid("absent_friend_id can't be used when comparing friend sets, with overlap_friend_ids, with self_activity_table, or with output_format \"friends\"".to_owned()));
+        }
+        if output_format == OutputFormat::Blocks && compare_friend_ids.is_some() {
+            return Err(Error::ConfigInvalid("output_format \"blocks\" can't be used when comparing friend sets".to_owned()));
+        }
+        let day_block_start_hours = config.day_block_start_hours.unwrap_or_else(|| DEFAULT_DAY_BLOCK_START_HOURS.to_vec());
+        let day_block_start_hours_valid = day_block_start_hours.len() == DAY_BLOCK_NAMES.len()
+            && day_block_start_hours.windows(2).all(|window| window[0] < window[1])
+            && day_block_start_hours.iter().all(|hour| *hour < HOURS_PER_DAY);
+        if !day_block_start_hours_valid {
+            return Err(Error::ConfigInvalid(format!("day_block_start_hours must be {} ascending hours from 0 to 23", DAY_BLOCK_NAMES.len())));
+        }
+        let highlight_window_minutes = config.highlight_window_minutes.unwrap_or(MINUTES_PER_HOUR);
+        if highlight_window_minutes == 0 || highlight_window_minutes > MINUTES_PER_DAY {
+            return Err(Error::ConfigInvalid(format!("highlight_window_minutes ({highlight_window_minutes}) must be from 1 to {MINUTES_PER_DAY}")));
+        }
+        let range_tolerance = config.range_tolerance.unwrap_or(0.0);
+        if !range_tolerance.is_finite() || range_tolerance < 0.0 {
+            return Err(Error::ConfigInvalid(format!("range_tolerance ({range_tolerance}) must be a non-negative number")));
+        }
+        let decimal_separator = config.decimal_separator.unwrap_or('.');
+        if decimal_separator.is_ascii_digit() || decimal_separator.is_whitespace() || decimal_separator == '-' {
+            return Err(Error::ConfigInvalid(format!("decimal_separator ({decimal_separator:?}) can't be a digit, whitespace, or '-'")));
+        }
+        let input_format = config.input_format.unwrap_or_default();
+        if config.self_activity_table.is_some() && input_format == InputFormat::Csv {
+            return Err(Error::ConfigInvalid("self_activity_table can only be used when input_format is \"sqlite\"".to_owned()));
+        }
+        let input = match input_format {
+            InputFormat::Sqlite => Input::Sqlite {
+                vrcx_db_paths: config.vrcx_db_path
+                    .map(PathList::into_vec)
+                    .filter(|vrcx_db_paths| !vrcx_db_paths.is_empty())
+                    .ok_or_else(|| Error::ConfigInvalid("vrcx_db_path must be set when input_format is \"sqlite\"".to_owned()))?,
+            },
+            InputFormat::Csv => Input::Csv {
+                online_offline_path: config.csv_online_offline_path
+                    .ok_or_else(|| Error::ConfigInvalid("csv_online_offline_path must be set when input_format is \"csv\"".to_owned()))?,
+                all_events_path: config.csv_all_events_path,
+            },
+        };
+
+        Ok(Self {
+            excluded_user_id: config.exclude_self.unwrap_or(false).then(|| canonical_user_id(config.your_user_id.clone())),
+            your_user_id: config.your_user_id,
+            input,
+            event_type_names: EventTypeNames::new(online_event_types, offline_event_types),
+            wal_snapshot_threshold_bytes: config.wal_snapshot_threshold_mb.map(|megabytes| megabytes.saturating_mul(1024 * 1024)),
+            friend_ids,
+            friend_ids_by_display_name: config.friend_ids_by_display_name.unwrap_or(false),
+            friend_weights,
+            user_id_aliases,
+            compare_friend_ids,
+            overlap_friend_ids,
+            absent_friend_ids,
+            fail_on_unmatched_friend_ids: config.fail_on_unmatched_friend_ids.unwrap_or(false),
+            self_activity_table: config.self_activity_table,
+            vrcx_running_detection_threshold: Duration::minutes(i64::from(vrcx_running_detection_threshold_minutes)),
+            assume_always_running,
+            partial_bucket_threshold,
+            max_tracked_dates: config.max_tracked_dates_per_bucket.map(|max_tracked_dates_per_bucket| usize::try_from(max_tracked_dates_per_bucket).unwrap()),
+            event_collapse_window: config.collapse_events_within_seconds.map(|seconds| Duration::seconds(i64::from(seconds))),
+            extend_final_session_to_now: config.extend_final_session_to_now.unwrap_or(false),
+            bucket_duration_minutes: config.bucket_duration_minutes,
+            align_to_hour,
+            bucket_layout,
+            // coverage normalization divides by fractional dates, so friend counts have to be fractional to match
+            weight_by_overlap: config.weight_by_overlap.unwrap_or(false) || config.normalize == NormalizationMode::Coverage,
+            closed_bucket_boundaries: config.closed_bucket_boundaries.unwrap_or(false),
+            infer_session_starts: config.infer_session_starts.unwrap_or(false),
+            normalize: config.normalize,
+            normalization_baseline: config.normalization_baseline.unwrap_or_default(),
+            analysis_range,
+            clamp_sessions_to_range: config.clamp_sessions_to_range.unwrap_or(false),
+            exclude_current_week: config.exclude_current_week.unwrap_or(false),
+            maximum_session_duration,
+            recency_ramp: config.recency_linear_weeks.map(|weeks| Duration::weeks(i64::from(weeks))),
+            recency_half_life: config.recency_half_life_weeks.map(|weeks| Duration::weeks(i64::from(weeks))),
+            minimum_bucket_activations: config.minimum_bucket_activations.unwrap_or(1).max(1),
+            low_confidence_threshold: config.low_confidence_threshold,
+            smoothing_radius: config.smoothing_radius,
+            no_data_returns_zero: config.no_data_returns_zero.unwrap_or(false),
+            print_statistics: config.print_statistics.unwrap_or(false),
+            statistics_minimum_events: config.statistics_minimum_events.unwrap_or(0),
+            stats_hours,
+            print_runtime: config.print_runtime.unwrap_or(false),
+            output_format,
+            day_block_start_hours,
+            highlight_window: Duration::minutes(i64::from(highlight_window_minutes)),
+            range_tolerance,
+            transpose: config.transpose.unwrap_or(false),
+            time_format_12h: config.time_format_12h.unwrap_or(false),
+            label_style: config.label_style.unwrap_or_default(),
+            decimal_separator,
+            decimal_places: config.decimal_places,
+            split_by_month: config.split_by_month.unwrap_or(false),
+            chunk_count: config.chunk_count,
+            sliding_windows,
+            output_file: config.output_file,
+            filtered_output,
+            bundle_file: config.bundle_file,
+            config_table: config.table,
+            recommendation_count: config.recommendation_count,
+            recommendation_weekdays,
+            recommend_min_friends: config.recommend_min_friends,
+            stale_weekday_threshold: config.stale_weekday_days.map(|days| Duration::days(i64::from(days))),
+            recommendation_sort: config.recommendation_sort.unwrap_or_default(),
+            recommendation_rank: config.recommendation_rank.unwrap_or_default(),
+            calendar_export,
+            friend_calendar_export,
+            verbose: config.verbose.unwrap_or(false),
+            warnings_format: config.warnings_format.unwrap_or_default(),
+            print_sql: false,
+            self_check: false,
+            strict: config.strict.unwrap_or(false),
+        })
+    }
+
+    pub const fn table_style(&self) -> TableStyle {
+        TableStyle {
+            time_format_12h: self.time_format_12h,
+            label_style: self.label_style,
+            transpose: self.transpose,
+            number_format: self.number_format(),
+            // these compute something else from the counts, whether or not they're normalized
+            value_kind: if matches!(self.output_format, OutputFormat::Stddev | OutputFormat::Reliability) { ValueKind::Measure } else { self.value_kind() },
+            markdown: matches!(self.output_format, OutputFormat::Markdown),
+        }
+    }
+
+    pub const fn number_format(&self) -> NumberFormat {
+        NumberFormat {
+            decimal_separator: self.decimal_separator,
+            decimal_places: self.decimal_places,
+        }
+    }
+
+    /// what the output values are: counts, unless they've been normalized
+    pub const fn value_kind(&self) -> ValueKind {
+        if matches!(self.normalize, NormalizationMode::None) {
+            ValueKind::Count
+        } else {
+            ValueKind::Measure
+        }
+    }
+
+    /// compute the output value grid for some buckets, diffed against comparison buckets if we're comparing friend sets
+    pub fn output_values(&self, buckets: &[Vec<BucketValue>], comparison_buckets: Option<&[Vec<BucketValue>]>) -> ValueGrid {
+        let values = self.smoothed_output_values(buckets);
+        match comparison_buckets {
+            Some(comparison_buckets) => difference_output_values(&values, &self.smoothed_output_values(comparison_buckets)),
+            None => values,
+        }
+    }
+
+    /// compute the output value grid for some buckets, applying smoothing if configured
+    fn smoothed_output_values(&self, buckets: &[Vec<BucketValue>]) -> ValueGrid {
+        let values = bucket_output_values(self.normalize, self.normalization_baseline, self.minimum_bucket_activations, self.recency_half_life, buckets);
+        match self.smoothing_radius {
+            Some(smoothing_radius) => smooth_output_values(smoothing_radius, &values, buckets),
+            None => values,
+        }
+    }
+
+    /// flag weekdays with no recent VRCX activity, if a `stale_weekday_threshold` is configured
+    pub fn stale_weekdays(&self, buckets: &[Vec<BucketValue>]) -> Option<Vec<bool>> {
+        self.stale_weekday_threshold.map(|stale_weekday_threshold| stale_weekdays(stale_weekday_threshold, Local::now(), buckets))
+    }
+
+    /// flag low-confidence buckets, if a `low_confidence_threshold` is configured
+    pub fn low_confidence_flags(&self, buckets: &[Vec<BucketValue>], comparison_buckets: Option<&[Vec<BucketValue>]>) -> Option<Vec<Vec<bool>>> {
+        self.low_confidence_threshold.map(|low_confidence_threshold| low_confidence_flags(low_confidence_threshold, buckets, comparison_buckets))
+    }
+}
+
+/// the results of one run of the analysis
+pub struct Analysis {
+    pub buckets: Vec<Vec<BucketValue>>,
+    /// buckets for friend set B, if we're comparing two friend sets
+    pub comparison_buckets: Option<Vec<Vec<BucketValue>>>,
+    pub clamp_stats: ClampStats,
+    pub all_event_count: usize,
+    pub online_offline_event_count: usize,
+    pub first_event_timestamp: Option<DateTime<Utc>>,
+    pub last_event_timestamp: Option<DateTime<Utc>>,
+    /// how many events are dated implausibly far in the future, which suggests VRCX's timestamps aren't really UTC
+    pub future_event_count: usize,
+    /// display names for every user in the online/offline events, for labeling ids in output
+    pub display_names: DisplayNames,
+    /// `friend_ids` as used to filter, with any display names looked up
+    pub friend_ids: FriendIds,
+    /// `friend_ids` display names that no user in the online/offline events has had
+    pub unresolved_friend_names: Vec<String>,
+    /// `friend_ids` display names more than one user has had, with each of their ids. These are left out of the filter.
+    pub ambiguous_friend_names: Vec<(String, Vec<String>)>,
+    /// the names of configured friend filters that didn't match a single online/offline event
+    pub unmatched_friend_filters: Vec<&'static str>,
+    /// each friend's best bucket, if output_format is "friends"
+    pub friend_peaks: Option<Vec<FriendPeak>>,
+    /// each friend's user id and own output grid, if friend_calendar_path is set
+    pub friend_grids: Option<Vec<(String, ValueGrid)>>,
+    /// when VRCX is believed to have been running, across every input
+    pub vrcx_uptime: VrcxUptime,
+    /// how far apart consecutive VRCX events typically are, if there were any gaps at all
+    pub event_gap_percentiles: Option<EventGapPercentiles>,
+    /// data that made no sense and was skipped
+    pub anomalies: Anomalies,
+    /// how many events were dropped for falling within `event_collapse_window` of an earlier one
+    pub collapsed_event_count: usize,
+}
+
+/// a summary of the gaps between consecutive VRCX events on the same machine
+pub struct EventGapPercentiles {
+    pub median: Duration,
+    pub percentile_90: Duration,
+}
+
+impl EventGapPercentiles {
+    /// Summarize some gaps in any order, or `None` if there are none. Zero-length gaps are skipped, as bursts of
+    /// simultaneous events say nothing about how often VRCX records things.
+    fn from_gaps(mut gaps: Vec<Duration>) -> Option<Self> {
+        gaps.retain(|gap| *gap > Duration::zero());
+        if gaps.is_empty() {
+            return None;
+        }
+        gaps.sort_unstable();
+        let percentile = |percent: usize| gaps.get((gaps.len() - 1) * percent / 100).copied();
+        Some(Self {
+            median: percentile(50)?,
+            percentile_90: percentile(90)?,
+        })
+    }
+}
+
+/// the bucket a friend is most likely to be online in
+pub struct FriendPeak {
+    pub user_id: String,
+    pub day_index: usize,
+    pub bucket_index: usize,
+    pub value: f64,
+}
+
+/// open every configured input. Only SQLite input can have more than one, for combining databases from several machines.
+pub fn open_event_sources(settings: &Settings) -> Result<Vec<Box<dyn EventSource>>, Error> {
+    match &settings.input {
+        Input::Sqlite { vrcx_db_paths } => vrcx_db_paths.iter()
+            .map(|vrcx_db_path| Ok(Box::new(SqliteSource::open(vrcx_db_path, &settings.your_user_id, settings.print_sql, settings.wal_snapshot_threshold_bytes, settings.self_activity_table, settings.event_type_names.clone())?) as Box<dyn EventSource>))
+            .collect(),
+        Input::Csv { online_offline_path, all_events_path } => Ok(vec![Box::new(CsvSource::new(online_offline_path.clone(), all_events_path.clone(), settings.event_type_names.clone()))]),
+    }
+}
+
+/// read the events out of every configured input, one [`EventData`] per input
+pub fn read_all_events(settings: &Settings) -> Result<Vec<EventData>, Error> {
+    open_event_sources(settings)?.iter_mut()
+        .map(|event_source| event_source.read_events())
+        .collect()
+}
+
+/// read the VRCX events and bucket them
+pub fn analyze(settings: &Settings) -> Result<Analysis, Error> {
+    // read all the events we need out of VRCX
+    let event_data = {
+        let _span = profile::span("read events");
+        read_all_events(settings)?
+    };
+    analyze_events(settings, event_data, Utc::now())
+}
+
+/// bucket events that have already been read, one [`EventData`] per input, as of `current_time`
+pub fn analyze_events(settings: &Settings, mut event_data: Vec<EventData>, current_time: DateTime<Utc>) -> Result<Analysis, Error> {
+    let mut anomalies = Anomalies::new(settings.strict);
+    for event_data in &mut event_data {
+        // Every source sorts its timestamps, but everything below silently goes wrong on out-of-order data, so they get
+        // re-sorted here rather than trusting that nothing ever slips through.
+        if !event_data.all_event_timestamps.is_sorted() {
+            anomalies.report("VRCX event timestamps were read out of order".to_owned());
+            event_data.all_event_timestamps.sort_unstable();
+        }
+        for row in &mut event_data.online_offline_rows {
+            if let Some(user_id) = settings.user_id_aliases.get(&row.user_id) {
+                row.user_id.clone_from(user_id);
+            }
+        }
+    }
+    let future_event_count: usize = event_data.iter()
+        .map(|event_data| &event_data.all_event_timestamps)
+        .map(|timestamps| timestamps.len() - timestamps.partition_point(|timestamp| *timestamp <= current_time + FUTURE_TIMESTAMP_TOLERANCE))
+        .sum();
+    // how far extend_final_session_to_now can stretch the final uptime interval
+    let mut extend_until = current_time;
+    if settings.exclude_current_week {
+        // the current week hasn't had every bucket come around yet, so it would drag recent-heavy denominators down
+        let week_start = week_start(current_time.with_timezone(&Local).date_naive());
+        // its dates mustn't come back as uptime with none of its friend sessions
+        extend_until = week_start;
+        for event_data in &mut event_data {
+            let in_progress_index = event_data.all_event_timestamps.partition_point(|timestamp| *timestamp < week_start);
+            event_data.all_event_timestamps.truncate(in_progress_index);
+            event_data.online_offline_rows.retain(|row| row.created_at < week_start);
+        }
+    }
+    if event_data.iter().all(|event_data| event_data.all_event_timestamps.is_empty()) {
+        return Err(Error::NoData);
+    }
+
+    // set up data structures we'll need for the VRCX running analysis
+    let bucket_layout = &settings.bucket_layout;
+    let mut buckets = build_daily_buckets(bucket_layout.buckets_per_day());
+    if let Some(max_tracked_dates) = settings.max_tracked_dates {
+        for bucket_value in buckets.iter_mut().flatten() {
+            bucket_value.date_sample = Some(DateSample::new(max_tracked_dates));
+        }
+    }
+    let all_event_count: usize = event_data.iter().map(|event_data| event_data.all_event_timestamps.len()).sum();
+    let first_event_timestamp: Option<DateTime<Utc>> = event_data.iter().filter_map(|event_data| event_data.all_event_timestamps.first().copied()).min();
+    let last_event_timestamp: Option<DateTime<Utc>> = event_data.iter().filter_map(|event_data| event_data.all_event_timestamps.last().copied()).max();
+
+    // Each machine's uptime is worked out from its own events, as interleaving two machines' events could bridge gaps
+    // that neither machine actually covered. The bucket activity dates are sets, so a date seen by several machines is
+    // still only counted once.
+    let mut vrcx_uptimes: Vec<VrcxUptime> = Vec::with_capacity(event_data.len());
+    let mut online_offline_rows: Vec<Row> = Vec::new();
+    let mut self_activity_timestamps: Vec<DateTime<Utc>> = Vec::new();
+    let mut event_gaps: Vec<Duration> = Vec::with_capacity(all_event_count);
+    let mut collapsed_event_count: usize = 0;
+    for EventData { mut all_event_timestamps, online_offline_rows: rows, self_activity_timestamps: timestamps } in event_data {
+        if let Some(event_collapse_window) = settings.event_collapse_window {
+            collapsed_event_count += collapse_event_bursts(&mut all_event_timestamps, event_collapse_window);
+        }
+        if !all_event_timestamps.is_empty() {
+            let _span = profile::span("detect uptime");
+            let vrcx_start_stop_events = if settings.assume_always_running {
+                always_running_uptime(settings, &all_event_timestamps, extend_until, &mut buckets)
+            } else {
+                detect_vrcx_uptime(settings, &all_event_timestamps, extend_until, &mut buckets, &mut event_gaps)
+            };
+            vrcx_uptimes.push(VrcxUptime::from_events(&vrcx_start_stop_events));
+        }
+        online_offline_rows.extend(rows);
+        self_activity_timestamps.extend(timestamps);
+    }
+    // Machines running at the same time record the same feed events, so identical timestamps are only counted once. Two
+    // genuinely separate events in the same millisecond are rare enough not to matter.
+    self_activity_timestamps.sort_unstable();
+    self_activity_timestamps.dedup();
+    let vrcx_uptime = VrcxUptime::union(vrcx_uptimes);
+    let event_gap_percentiles = EventGapPercentiles::from_gaps(event_gaps);
+    // Merge every machine's online/offline events into one stream. Machines running at the same time see the same friend
+    // sessions, and the session pairing already drops repeated online and unmatched offline events, so each session is
+    // only counted once. The sort is stable and each input is already sorted, so this is cheap for a single input.
+    online_offline_rows.sort_by_key(|row| row.created_at);
+    let online_offline_event_count: usize = online_offline_rows.len();
+
+    // bucket the friend online/offline sessions
+    let session_bucketer = SessionBucketer {
+        bucket_layout,
+        friend_weights: &settings.friend_weights,
+        weight_by_overlap: settings.weight_by_overlap,
+        closed_bucket_boundaries: settings.closed_bucket_boundaries,
+        infer_session_starts: settings.infer_session_starts,
+        analysis_range: settings.analysis_range,
+        clamp_sessions_to_range: settings.clamp_sessions_to_range,
+        maximum_session_duration: settings.maximum_session_duration,
+        recency_window: settings.recency_ramp.zip(last_event_timestamp).map(|(recency_ramp, last_event_timestamp)| TimeSpan::new(last_event_timestamp - recency_ramp, last_event_timestamp)),
+        vrcx_uptime: &vrcx_uptime,
+        self_check: settings.self_check,
+    };
+    let excluded_user_id = settings.excluded_user_id.as_deref();
+    let ResolvedFriendIds { friend_ids, unresolved_friend_names, ambiguous_friend_names } = if settings.friend_ids_by_display_name {
+        resolve_friend_display_names(&settings.friend_ids, &online_offline_rows)
+    } else {
+        ResolvedFriendIds { friend_ids: settings.friend_ids.clone(), unresolved_friend_names: Vec::new(), ambiguous_friend_names: Vec::new() }
+    };
+    // every comparison grid starts from the same VRCX activity data
+    let activity_buckets = buckets.clone();
+    let primary_friend_ids = match &settings.compare_friend_ids {
+        Some((compare_friend_ids_a, _)) => compare_friend_ids_a,
+        None => &friend_ids,
+    };
+    let mut clamp_stats = ClampStats::default();
+    if settings.self_activity_table.is_some() {
+        add_event_counts(bucket_layout, settings.analysis_range, &vrcx_uptime, &self_activity_timestamps, &mut buckets);
+    } else if let Some((overlap_friend_ids_a, overlap_friend_ids_b)) = &settings.overlap_friend_ids {
+        // bucket each friend on their own, then keep only the dates they were both online for
+        let mut friend_buckets_a = activity_buckets.clone();
+        session_bucketer.add_sessions(&online_offline_rows, |user_id| is_user_allowed(user_id, overlap_friend_ids_a, excluded_user_id), &mut clamp_stats, &mut anomalies, &mut friend_buckets_a);
+        let mut friend_buckets_b = activity_buckets.clone();
+        session_bucketer.add_sessions(&online_offline_rows, |user_id| is_user_allowed(user_id, overlap_friend_ids_b, excluded_user_id), &mut clamp_stats, &mut anomalies, &mut friend_buckets_b);
+        add_co_online_dates(&friend_buckets_a, &friend_buckets_b, &mut buckets);
+    } else if settings.absent_friend_ids.is_some() {
+        // bucket the reference friend on their own, then drop the dates they were online for from everyone else's grid
+        let absent_friend_ids = &settings.absent_friend_ids;
+        let mut absent_friend_buckets = activity_buckets.clone();
+        session_bucketer.add_sessions(&online_offline_rows, |user_id| is_user_allowed(user_id, absent_friend_ids, excluded_user_id), &mut clamp_stats, &mut anomalies, &mut absent_friend_buckets);
+        session_bucketer.add_sessions(&online_offline_rows, |user_id| !is_user_allowed(user_id, absent_friend_ids, None) && is_user_allowed(user_id, primary_friend_ids, excluded_user_id), &mut clamp_stats, &mut anomalies, &mut buckets);
+        remove_present_dates(&absent_friend_buckets, &mut buckets);
+    } else {
+        session_bucketer.add_sessions(&online_offline_rows, |user_id| is_user_allowed(user_id, primary_friend_ids, excluded_user_id), &mut clamp_stats, &mut anomalies, &mut buckets);
+    }
+    let mut comparison_buckets: Option<Vec<Vec<BucketValue>>> = settings.compare_friend_ids.as_ref().map(|(_, compare_friend_ids_b)| {
+        let mut comparison_buckets = activity_buckets.clone();
+        session_bucketer.add_sessions(&online_offline_rows, |user_id| is_user_allowed(user_id, compare_friend_ids_b, excluded_user_id), &mut clamp_stats, &mut anomalies, &mut comparison_buckets);
+        comparison_buckets
+    });
+    discard_inactive_online_counts(&mut buckets, &mut anomalies);
+    if let Some(comparison_buckets) = &mut comparison_buckets {
+        discard_inactive_online_counts(comparison_buckets, &mut anomalies);
+    }
+
+    // Bucket each friend on their own, for their best bucket and their own calendar. Only one friend's bucket grid
+    // exists at a time, as keeping them all around would multiply memory use by the number of friends.
+    let friend_grids: Vec<(String, ValueGrid)> = if settings.output_format == OutputFormat::Friends || settings.friend_calendar_export.is_some() {
+        // group the rows by friend once, so each friend's grid only looks at their own rows
+        let mut rows_by_user_id: BTreeMap<&str, Vec<&Row>> = BTreeMap::new();
+        for row in online_offline_rows.iter().filter(|row| is_user_allowed(&row.user_id, primary_friend_ids, excluded_user_id)) {
+            rows_by_user_id.entry(&row.user_id).or_default().push(row);
+        }
+        rows_by_user_id.into_iter()
+            .map(|(user_id, rows)| {
+                let mut friend_buckets = activity_buckets.clone();
+                // the main grid already reported anything wrong with these sessions, so they aren't reported twice
+                session_bucketer.add_sessions(rows, |_| true, &mut ClampStats::default(), &mut Anomalies::new(false), &mut friend_buckets);
+                discard_inactive_online_counts(&mut friend_buckets, &mut anomalies);
+                (user_id.to_owned(), settings.output_values(&friend_buckets, None))
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let friend_peaks: Option<Vec<FriendPeak>> = (settings.output_format == OutputFormat::Friends).then(|| {
+        friend_grids.iter()
+            .filter_map(|(user_id, values)| {
+                // the earliest bucket in the week wins ties
+                let mut peak: Option<FriendPeak> = None;
+                for (day_index, values_for_day) in values.iter().enumerate() {
+                    for (bucket_index, value) in values_for_day.iter().enumerate() {
+                        if let Some(value) = *value {
+                            if peak.as_ref().is_none_or(|peak| value > peak.value) {
+                                peak = Some(FriendPeak { user_id: user_id.clone(), day_index, bucket_index, value });
+                            }
+                        }
+                    }
+                }
+                peak
+            })
+            .collect()
+    });
+    let friend_grids = settings.friend_calendar_export.is_some().then_some(friend_grids);
+
+    // a filter that matches nobody produces an empty grid, which is easy to mistake for a real result
+    let mut friend_filters: Vec<(&'static str, &FriendIds)> = Vec::with_capacity(2);
+    match (&settings.compare_friend_ids, &settings.overlap_friend_ids) {
+        (Some((compare_friend_ids_a, compare_friend_ids_b)), _) => {
+            friend_filters.push(("compare_friend_ids_a", compare_friend_ids_a));
+            friend_filters.push(("compare_friend_ids_b", compare_friend_ids_b));
+        }
+        (None, Some((overlap_friend_ids_a, overlap_friend_ids_b))) => {
+            friend_filters.push(("overlap_friend_ids", overlap_friend_ids_a));
+            friend_filters.push(("overlap_friend_ids", overlap_friend_ids_b));
+        }
+        (None, None) => {
+            friend_filters.push(("friend_ids", &friend_ids));
+            friend_filters.push(("absent_friend_id", &settings.absent_friend_ids));
+        }
+    }
+    let mut unmatched_friend_filters: Vec<&'static str> = friend_filters.into_iter()
+        .filter(|(_, friend_ids)| friend_ids.is_some() && !online_offline_rows.iter().any(|row| is_user_allowed(&row.user_id, friend_ids, excluded_user_id)))
+        .map(|(name, _)| name)
+        .collect();
+    // both overlap_friend_ids share a name, so neither matching shouldn't be reported twice
+    unmatched_friend_filters.dedup();
+    if settings.fail_on_unmatched_friend_ids {
+        if let Some(name) = unmatched_friend_filters.first() {
+            return Err(Error::ConfigInvalid(format!("{name} didn't match any online/offline events. Check the ids against --list-users")));
+        }
+    }
+
+    Ok(Analysis {
+        buckets,
+        comparison_buckets,
+        clamp_stats,
+        all_event_count,
+        online_offline_event_count,
+        first_event_timestamp,
+        last_event_timestamp,
+        future_event_count,
+        display_names: DisplayNames::from_rows(&online_offline_rows),
+        friend_ids,
+        unresolved_friend_names,
+        ambiguous_friend_names,
+        unmatched_friend_filters,
+        friend_peaks,
+        friend_grids,
+        event_gap_percentiles,
+        vrcx_uptime,
+        anomalies,
+        collapsed_event_count,
+    })
+}
+
+/// Drop every event that comes within `event_collapse_window` of the last event kept, so a burst of near-duplicate feed rows
+/// counts as a single event. Measuring from the last event kept rather than the previous event means a steady stream of
+/// chatter still leaves an event every `event_collapse_window`, instead of collapsing into one. The timestamps must be
+/// ascending. Returns how many events were dropped.
+fn collapse_event_bursts(all_event_timestamps: &mut Vec<DateTime<Utc>>, event_collapse_window: Duration) -> usize {
+    let original_count = all_event_timestamps.len();
+    let mut last_kept: Option<DateTime<Utc>> = None;
+    all_event_timestamps.retain(|timestamp| {
+        let keep = last_kept.is_none_or(|last_kept| *timestamp - last_kept >= event_collapse_window);
+        if keep {
+            last_kept = Some(*timestamp);
+        }
+        keep
+    });
+    original_count - all_event_timestamps.len()
+}
+
+/// Treat VRCX as running without a break from one machine's first event to its last (or to `extend_until`, normally now,
+/// with `extend_final_session_to_now`), for a dedicated machine that never turns off. Every bucket in that range is
+/// registered as active, so each bucket's number of active dates is just how many times it came around. The timestamps
+/// must be ascending and non-empty.
+fn always_running_uptime(settings: &Settings, all_event_timestamps: &[DateTime<Utc>], extend_until: DateTime<Utc>, buckets: &mut [Vec<BucketValue>]) -> Vec<VrcxStartStopEvent> {
+    let first_timestamp = *all_event_timestamps.first().unwrap();
+    let last_timestamp = if settings.extend_final_session_to_now { extend_until } else { *all_event_timestamps.last().unwrap() };
+    let time_span = TimeSpan::new(first_timestamp, last_timestamp);
+    register_bucket_dates_for_range(&settings.bucket_layout, settings.partial_bucket_threshold, time_span, buckets);
+    if settings.normalize == NormalizationMode::Coverage {
+        register_bucket_coverage_for_range(&settings.bucket_layout, time_span, buckets);
+    }
+    vec![VrcxStartStopEvent::start(first_timestamp), VrcxStartStopEvent::stop(last_timestamp)]
+}
+
+/// Work out when VRCX was running from the gaps between one machine's events, registering the dates VRCX was active
+/// for each bucket along the way. Every gap is also pushed to `event_gaps`. With `extend_final_session_to_now`, fresh
+/// enough data keeps VRCX running up to `extend_until`, which is normally now. The timestamps must be ascending and
+/// non-empty.
+fn detect_vrcx_uptime(settings: &Settings, all_event_timestamps: &[DateTime<Utc>], extend_until: DateTime<Utc>, buckets: &mut [Vec<BucketValue>], event_gaps: &mut Vec<Duration>) -> Vec<VrcxStartStopEvent> {
+    let bucket_layout = &settings.bucket_layout;
+    let vrcx_running_detection_threshold = settings.vrcx_running_detection_threshold;
+    // coverage is a second map per bucket, so only pay for it when it's used
+    let track_coverage = settings.normalize == NormalizationMode::Coverage;
+    let mut vrcx_start_stop_events: Vec<VrcxStartStopEvent> = Vec::new();
+
+    // process all event timestamps
+    let mut vrcx_running: bool = false;
+    for window in all_event_timestamps.windows(2) {
+        match window {
+            &[event_timestamp_1, event_timestamp_2] => {
+                let duration = event_timestamp_2.signed_duration_since(event_timestamp_1);
+                debug_assert!(duration >= Duration::zero()); // assert that data is, in fact, ascending
+                event_gaps.push(duration);
+                if duration <= vrcx_running_detection_threshold && duration >= Duration::zero() {
+                    // we can skip over zero-length durations
+                    // duration between events was within the threshold, so assume VRCX is running for this entire time range
+
+                    if !vrcx_running {
+                        // vrcx just started running
+
+                        // the previous event should have been a stop event (or empty)
+                        debug_assert!(vrcx_start_stop_events.last().is_none() || matches!(vrcx_start_stop_events.last(), Some(VrcxStartStopEvent{ event: VrcxStartStopEventType::Stop, .. })));
+
+                        vrcx_running = true;
+                        vrcx_start_stop_events.push(VrcxStartStopEvent::start(event_timestamp_1));
+                    } // else, if vrcx was already running there's nothing for us to do
+
+                    // use any VRCX events available to reason that VRCX is running during a given time range
+                    if track_coverage {
+                        register_bucket_coverage_for_range(bucket_layout, TimeSpan::new(event_timestamp_1, event_timestamp_2), buckets);
+                    }
+                } else if vrcx_running {
+                    // duration was outside threshold, so assume VRCX is *not* running for this range (which may be quite long)
+                    // also, VRCX was running in the previous range, therefore we need to push a stop event
+
+                    // the previous event should have been a start event
+                    debug_assert!(matches!(vrcx_start_stop_events.last(), Some(VrcxStartStopEvent{ event: VrcxStartStopEventType::Start, .. })));
+
+                    vrcx_running = false;
+                    vrcx_start_stop_events.push(VrcxStartStopEvent::stop(event_timestamp_1));
+                }
+            }
+            _ => unreachable!()
+        }
+    }
+
+    // push the final stop event, if needed
+    let last_timestamp = *all_event_timestamps.last().unwrap();
+    if settings.extend_final_session_to_now && extend_until.signed_duration_since(last_timestamp) <= vrcx_running_detection_threshold {
+        // the data is so fresh that VRCX is probably still running, so assume it's running right up until now
+        if !vrcx_running {
+            vrcx_start_stop_events.push(VrcxStartStopEvent::start(last_timestamp));
+        }
+        if track_coverage {
+            register_bucket_coverage_for_range(bucket_layout, TimeSpan::new(last_timestamp, extend_until), buckets);
+        }
+        vrcx_start_stop_events.push(VrcxStartStopEvent::stop(extend_until));
+    } else if vrcx_running {
+        vrcx_start_stop_events.push(VrcxStartStopEvent::stop(last_timestamp));
+    }
+
+    // Dates are registered per uptime interval rather than per gap between events, so a bucket only partly covered by
+    // each of several short gaps still counts by how much of it the interval as a whole covers.
+    for start_stop in vrcx_start_stop_events.chunks_exact(2) {
+        let time_span = TimeSpan::new(start_stop[0].timestamp, start_stop[1].timestamp);
+        register_bucket_dates_for_range(bucket_layout, settings.partial_bucket_threshold, time_span, buckets);
+    }
+    vrcx_start_stop_events
+}
+
+/// Midnight at the start of the local calendar week (Monday) containing `date`. If DST skips that midnight, the week
+/// starts once the clocks have jumped forward instead.
+pub fn week_start(date: NaiveDate) -> DateTime<Utc> {
+    day_start(date - Duration::days(i64::from(date.weekday().num_days_from_monday())))
+}
+
+/// Local midnight at the start of `date`. If DST skips that midnight, the day starts once the clocks have jumped forward
+/// instead.
+pub fn day_start(date: NaiveDate) -> DateTime<Utc> {
+    let midnight = date.and_time(NaiveTime::MIN);
+    midnight.and_local_timezone(Local).earliest()
+        .or_else(|| (midnight + Duration::hours(1)).and_local_timezone(Local).earliest())
+        .expect("local midnight doesn't exist")
+        .with_timezone(&Utc)
+}
+
+/// Read friend ids from a file. The file is either a JSON array of strings, or one id per line with blank lines and lines
+/// starting with `#` ignored.
+fn read_friend_ids_file(path: &str) -> Result<HashSet<String>, Error> {
+    let text = fs::read_to_string(path).map_err(|e| Error::ConfigInvalid(format!("could not read friend_ids_file {path}: {e}")))?;
+    if text.trim_start().starts_with('[') {
+        let not_an_array = || Error::ConfigInvalid(format!("friend_ids_file {path} must be a JSON array of strings"));
+        let JsonValue::Array(values) = JsonValue::parse(&text).map_err(|e| Error::ConfigInvalid(format!("friend_ids_file {path} is not valid JSON: {e}")))? else {
+            return Err(not_an_array());
+        };
+        values.into_iter()
+            .map(|value| match value {
+                JsonValue::String(friend_id) => Ok(friend_id),
+                _ => Err(not_an_array()),
+            })
+            .collect()
+    } else {
+        Ok(text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect())
+    }
+}
+
+/// parse an ISO-8601 time from the config
+fn parse_config_time(key: &str, time: &str) -> Result<DateTime<Utc>, Error> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| Error::ConfigInvalid(format!("{key} \"{time}\" is not a valid ISO-8601 time: {e}")))
+}
+
+/// `friend_ids` with its display names looked up, and the names that couldn't be
+struct ResolvedFriendIds {
+    friend_ids: FriendIds,
+    unresolved_friend_names: Vec<String>,
+    /// each name several users have had, with all of their ids
+    ambiguous_friend_names: Vec<(String, Vec<String>)>,
+}
+
+/// Look up the `friend_ids` entries that aren't `usr_` ids as display names, replacing each with the id of the one user who
+/// has had that name. Names nobody has had, and names several users have had, are left out and returned so they can be
+/// warned about.
+fn resolve_friend_display_names(friend_ids: &FriendIds, rows: &[Row]) -> ResolvedFriendIds {
+    let Some(friend_ids) = friend_ids else {
+        return ResolvedFriendIds { friend_ids: None, unresolved_friend_names: Vec::new(), ambiguous_friend_names: Vec::new() };
+    };
+    let user_ids_by_display_name = user_ids_by_display_name(rows);
+    let mut resolved_friend_ids: HashSet<String> = HashSet::with_capacity(friend_ids.len());
+    let mut unresolved_friend_names: Vec<String> = Vec::new();
+    let mut ambiguous_friend_names: Vec<(String, Vec<String>)> = Vec::new();
+    for friend_id in friend_ids {
+        if friend_id.starts_with(USER_ID_PREFIX) {
+            resolved_friend_ids.insert(friend_id.clone());
+            continue;
+        }
+        match user_ids_by_display_name.get(friend_id.as_str()) {
+            None => unresolved_friend_names.push(friend_id.clone()),
+            Some(user_ids) if user_ids.len() == 1 => {
+                resolved_friend_ids.insert((*user_ids.first().unwrap()).to_owned());
+            }
+            Some(user_ids) => ambiguous_friend_names.push((friend_id.clone(), user_ids.iter().map(|user_id| (*user_id).to_owned()).collect())),
+        }
+    }
+    // friend_ids is a set, so sort these for stable output
+    unresolved_friend_names.sort_unstable();
+    ambiguous_friend_names.sort_unstable();
+    ResolvedFriendIds { friend_ids: Some(resolved_friend_ids), unresolved_friend_names, ambiguous_friend_names }
+}
+
+/// Check if a given user has been filtered out by our configuration. The excluded user id takes priority over the
+/// friend_ids allowlist.
+fn is_user_allowed(user_id: &str, friend_ids: &FriendIds, excluded_user_id: Option<&str>) -> bool {
+    if excluded_user_id == Some(user_id) {
+        return false;
+    }
+    // if friend ids is unset, then allow every user id
+    friend_ids.as_ref().is_none_or(|friend_ids| friend_ids.contains(user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::{env, process};
+
+    use chrono::{SecondsFormat, TimeZone};
+    use rusqlite::Connection;
+
+    use super::*;
+
+    /// an RFC 3339 `created_at` for a local time on Monday 2024-01-01, the way VRCX writes it
+    fn created_at(hour: u32, minute: u32) -> String {
+        Local.with_ymd_and_hms(2024, 1, 1, hour, minute, 0).unwrap().with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Millis, true)
+    }
+
+    /// Write a VRCX database for `usr_me` where the same `created_at` values show up in several of the unioned event
+    /// tables, and friends go offline and back online within the same millisecond.
+    fn write_database(path: &Path) {
+        let db = Connection::open(path).unwrap();
+        db.execute_batch("
+            create table usrme_feed_online_offline (id integer primary key, created_at text, user_id text, display_name text, type text);
+            create table usrme_feed_gps (id integer primary key, created_at text);
+            create table usrme_feed_status (id integer primary key, created_at text);
+        ").unwrap();
+        for minute in (0..=110).step_by(10) {
+            let created_at = created_at(9 + (50 + minute) / 60, (50 + minute) % 60);
+            db.execute("insert into usrme_feed_gps (created_at) values (?1)", [&created_at]).unwrap();
+            db.execute("insert into usrme_feed_status (created_at) values (?1)", [&created_at]).unwrap();
+        }
+        let rows = [
+            (created_at(10, 0), "usr_a", "Online"),
+            (created_at(10, 15), "usr_b", "Online"),
+            (created_at(10, 30), "usr_b", "Offline"),
+            (created_at(10, 30), "usr_b", "Online"),
+            (created_at(10, 40), "usr_a", "Offline"),
+            (created_at(11, 20), "usr_b", "Offline"),
+        ];
+        for (created_at, user_id, event_type) in rows {
+            db.execute("insert into usrme_feed_online_offline (created_at, user_id, display_name, type) values (?1, ?2, ?2, ?3)", [&created_at, user_id, event_type]).unwrap();
+        }
+    }
+
+    #[test]
+    fn duplicate_timestamps_across_tables_and_databases() {
+        let directory = env::temp_dir().join(format!("vrcx-optimal-time-test-{}-duplicate-timestamps", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        // two machines running at the same time, which recorded identical events
+        let db_paths = [directory.join("a.sqlite3"), directory.join("b.sqlite3")];
+        for db_path in &db_paths {
+            write_database(db_path);
+        }
+        let config = format!(
+            "your_user_id = \"usr_me\"\nvrcx_db_path = [{:?}, {:?}]\nvrcx_running_detection_threshold_minutes = 30\nbucket_duration_minutes = 60\nnormalize = false\nstrict = true",
+            db_paths[0].to_str().unwrap(),
+            db_paths[1].to_str().unwrap(),
+        );
+        let settings = Settings::from_config(Configuration::parse(&config).unwrap()).unwrap();
+        let analysis = analyze(&settings);
+        fs::remove_dir_all(&directory).unwrap();
+        let analysis = analysis.unwrap();
+
+        assert_eq!(analysis.anomalies.count(), 0);
+        // the union keeps each distinct created_at once per database, which is the twelve feed times plus usr_b's 10:15
+        assert_eq!(analysis.all_event_count, 2 * 13);
+        assert_eq!(analysis.online_offline_event_count, 2 * 6);
+        // usr_a from 10:00 to 10:40, and usr_b from 10:15 to 10:30 then 10:30 to 11:20, each counted once despite being
+        // seen by both machines
+        let online_counts: Vec<(usize, usize, f64)> = analysis.buckets.iter().enumerate()
+            .flat_map(|(day_index, buckets_for_day)| buckets_for_day.iter().enumerate()
+                .filter(|(_, bucket_value)| bucket_value.online_count != 0.0)
+                .map(move |(bucket_index, bucket_value)| (day_index, bucket_index, bucket_value.online_count)))
+            .collect();
+        assert_eq!(online_counts, vec![(0, 10, 3.0), (0, 11, 1.0)]);
+        assert_eq!(analysis.clamp_stats.dropped, 0);
+    }
+
+    #[test]
+    fn extended_uptime_stops_at_excluded_week() {
+        // VRCX running now, and every five minutes since the Monday a week before the current week started
+        let current_time = Utc::now();
+        let current_week_start = week_start(current_time.with_timezone(&Local).date_naive());
+        let first_timestamp = current_week_start - Duration::weeks(1);
+        let timestamps = || -> Vec<DateTime<Utc>> {
+            (0..)
+                .map(|index| first_timestamp + Duration::minutes(5 * index))
+                .take_while(|timestamp| *timestamp <= current_time)
+                .collect()
+        };
+        for always_running in [false, true] {
+            let config = format!("your_user_id = \"usr_me\"\nvrcx_db_path = \"VRCX.sqlite3\"\nvrcx_running_detection_threshold_minutes = 30\nbucket_duration_minutes = 60\nnormalize = true\nassume_always_running = {always_running}\nextend_final_session_to_now = true\nexclude_current_week = true");
+            let settings = Settings::from_config(Configuration::parse(&config).unwrap()).unwrap();
+            let event_data = vec![EventData { all_event_timestamps: timestamps(), online_offline_rows: Vec::new(), self_activity_timestamps: Vec::new() }];
+            let analysis = analyze_events(&settings, event_data, current_time).unwrap();
+            let last_date = analysis.buckets.iter().flatten().filter_map(BucketValue::last_date).max().unwrap();
+            assert!(last_date < current_week_start, "assume_always_running = {always_running}: {last_date} is in the excluded week");
+            assert_eq!(analysis.vrcx_uptime.intervals().last().unwrap().stop, current_week_start, "assume_always_running = {always_running}");
+        }
+    }
+}
@@ -0,0 +1,44 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+//! Data that breaks an assumption the analysis relies on, such as a session with a negative duration. Real-world
+//! databases occasionally contain things nobody anticipated, so by default the offending item is skipped and the
+//! anomaly is reported as a warning, rather than throwing away the whole run. With `strict = true` the first anomaly
+//! panics instead, so it gets noticed and fixed.
+
+/// the anomalies seen so far
+pub struct Anomalies {
+    /// panic on the first anomaly instead of recording it
+    strict: bool,
+    count: usize,
+    /// the first anomaly's message, as an example for the warning
+    first_message: Option<String>,
+}
+
+impl Anomalies {
+    pub const fn new(strict: bool) -> Self {
+        Self {
+            strict,
+            count: 0,
+            first_message: None,
+        }
+    }
+
+    /// Report an anomaly. The caller is expected to skip the offending item afterwards.
+    pub fn report(&mut self, message: String) {
+        if self.strict {
+            panic!("{message}");
+        }
+        self.count += 1;
+        self.first_message.get_or_insert(message);
+    }
+
+    pub const fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn first_message(&self) -> Option<&str> {
+        self.first_message.as_deref()
+    }
+}
@@ -0,0 +1,511 @@
+// Copyright 2022-2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{Datelike, DateTime, Duration, Local, Timelike, Utc};
+
+use crate::anomalies::Anomalies;
+use crate::constants::{DAYS_PER_WEEK, SECONDS_PER_MINUTE};
+use crate::layout::BucketLayout;
+use crate::dto::{AnalysisRange, BucketValue, OnlineOfflineEventType, Row, TimeSpan};
+use crate::profile;
+use crate::uptime::{ClampStats, VrcxUptime};
+
+/// everything needed to turn friend online/offline rows into bucket counts
+pub struct SessionBucketer<'a> {
+    pub bucket_layout: &'a BucketLayout,
+    /// how much each friend counts toward a bucket. Friends not listed count as 1.
+    pub friend_weights: &'a HashMap<String, f64>,
+    pub weight_by_overlap: bool,
+    /// also count the bucket a session ends exactly at the start of
+    pub closed_bucket_boundaries: bool,
+    /// Give an offline event with no online event before it a session starting when VRCX started, as the friend was
+    /// presumably already online by then. This only applies if VRCX hadn't seen the friend go offline since starting.
+    pub infer_session_starts: bool,
+    pub analysis_range: AnalysisRange,
+    pub clamp_sessions_to_range: bool,
+    /// sessions longer than this are discarded
+    pub maximum_session_duration: Option<Duration>,
+    /// Sessions starting before this count for nothing, and ones starting within it count in proportion to how far into
+    /// it they start, so recent weeks matter more without older ones being cut off abruptly.
+    pub recency_window: Option<TimeSpan>,
+    pub vrcx_uptime: &'a VrcxUptime,
+    /// cross-check every clamp against a brute-force implementation
+    pub self_check: bool,
+}
+
+impl SessionBucketer<'_> {
+    /// Pair up online/offline rows for allowed users into sessions, and add those sessions to the bucket counts. How each
+    /// session was clamped to VRCX uptime, or whether it was discarded for being too long, is tallied in `clamp_stats`.
+    /// Sessions that make no sense, such as ones ending before they start, are skipped and reported to `anomalies`. Rows
+    /// must be in the order they happened, but can be any subset of the online/offline rows.
+    pub fn add_sessions<'r>(&self, online_offline_rows: impl IntoIterator<Item = &'r Row>, is_user_allowed: impl Fn(&str) -> bool, clamp_stats: &mut ClampStats, anomalies: &mut Anomalies, buckets: &mut [Vec<BucketValue>]) {
+        let _span = profile::span("add sessions");
+        // set up data structures we'll need for the online/offline analysis
+        let mut user_online_time: HashMap<&str, DateTime<Utc>> = HashMap::new();
+        let mut user_offline_time: HashMap<&str, DateTime<Utc>> = HashMap::new();
+
+        // process the user online/offline events
+        for row in online_offline_rows {
+            // apply start_time and end_time filters. If we're clamping sessions instead then every row is needed, as sessions
+            // straddling the range boundaries still contribute their in-range portion.
+            if !self.clamp_sessions_to_range && !self.analysis_range.contains(row.created_at) {
+                continue;
+            }
+
+            if is_user_allowed(&row.user_id) {
+                match row.event_type {
+                    OnlineOfflineEventType::Online => {
+                        // it is intentional that this overwrites previous Online events,
+                        // because given two Online events in a row we should drop the first one
+                        user_online_time.insert(&row.user_id, row.created_at);
+                    }
+                    OnlineOfflineEventType::Offline => {
+                        let online_time = user_online_time.remove(row.user_id.as_str())
+                            .or_else(|| self.inferred_online_time(row.created_at, user_offline_time.get(row.user_id.as_str()).copied(), clamp_stats));
+                        user_offline_time.insert(&row.user_id, row.created_at);
+                        if let Some(online_time) = online_time {
+                            let offline_time = row.created_at;
+                            let time_span = TimeSpan::new(online_time, offline_time);
+                            if time_span.stop < time_span.start {
+                                anomalies.report(format!("Got a negative ({}ms) duration for {}. This should not happen.", time_span.duration().num_milliseconds(), row.display_name));
+                                continue;
+                            }
+                            if self.maximum_session_duration.is_some_and(|maximum| time_span.duration() > maximum) {
+                                // a marathon session would flood every bucket it touches, so drop it entirely
+                                clamp_stats.too_long += 1;
+                                continue;
+                            }
+                            let time_span = if self.clamp_sessions_to_range {
+                                // this may produce a non-positive span if the session was entirely outside the range, which is then skipped below
+                                self.analysis_range.clamp(time_span)
+                            } else {
+                                time_span
+                            };
+                            if time_span.stop > time_span.start {
+                                let friend_weight = self.friend_weights.get(&row.user_id).copied().unwrap_or(1.0)
+                                    * self.recency_window.map_or(1.0, |recency_window| recency_weight(recency_window, time_span.start));
+                                if self.self_check {
+                                    self.vrcx_uptime.self_check_clamp_range(time_span);
+                                }
+                                let clamped = {
+                                    let _span = profile::span("clamp");
+                                    self.vrcx_uptime.clamp_range(time_span, clamp_stats)
+                                };
+                                if let Ok(events) = clamped {
+                                    let _span = profile::span("update buckets");
+                                    // perfect, we got a usable event. We need to update buckets!
+                                    for time_span in events.into_iter() {
+                                        if time_span.is_negative_or_zero() {
+                                            anomalies.report(format!("Got a non-positive clamped duration ({}ms) for {}. This should not happen if my clamping code actually works.", time_span.duration().num_milliseconds(), row.display_name));
+                                            continue;
+                                        }
+                                        update_bucket_counts_for_range(self.bucket_layout, self.weight_by_overlap, self.closed_bucket_boundaries, friend_weight, time_span, buckets);
+                                    }
+                                } // else, the range is too long, so drop the event
+                            } // else, the time_span doesn't have positive duration so we skip it. Note that this also drops the Online event, because a simultaneous Online+Offline event is nonsensical.
+                        } // else, no matching online time, so drop the event
+                    }
+                };
+            }
+        }
+    }
+
+    /// When a friend went offline at `offline_time` without going online first, the start of the VRCX uptime interval it
+    /// happened in, if `infer_session_starts` is on. There's nothing to infer if VRCX wasn't running, or if it already saw
+    /// the friend go offline (at `previous_offline_time`) since starting, as then the missing online event went missing
+    /// some other way.
+    fn inferred_online_time(&self, offline_time: DateTime<Utc>, previous_offline_time: Option<DateTime<Utc>>, clamp_stats: &mut ClampStats) -> Option<DateTime<Utc>> {
+        if !self.infer_session_starts {
+            return None;
+        }
+        let vrcx_start = self.vrcx_uptime.interval_start(offline_time)?;
+        if previous_offline_time.is_some_and(|previous_offline_time| previous_offline_time >= vrcx_start) {
+            return None;
+        }
+        clamp_stats.inferred_start += 1;
+        Some(vrcx_start)
+    }
+}
+
+/// How much a session starting at `start` counts for under a linear recency ramp: nothing before the window, rising
+/// linearly to 1 at its end, and 1 after it.
+fn recency_weight(recency_window: TimeSpan, start: DateTime<Utc>) -> f64 {
+    let elapsed = start.signed_duration_since(recency_window.start).num_milliseconds() as f64;
+    (elapsed / recency_window.duration().num_milliseconds() as f64).clamp(0.0, 1.0)
+}
+
+/// Count individual events into the buckets they fall in, instead of friend sessions. Only events within the analysis range
+/// and VRCX uptime count, and each counted event also marks its bucket as active for that date, as VRCX was evidently
+/// running to record it.
+pub fn add_event_counts(bucket_layout: &BucketLayout, analysis_range: AnalysisRange, vrcx_uptime: &VrcxUptime, event_timestamps: &[DateTime<Utc>], buckets: &mut [Vec<BucketValue>]) {
+    for timestamp in event_timestamps {
+        if !analysis_range.contains(*timestamp) || !vrcx_uptime.contains(*timestamp) {
+            continue;
+        }
+        let bucket_start_time = bucket_layout.bucket_start(timestamp.with_timezone(&Local));
+        let (day_index, bucket_index) = bucket_layout.position(bucket_start_time);
+        let bucket_value = bucket_at(buckets, day_index, bucket_index, bucket_start_time);
+        bucket_value.add_online_count(bucket_start_time, 1.0);
+        bucket_value.register_date(bucket_start_time);
+    }
+}
+
+/// Count the dates both friends were online for each bucket, given a grid for each friend bucketed on their own. Both
+/// friends only have to be online at some point during the bucket, not necessarily at the same moment. Each co-online date
+/// adds 1 regardless of friend weights, and every date either grid was active for is registered as active, matching what
+/// bucketing both friends into one grid would have registered.
+pub fn add_co_online_dates(friend_buckets_a: &[Vec<BucketValue>], friend_buckets_b: &[Vec<BucketValue>], buckets: &mut [Vec<BucketValue>]) {
+    for ((buckets_for_day, buckets_for_day_a), buckets_for_day_b) in buckets.iter_mut().zip(friend_buckets_a).zip(friend_buckets_b) {
+        for ((bucket_value, bucket_value_a), bucket_value_b) in buckets_for_day.iter_mut().zip(buckets_for_day_a).zip(buckets_for_day_b) {
+            for date in bucket_value_a.vrcx_activity_dates.iter().chain(&bucket_value_b.vrcx_activity_dates) {
+                bucket_value.register_date(*date);
+            }
+            for date in bucket_value_a.online_counts_by_date.keys() {
+                if bucket_value_b.online_counts_by_date.contains_key(date) {
+                    bucket_value.add_online_count(*date, 1.0);
+                }
+            }
+        }
+    }
+}
+
+/// Drop every date a reference friend was online for from each bucket, given a grid for that friend bucketed on their own.
+/// Both the friend online counts and the dates VRCX was active for are dropped, so normalizing gives the activity on
+/// dates the reference friend was absent for that bucket, rather than just leaving their dates out of the numerator.
+pub fn remove_present_dates(reference_buckets: &[Vec<BucketValue>], buckets: &mut [Vec<BucketValue>]) {
+    for (buckets_for_day, reference_buckets_for_day) in buckets.iter_mut().zip(reference_buckets) {
+        for (bucket_value, reference_bucket_value) in buckets_for_day.iter_mut().zip(reference_buckets_for_day) {
+            for date in reference_bucket_value.online_counts_by_date.keys() {
+                bucket_value.vrcx_activity_dates.remove(date);
+                bucket_value.vrcx_coverage_by_date.remove(date);
+                if let Some(count) = bucket_value.online_counts_by_date.remove(date) {
+                    bucket_value.online_count -= count;
+                }
+            }
+        }
+    }
+}
+
+/// Reset any bucket with a friend online count but no dates VRCX was active on, reporting each to `anomalies`. Bucketing
+/// always registers a date alongside a count, so this shouldn't happen, but such a bucket would otherwise have nothing to
+/// be normalized against.
+pub fn discard_inactive_online_counts(buckets: &mut [Vec<BucketValue>], anomalies: &mut Anomalies) {
+    for (day_index, buckets_for_day) in buckets.iter_mut().enumerate() {
+        for (bucket_index, bucket_value) in buckets_for_day.iter_mut().enumerate() {
+            if bucket_value.total_dates() == 0 && bucket_value.online_count != 0.0 {
+                anomalies.report(format!("Bucket {bucket_index} of day {day_index} has online_count={} but was never active, which is nonsensical.", bucket_value.online_count));
+                bucket_value.online_count = 0.0;
+                bucket_value.online_counts_by_date.clear();
+            }
+        }
+    }
+}
+
+/// Build buckets according to configured bucket size. This is deliberately dense: an empty bucket's `BTreeSet` and
+/// `BTreeMap`s don't allocate until something is inserted, so each empty bucket is about 150 bytes, and even 1 minute
+/// buckets (10,080 a week) cost about 1.5 MiB before any data arrives. A sparse map would only save that, while making
+/// every lookup a hash.
+pub fn build_daily_buckets(buckets_per_day: usize) -> Vec<Vec<BucketValue>> {
+    vec![vec![BucketValue::default(); buckets_per_day]; DAYS_PER_WEEK]
+}
+
+/// Update bucket counts that a provided range encompasses. Buckets are half-open, so a range ending exactly on a bucket
+/// boundary does not touch the following bucket, but one ending a millisecond later does. If `closed_bucket_boundaries`
+/// is set, a range ending exactly on a boundary counts toward the following bucket as well. Each bucket's weekday is
+/// computed independently, so a range crossing midnight is split between the two days at the correct bucket.
+///
+/// By default any overlap with a bucket counts as a full increment. If `weight_by_overlap` is set, each bucket is instead
+/// incremented by the fraction of the bucket the range covers, so a range from 23:55 to 00:15 with 10 minute buckets adds
+/// 0.5 to the 23:50 bucket, 1 to the 00:00 bucket, and 0.5 to the 00:10 bucket. A bucket the range merely touches is
+/// covered for no time at all, so `closed_bucket_boundaries` has no effect in this case.
+///
+/// Every increment is then multiplied by `friend_weight`, the importance of the friend this range belongs to.
+///
+/// Every bucket counted here is also registered as active for its date, whatever the overlap. This is deliberately looser
+/// than [`register_bucket_dates_for_range`], as a bucket with a friend count must always have an active date to be divided
+/// by.
+pub fn update_bucket_counts_for_range(bucket_layout: &BucketLayout, weight_by_overlap: bool, closed_bucket_boundaries: bool, friend_weight: f64, time_span: TimeSpan, buckets: &mut [Vec<BucketValue>]) {
+    let end_time = time_span.stop.with_timezone(&Local);
+    let mut start_time = time_span.start.with_timezone(&Local);
+    start_time = bucket_layout.bucket_start(start_time);
+    let include_end_boundary = closed_bucket_boundaries && !weight_by_overlap;
+
+    while start_time < end_time || (include_end_boundary && start_time == end_time) {
+        let (day_index, bucket_index) = bucket_layout.position(start_time);
+        let next_start_time = bucket_layout.next_bucket_start(start_time);
+
+        let weight = if weight_by_overlap {
+            let bucket_span = TimeSpan::new(start_time.with_timezone(&Utc), next_start_time.with_timezone(&Utc));
+            let overlap = TimeSpan::new(bucket_span.start.max(time_span.start), bucket_span.stop.min(time_span.stop));
+            overlap.duration().num_milliseconds() as f64 / bucket_layout.duration(bucket_index).num_milliseconds() as f64
+        } else {
+            1.0
+        };
+
+        let bucket_value = bucket_at(buckets, day_index, bucket_index, start_time);
+
+        // increment the friend online count
+        bucket_value.add_online_count(start_time, weight * friend_weight);
+
+        // we're assuming that VRCX is actually running for this whole range, so update the VRCX running dates as well...
+        bucket_value.register_date(start_time);
+
+        start_time = next_start_time;
+    }
+}
+
+/// Register this range's dates as active for the relevant buckets. A bucket only partly covered by the range counts if the
+/// range covers more than `partial_bucket_threshold` of it (a fraction from 0 to 1), so at the default of a half a range
+/// ending exactly on a boundary never registers the following bucket, regardless of `closed_bucket_boundaries`. This only
+/// decides which dates VRCX was running, so the stricter rule keeps brief overlaps from inflating the activity counts.
+pub fn register_bucket_dates_for_range(bucket_layout: &BucketLayout, partial_bucket_threshold: f64, time_span: TimeSpan, buckets: &mut [Vec<BucketValue>]) {
+    let end_time = time_span.stop.with_timezone(&Local);
+    let mut start_time = bucket_layout.bucket_start(time_span.start.with_timezone(&Local));
+    while start_time < end_time {
+        let bucket_duration = bucket_layout.duration(bucket_layout.position(start_time).1);
+        let next_start_time = bucket_layout.next_bucket_start(start_time);
+        let overlap = TimeSpan::new(start_time.with_timezone(&Utc).max(time_span.start), next_start_time.with_timezone(&Utc).min(time_span.stop)).duration();
+        // a whole bucket always counts, even with a threshold of 1
+        if overlap >= bucket_duration || overlap.num_milliseconds() as f64 > partial_bucket_threshold * bucket_duration.num_milliseconds() as f64 {
+            register_bucket_date(bucket_layout, start_time, buckets);
+        }
+        start_time = next_start_time;
+    }
+}
+
+/// Record what fraction of each bucket this range covers, for coverage normalization. Unlike
+/// [`register_bucket_dates_for_range`] every overlap counts, however brief, but only for the fraction it covers.
+pub fn register_bucket_coverage_for_range(bucket_layout: &BucketLayout, time_span: TimeSpan, buckets: &mut [Vec<BucketValue>]) {
+    let end_time = time_span.stop.with_timezone(&Local);
+    let mut start_time = bucket_layout.bucket_start(time_span.start.with_timezone(&Local));
+    while start_time < end_time {
+        let (day_index, bucket_index) = bucket_layout.position(start_time);
+        let next_start_time = bucket_layout.next_bucket_start(start_time);
+        let overlap = TimeSpan::new(start_time.with_timezone(&Utc).max(time_span.start), next_start_time.with_timezone(&Utc).min(time_span.stop));
+        let fraction = overlap.duration().num_milliseconds() as f64 / bucket_layout.duration(bucket_index).num_milliseconds() as f64;
+        bucket_at(buckets, day_index, bucket_index, start_time).add_coverage(start_time, fraction);
+        start_time = next_start_time;
+    }
+}
+
+/// Split buckets into a separate set of buckets for each calendar month, keyed by (year, month). Each bucket date lands in
+/// the month it occurred in, local time.
+pub fn split_buckets_by_month(buckets_per_day: usize, buckets: &[Vec<BucketValue>]) -> BTreeMap<(i32, u32), Vec<Vec<BucketValue>>> {
+    let mut monthly_buckets: BTreeMap<(i32, u32), Vec<Vec<BucketValue>>> = BTreeMap::new();
+    for (day_index, buckets_for_day) in buckets.iter().enumerate() {
+        for (bucket_index, bucket_value) in buckets_for_day.iter().enumerate() {
+            for date in &bucket_value.vrcx_activity_dates {
+                let month_buckets = monthly_buckets.entry((date.year(), date.month())).or_insert_with(|| build_daily_buckets(buckets_per_day));
+                month_buckets[day_index][bucket_index].register_date(*date);
+            }
+            for (date, count) in &bucket_value.online_counts_by_date {
+                let month_buckets = monthly_buckets.entry((date.year(), date.month())).or_insert_with(|| build_daily_buckets(buckets_per_day));
+                month_buckets[day_index][bucket_index].add_online_count(*date, *count);
+            }
+            for (date, fraction) in &bucket_value.vrcx_coverage_by_date {
+                let month_buckets = monthly_buckets.entry((date.year(), date.month())).or_insert_with(|| build_daily_buckets(buckets_per_day));
+                month_buckets[day_index][bucket_index].add_coverage(*date, *fraction);
+            }
+        }
+    }
+    monthly_buckets
+}
+
+/// Copy just the dates within a time span into a new set of buckets, for analyzing a slice of the history on its own. A
+/// date counts if its bucket starts within the span, which is half-open so back-to-back spans never share a date.
+pub fn buckets_in_span(buckets_per_day: usize, time_span: TimeSpan, buckets: &[Vec<BucketValue>]) -> Vec<Vec<BucketValue>> {
+    let in_span = |date: &DateTime<Local>| time_span.start <= *date && *date < time_span.stop;
+    let mut span_buckets = build_daily_buckets(buckets_per_day);
+    for (span_buckets_for_day, buckets_for_day) in span_buckets.iter_mut().zip(buckets) {
+        for (span_bucket_value, bucket_value) in span_buckets_for_day.iter_mut().zip(buckets_for_day) {
+            for date in bucket_value.vrcx_activity_dates.iter().filter(|date| in_span(date)) {
+                span_bucket_value.register_date(*date);
+            }
+            for (date, count) in bucket_value.online_counts_by_date.iter().filter(|(date, _)| in_span(date)) {
+                span_bucket_value.add_online_count(*date, *count);
+            }
+            for (date, fraction) in bucket_value.vrcx_coverage_by_date.iter().filter(|(date, _)| in_span(date)) {
+                span_bucket_value.add_coverage(*date, *fraction);
+            }
+        }
+    }
+    span_buckets
+}
+
+#[inline]
+fn register_bucket_date(bucket_layout: &BucketLayout, bucket_time: DateTime<Local>, buckets: &mut [Vec<BucketValue>]) {
+    let (day_index, bucket_index) = bucket_layout.position(bucket_time);
+    bucket_at(buckets, day_index, bucket_index, bucket_time).register_date(bucket_time);
+}
+
+/// Look up the bucket a time was mapped to. An out-of-range index means the layout math is broken (most likely around a
+/// DST transition), so panic with enough detail to reproduce it instead of a bare "index out of bounds".
+#[inline]
+fn bucket_at(buckets: &mut [Vec<BucketValue>], day_index: usize, bucket_index: usize, bucket_time: DateTime<Local>) -> &mut BucketValue {
+    let day_count = buckets.len();
+    match buckets.get_mut(day_index) {
+        Some(buckets_for_day) => {
+            let buckets_per_day = buckets_for_day.len();
+            match buckets_for_day.get_mut(bucket_index) {
+                Some(bucket_value) => bucket_value,
+                None => panic!(
+                    "{bucket_time} ({}, minute {} of the day) mapped to bucket {bucket_index}, but there are only {buckets_per_day} buckets per day. This should not happen.",
+                    bucket_time.weekday(),
+                    bucket_time.num_seconds_from_midnight() / SECONDS_PER_MINUTE,
+                ),
+            }
+        }
+        None => panic!(
+            "{bucket_time} ({}) mapped to day {day_index}, but there are only {day_count} days. This should not happen.",
+            bucket_time.weekday(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    /// A local time, as the buckets are laid out in local time. January has no DST transitions in any common zone.
+    fn local(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(2024, 1, day, hour, minute, 0).unwrap().with_timezone(&Utc)
+    }
+
+    /// every bucket with a friend count, as `(day_index, bucket_index, online_count)`
+    fn online_counts(buckets: &[Vec<BucketValue>]) -> Vec<(usize, usize, f64)> {
+        buckets.iter().enumerate()
+            .flat_map(|(day_index, buckets_for_day)| buckets_for_day.iter().enumerate()
+                .filter(|(_, bucket_value)| bucket_value.online_count != 0.0)
+                .map(move |(bucket_index, bucket_value)| (day_index, bucket_index, bucket_value.online_count)))
+            .collect()
+    }
+
+    /// every bucket with an active date, as `(day_index, bucket_index)`
+    fn active_buckets(buckets: &[Vec<BucketValue>]) -> Vec<(usize, usize)> {
+        buckets.iter().enumerate()
+            .flat_map(|(day_index, buckets_for_day)| buckets_for_day.iter().enumerate()
+                .filter(|(_, bucket_value)| bucket_value.total_dates() != 0)
+                .map(move |(bucket_index, _)| (day_index, bucket_index)))
+            .collect()
+    }
+
+    fn counted_buckets(bucket_layout: &BucketLayout, weight_by_overlap: bool, closed_bucket_boundaries: bool, friend_weight: f64, time_span: TimeSpan) -> Vec<Vec<BucketValue>> {
+        let mut buckets = build_daily_buckets(bucket_layout.buckets_per_day());
+        update_bucket_counts_for_range(bucket_layout, weight_by_overlap, closed_bucket_boundaries, friend_weight, time_span, &mut buckets);
+        buckets
+    }
+
+    fn registered_buckets(partial_bucket_threshold: f64, time_span: TimeSpan) -> Vec<Vec<BucketValue>> {
+        let bucket_layout = BucketLayout::uniform(60);
+        let mut buckets = build_daily_buckets(bucket_layout.buckets_per_day());
+        register_bucket_dates_for_range(&bucket_layout, partial_bucket_threshold, time_span, &mut buckets);
+        buckets
+    }
+
+    #[test]
+    fn range_crossing_midnight_counts_each_bucket_once() {
+        // Wednesday 23:55 to Thursday 00:15
+        let time_span = TimeSpan::new(local(3, 23, 55), local(4, 0, 15));
+        let bucket_layout = BucketLayout::uniform(10);
+        let buckets = counted_buckets(&bucket_layout, false, false, 1.0, time_span);
+        assert_eq!(online_counts(&buckets), vec![(2, 143, 1.0), (3, 0, 1.0), (3, 1, 1.0)]);
+        assert_eq!(active_buckets(&buckets), vec![(2, 143), (3, 0), (3, 1)]);
+        assert_eq!(buckets[2][143].vrcx_activity_dates.first().copied(), Some(Local.with_ymd_and_hms(2024, 1, 3, 23, 50, 0).unwrap()));
+        assert_eq!(buckets[3][0].vrcx_activity_dates.first().copied(), Some(Local.with_ymd_and_hms(2024, 1, 4, 0, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn weight_by_overlap_splits_midnight_between_days() {
+        let time_span = TimeSpan::new(local(3, 23, 55), local(4, 0, 15));
+        let buckets = counted_buckets(&BucketLayout::uniform(10), true, false, 1.0, time_span);
+        assert_eq!(online_counts(&buckets), vec![(2, 143, 0.5), (3, 0, 1.0), (3, 1, 0.5)]);
+        // a sliver of a bucket is still an active date, so the count has something to be divided by
+        assert_eq!(active_buckets(&buckets), vec![(2, 143), (3, 0), (3, 1)]);
+    }
+
+    #[test]
+    fn weight_by_overlap_wraps_from_sunday_to_monday() {
+        // Sunday 23:55 to Monday 00:15, with hour buckets so neither side covers a whole bucket
+        let time_span = TimeSpan::new(local(7, 23, 55), local(8, 0, 15));
+        let buckets = counted_buckets(&BucketLayout::uniform(60), true, false, 1.0, time_span);
+        assert_eq!(online_counts(&buckets), vec![(0, 0, 15.0 / 60.0), (6, 23, 5.0 / 60.0)]);
+        assert_eq!(active_buckets(&buckets), vec![(0, 0), (6, 23)]);
+        assert_eq!(buckets[6][23].online_counts_by_date.keys().copied().collect::<Vec<_>>(), vec![Local.with_ymd_and_hms(2024, 1, 7, 23, 0, 0).unwrap()]);
+        assert_eq!(buckets[0][0].online_counts_by_date.keys().copied().collect::<Vec<_>>(), vec![Local.with_ymd_and_hms(2024, 1, 8, 0, 0, 0).unwrap()]);
+    }
+
+    #[test]
+    fn weight_by_overlap_scales_by_friend_weight() {
+        let time_span = TimeSpan::new(local(7, 23, 55), local(8, 0, 15));
+        let buckets = counted_buckets(&BucketLayout::uniform(10), true, false, 2.0, time_span);
+        assert_eq!(online_counts(&buckets), vec![(0, 0, 2.0), (0, 1, 1.0), (6, 143, 1.0)]);
+    }
+
+    #[test]
+    fn weight_by_overlap_uses_each_aligned_bucket_duration() {
+        // with 45 minute buckets aligned to the hour, 23:45 starts a 15 minute bucket
+        let time_span = TimeSpan::new(local(7, 23, 50), local(8, 0, 15));
+        let bucket_layout = BucketLayout::aligned_to_hour(45);
+        let last_bucket_index = bucket_layout.buckets_per_day() - 1;
+        let buckets = counted_buckets(&bucket_layout, true, false, 1.0, time_span);
+        assert_eq!(online_counts(&buckets), vec![(0, 0, 15.0 / 45.0), (6, last_bucket_index, 10.0 / 15.0)]);
+    }
+
+    #[test]
+    fn range_ending_on_boundary_is_half_open() {
+        // Monday 10:00 to 11:00
+        let time_span = TimeSpan::new(local(1, 10, 0), local(1, 11, 0));
+        let buckets = counted_buckets(&BucketLayout::uniform(60), false, false, 1.0, time_span);
+        assert_eq!(online_counts(&buckets), vec![(0, 10, 1.0)]);
+        assert_eq!(active_buckets(&buckets), vec![(0, 10)]);
+    }
+
+    #[test]
+    fn range_ending_on_boundary_with_closed_boundaries() {
+        let time_span = TimeSpan::new(local(1, 10, 0), local(1, 11, 0));
+        let buckets = counted_buckets(&BucketLayout::uniform(60), false, true, 1.0, time_span);
+        assert_eq!(online_counts(&buckets), vec![(0, 10, 1.0), (0, 11, 1.0)]);
+        assert_eq!(active_buckets(&buckets), vec![(0, 10), (0, 11)]);
+
+        // the following bucket is covered for no time at all, so weighting by overlap ignores the boundary
+        let buckets = counted_buckets(&BucketLayout::uniform(60), true, true, 1.0, time_span);
+        assert_eq!(online_counts(&buckets), vec![(0, 10, 1.0)]);
+        assert_eq!(active_buckets(&buckets), vec![(0, 10)]);
+    }
+
+    #[test]
+    fn range_ending_past_boundary_counts_following_bucket() {
+        let time_span = TimeSpan::new(local(1, 10, 0), local(1, 11, 0) + Duration::milliseconds(1));
+        for closed_bucket_boundaries in [false, true] {
+            let buckets = counted_buckets(&BucketLayout::uniform(60), false, closed_bucket_boundaries, 1.0, time_span);
+            assert_eq!(online_counts(&buckets), vec![(0, 10, 1.0), (0, 11, 1.0)]);
+            assert_eq!(active_buckets(&buckets), vec![(0, 10), (0, 11)]);
+
+            let buckets = counted_buckets(&BucketLayout::uniform(60), true, closed_bucket_boundaries, 1.0, time_span);
+            assert_eq!(online_counts(&buckets), vec![(0, 10, 1.0), (0, 11, 1.0 / 3_600_000.0)]);
+        }
+    }
+
+    #[test]
+    fn registered_dates_never_include_touched_bucket() {
+        let time_span = TimeSpan::new(local(1, 10, 0), local(1, 11, 0));
+        // even a threshold of zero needs some overlap
+        for partial_bucket_threshold in [0.0, 0.5, 1.0] {
+            assert_eq!(active_buckets(&registered_buckets(partial_bucket_threshold, time_span)), vec![(0, 10)]);
+        }
+    }
+
+    #[test]
+    fn registered_dates_past_boundary_follow_threshold() {
+        let time_span = TimeSpan::new(local(1, 10, 0), local(1, 11, 0) + Duration::milliseconds(1));
+        assert_eq!(active_buckets(&registered_buckets(0.0, time_span)), vec![(0, 10), (0, 11)]);
+        assert_eq!(active_buckets(&registered_buckets(0.5, time_span)), vec![(0, 10)]);
+        // a whole bucket counts even with a threshold of 1
+        assert_eq!(active_buckets(&registered_buckets(1.0, time_span)), vec![(0, 10)]);
+    }
+}
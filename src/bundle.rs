@@ -0,0 +1,132 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+//! A single JSON file recording everything that went into a result: the config as given, the settings it resolved to,
+//! the statistics, and the raw bucket grid. Anyone with the bundle can see exactly what produced a heatmap, and recompute it, without the database.
+
+use chrono::{DateTime, Utc, Weekday};
+use num_traits::cast::FromPrimitive;
+
+use crate::analysis::{Analysis, Settings};
+use crate::constants::{DAYS_PER_WEEK, MILLISECONDS_PER_HOUR};
+use crate::dto::BucketValue;
+use crate::json::JsonValue;
+use crate::output::ValueGrid;
+
+/// Describe the config, effective settings, statistics, and grids of an analysis. `online_counts` and `active_dates` are the raw numerator and
+/// denominator of each bucket, indexed by `[day][bucket]` like `values`, which is the grid as it was output.
+pub fn bundle_json(settings: &Settings, analysis: &Analysis, current_time: DateTime<Utc>) -> JsonValue {
+    let values = settings.output_values(&analysis.buckets, analysis.comparison_buckets.as_deref());
+    let mut members = vec![
+        ("generated_at".to_owned(), JsonValue::String(current_time.to_rfc3339())),
+        ("config".to_owned(), JsonValue::from_toml(toml::Value::Table(settings.config_table.clone()))),
+        ("effective_config".to_owned(), effective_config_json(settings)),
+        ("statistics".to_owned(), statistics_json(analysis)),
+    ];
+    members.extend(grid_json(settings, &values));
+    members.extend(raw_grid_json("", &analysis.buckets));
+    if let Some(comparison_buckets) = &analysis.comparison_buckets {
+        members.extend(raw_grid_json("comparison_", comparison_buckets));
+    }
+    JsonValue::Object(members)
+}
+
+/// The `days`, `buckets`, and `values` members describing an output grid, shared by the bundle and `--serve` so they can't
+/// drift apart. `values` is indexed by `[day][bucket]`, with days starting on Monday to match `days`, and buckets matching
+/// `buckets`. Buckets with no data are null, unless `no_data_returns_zero` is set.
+pub fn grid_json(settings: &Settings, values: &ValueGrid) -> [(String, JsonValue); 3] {
+    let days = (0..DAYS_PER_WEEK)
+        .map(|day| JsonValue::String(Weekday::from_usize(day).unwrap().to_string()))
+        .collect();
+    let buckets = settings.bucket_layout.labels(settings.label_style, settings.time_format_12h).into_iter()
+        .map(JsonValue::String)
+        .collect();
+    let values = values.iter()
+        .map(|values_for_day| JsonValue::Array(values_for_day.iter()
+            .map(|value| match value {
+                Some(value) => JsonValue::Float(*value),
+                None if settings.no_data_returns_zero => JsonValue::Float(0.0),
+                None => JsonValue::Null,
+            })
+            .collect()))
+        .collect();
+    [
+        ("days".to_owned(), JsonValue::Array(days)),
+        ("buckets".to_owned(), JsonValue::Array(buckets)),
+        ("values".to_owned(), JsonValue::Array(values)),
+    ]
+}
+
+/// The settings that shape the output grid, with every default filled in, under the config keys they came from. Readers
+/// of the bundle use these instead of guessing what an omitted key would have defaulted to.
+fn effective_config_json(settings: &Settings) -> JsonValue {
+    let optional_integer = |value: Option<i64>| value.map_or(JsonValue::Null, JsonValue::Integer);
+    let optional_timestamp = |timestamp: Option<DateTime<Utc>>| timestamp.map_or(JsonValue::Null, |timestamp| JsonValue::String(timestamp.to_rfc3339()));
+    JsonValue::Object(vec![
+        ("bucket_duration_minutes".to_owned(), JsonValue::Integer(settings.bucket_duration_minutes.into())),
+        ("align_to_hour".to_owned(), JsonValue::Bool(settings.align_to_hour)),
+        ("normalize".to_owned(), JsonValue::String(settings.normalize.name().to_owned())),
+        ("minimum_bucket_activations".to_owned(), JsonValue::Integer(settings.minimum_bucket_activations.into())),
+        ("low_confidence_threshold".to_owned(), optional_integer(settings.low_confidence_threshold.map(i64::from))),
+        ("smoothing_radius".to_owned(), optional_integer(settings.smoothing_radius.map(|radius| i64::try_from(radius).unwrap()))),
+        ("no_data_returns_zero".to_owned(), JsonValue::Bool(settings.no_data_returns_zero)),
+        ("exclude_current_week".to_owned(), JsonValue::Bool(settings.exclude_current_week)),
+        ("start_time".to_owned(), optional_timestamp(settings.analysis_range.start)),
+        ("end_time".to_owned(), optional_timestamp(settings.analysis_range.end)),
+        ("time_format_12h".to_owned(), JsonValue::Bool(settings.time_format_12h)),
+        ("decimal_places".to_owned(), optional_integer(settings.decimal_places.map(|decimal_places| i64::try_from(decimal_places).unwrap()))),
+    ])
+}
+
+/// the numbers `print_statistics` works from
+fn statistics_json(analysis: &Analysis) -> JsonValue {
+    let timestamp_json = |timestamp: Option<DateTime<Utc>>| timestamp.map_or(JsonValue::Null, |timestamp| JsonValue::String(timestamp.to_rfc3339()));
+    let count_json = |count: usize| JsonValue::Integer(i64::try_from(count).unwrap());
+    let clamp_stats = &analysis.clamp_stats;
+    let dropped_hours = f64::from_i64(clamp_stats.dropped_duration.num_milliseconds()).unwrap() / f64::from(MILLISECONDS_PER_HOUR);
+    JsonValue::Object(vec![
+        ("all_event_count".to_owned(), count_json(analysis.all_event_count)),
+        ("online_offline_event_count".to_owned(), count_json(analysis.online_offline_event_count)),
+        ("first_event_timestamp".to_owned(), timestamp_json(analysis.first_event_timestamp)),
+        ("last_event_timestamp".to_owned(), timestamp_json(analysis.last_event_timestamp)),
+        ("future_event_count".to_owned(), count_json(analysis.future_event_count)),
+        ("collapsed_event_count".to_owned(), count_json(analysis.collapsed_event_count)),
+        ("anomaly_count".to_owned(), count_json(analysis.anomalies.count())),
+        ("sessions_too_long".to_owned(), count_json(clamp_stats.too_long)),
+        ("sessions_full_overlap".to_owned(), count_json(clamp_stats.full_overlap)),
+        ("sessions_front_clamped".to_owned(), count_json(clamp_stats.front_clamp)),
+        ("sessions_tail_clamped".to_owned(), count_json(clamp_stats.tail_clamp)),
+        ("sessions_split".to_owned(), count_json(clamp_stats.split)),
+        ("sessions_dropped".to_owned(), count_json(clamp_stats.dropped)),
+        ("dropped_session_hours".to_owned(), JsonValue::Float(dropped_hours)),
+    ])
+}
+
+/// the online count and number of active dates of every bucket, with `prefix` on each member name
+fn raw_grid_json(prefix: &str, buckets: &[Vec<BucketValue>]) -> [(String, JsonValue); 2] {
+    let grid = |value: fn(&BucketValue) -> JsonValue| JsonValue::Array(buckets.iter()
+        .map(|buckets_for_day| JsonValue::Array(buckets_for_day.iter().map(value).collect()))
+        .collect());
+    [
+        (format!("{prefix}online_counts"), grid(|bucket_value| JsonValue::Float(bucket_value.online_count))),
+        (format!("{prefix}active_dates"), grid(|bucket_value| JsonValue::Integer(i64::try_from(bucket_value.total_dates()).unwrap()))),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Configuration;
+
+    #[test]
+    fn effective_config_fills_in_defaults() {
+        let config = "your_user_id = \"usr_me\"\nvrcx_db_path = \"VRCX.sqlite3\"\nvrcx_running_detection_threshold_minutes = 5\nbucket_duration_minutes = 30\nnormalize = true\ndecimal_places = 2";
+        let settings = Settings::from_config(Configuration::parse(config).unwrap()).unwrap();
+        let effective_config = effective_config_json(&settings);
+        assert_eq!(effective_config.get("minimum_bucket_activations"), Some(&JsonValue::Integer(1)));
+        assert_eq!(effective_config.get("normalize"), Some(&JsonValue::String("activity".to_owned())));
+        assert_eq!(effective_config.get("smoothing_radius"), Some(&JsonValue::Null));
+        assert_eq!(effective_config.get("decimal_places"), Some(&JsonValue::Integer(2)));
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+//! iCalendar export, for blocking out likely social times as weekly recurring events.
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Utc, Weekday};
+use num_traits::cast::FromPrimitive;
+
+use crate::constants::MINUTES_PER_DAY;
+use crate::layout::BucketLayout;
+use crate::names::DisplayNames;
+use crate::output::ValueGrid;
+
+/// everything before the events
+const CALENDAR_START: &str = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//vrcx-optimal-time//EN\r\n";
+/// everything after the events
+const CALENDAR_END: &str = "END:VCALENDAR\r\n";
+
+/// a contiguous run of above-threshold buckets within one weekday
+struct CalendarBlock {
+    weekday: Weekday,
+    start_minutes: u32,
+    end_minutes: u32,
+}
+
+/// Merge adjacent buckets with a value above `threshold` into blocks. Blocks never cross midnight, so a run from Friday
+/// evening into Saturday morning becomes two blocks.
+fn coalesce_blocks(bucket_layout: &BucketLayout, threshold: f64, values: &ValueGrid) -> Vec<CalendarBlock> {
+    let mut blocks: Vec<CalendarBlock> = Vec::new();
+    for (day_index, values_for_day) in values.iter().enumerate() {
+        let weekday = Weekday::from_usize(day_index).unwrap();
+        let mut block_start: Option<u32> = None;
+        for (bucket_index, value) in values_for_day.iter().enumerate() {
+            let above_threshold = value.is_some_and(|value| value > threshold);
+            match (above_threshold, block_start) {
+                (true, None) => block_start = Some(bucket_layout.start_minutes(bucket_index)),
+                (false, Some(start_minutes)) => {
+                    blocks.push(CalendarBlock { weekday, start_minutes, end_minutes: bucket_layout.start_minutes(bucket_index) });
+                    block_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start_minutes) = block_start {
+            blocks.push(CalendarBlock { weekday, start_minutes, end_minutes: MINUTES_PER_DAY });
+        }
+    }
+    blocks
+}
+
+/// Build an `.ics` calendar with one weekly recurring event per block of buckets whose value exceeds `threshold`. Times
+/// are floating local times, so the events stay put on the wall clock across DST changes. Each series starts on the next
+/// occurrence of its weekday, counting today.
+pub fn calendar_ics(bucket_layout: &BucketLayout, threshold: f64, values: &ValueGrid, today: NaiveDate) -> String {
+    let timestamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut ics = String::new();
+    ics.push_str(CALENDAR_START);
+    for block in coalesce_blocks(bucket_layout, threshold, values) {
+        let uid = format!("{}-{:04}", block.weekday, block.start_minutes);
+        write_event(&mut ics, &timestamp, &block, today, &uid, "Friends likely online");
+    }
+    ics.push_str(CALENDAR_END);
+    ics
+}
+
+/// Like [`calendar_ics`], but with a separate set of events for each friend's own grid, named after them, so subscribing
+/// to it shows when each friend is usually on.
+pub fn friend_calendar_ics(bucket_layout: &BucketLayout, threshold: f64, display_names: &DisplayNames, friend_grids: &[(String, ValueGrid)], today: NaiveDate) -> String {
+    let timestamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut ics = String::new();
+    ics.push_str(CALENDAR_START);
+    for (user_id, values) in friend_grids {
+        let summary = format!("{} likely online", display_names.label(user_id));
+        for block in coalesce_blocks(bucket_layout, threshold, values) {
+            let uid = format!("{user_id}-{}-{:04}", block.weekday, block.start_minutes);
+            write_event(&mut ics, &timestamp, &block, today, &uid, &summary);
+        }
+    }
+    ics.push_str(CALENDAR_END);
+    ics
+}
+
+/// a weekly recurring event for one block, starting on the next occurrence of its weekday, counting today
+fn write_event(ics: &mut String, timestamp: &str, block: &CalendarBlock, today: NaiveDate, uid: &str, summary: &str) {
+    let days_until = (i64::from(block.weekday.num_days_from_monday()) - i64::from(today.weekday().num_days_from_monday())).rem_euclid(7);
+    let date = today + Duration::days(days_until);
+    let start: NaiveDateTime = date.and_hms_opt(0, 0, 0).unwrap() + Duration::minutes(i64::from(block.start_minutes));
+    let end: NaiveDateTime = date.and_hms_opt(0, 0, 0).unwrap() + Duration::minutes(i64::from(block.end_minutes));
+    for line in [
+        "BEGIN:VEVENT".to_owned(),
+        format!("UID:{uid}@vrcx-optimal-time"),
+        format!("DTSTAMP:{timestamp}"),
+        format!("DTSTART:{}", start.format("%Y%m%dT%H%M%S")),
+        format!("DTEND:{}", end.format("%Y%m%dT%H%M%S")),
+        "RRULE:FREQ=WEEKLY".to_owned(),
+        format!("SUMMARY:{}", escape_text(summary)),
+        "TRANSP:TRANSPARENT".to_owned(),
+        "END:VEVENT".to_owned(),
+    ] {
+        write_content_line(ics, &line);
+    }
+}
+
+/// Write a content line and its CRLF, folded as RFC 5545 requires: no physical line is longer than 75 octets, and each
+/// continuation starts with a space. Folds only fall between characters, so multibyte UTF-8 is never split.
+fn write_content_line(ics: &mut String, line: &str) {
+    /// the longest a physical line can be, not counting its CRLF
+    const MAX_LINE_OCTETS: usize = 75;
+    let mut line_octets = 0;
+    for c in line.chars() {
+        if line_octets + c.len_utf8() > MAX_LINE_OCTETS {
+            ics.push_str("\r\n ");
+            // the leading space counts toward the continuation's length
+            line_octets = 1;
+        }
+        ics.push(c);
+        line_octets += c.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+/// escape the characters iCalendar gives a meaning in text values, as display names can contain any of them
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content_line(line: &str) -> String {
+        let mut ics = String::new();
+        write_content_line(&mut ics, line);
+        ics
+    }
+
+    #[test]
+    fn content_lines_fold_at_75_octets() {
+        assert_eq!(content_line("SUMMARY:short"), "SUMMARY:short\r\n");
+        let exactly_75 = "x".repeat(75);
+        assert_eq!(content_line(&exactly_75), format!("{exactly_75}\r\n"));
+
+        let long = format!("SUMMARY:{} likely online", "a".repeat(150));
+        let folded = content_line(&long);
+        assert!(folded.split("\r\n").all(|physical_line| physical_line.len() <= 75), "{folded}");
+        assert!(folded.split("\r\n").skip(1).filter(|physical_line| !physical_line.is_empty()).all(|physical_line| physical_line.starts_with(' ')));
+        // unfolding gives back the original line
+        assert_eq!(folded.replace("\r\n ", ""), format!("{long}\r\n"));
+    }
+
+    #[test]
+    fn content_lines_never_split_a_character() {
+        // 3-octet characters after the 8 octet name put a fold boundary in the middle of one
+        let long = format!("SUMMARY:{}", "\u{3042}".repeat(40));
+        let folded = content_line(&long);
+        for physical_line in folded.split("\r\n") {
+            assert!(physical_line.len() <= 75, "{physical_line}");
+        }
+        assert_eq!(folded.replace("\r\n ", ""), format!("{long}\r\n"));
+    }
+}
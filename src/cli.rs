@@ -0,0 +1,100 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+use std::env;
+
+use crate::error::Error;
+
+/// command-line arguments
+#[derive(Default)]
+pub struct Arguments {
+    /// TOML config file to read instead of config.toml, where `-` means stdin
+    pub config_path: Option<String>,
+    /// inline JSON or TOML configuration to use instead of reading config.toml
+    pub config_json: Option<String>,
+    /// print the SQL queries we build before running them
+    pub print_sql: bool,
+    /// list the users seen in the online/offline events instead of running the analysis
+    pub list_users: bool,
+    /// print the detected VRCX uptime intervals instead of the bucket grid
+    pub dump_uptime: bool,
+    /// a weekday and time like `saturday 21:00`, to describe how that bucket's value was computed instead of printing the
+    /// bucket grid
+    pub explain: Option<String>,
+    /// two bundles to compare, old then new, instead of running the analysis
+    pub diff: Option<(String, String)>,
+    /// suppress everything on stderr except errors
+    pub quiet: bool,
+    /// cross-check session clamping against a brute-force implementation. Deliberately undocumented: it's a debugging aid.
+    pub self_check: bool,
+    /// address to serve results over HTTP on, instead of printing them once
+    #[cfg(feature = "serve")]
+    pub serve: Option<String>,
+    /// file to write a collapsed-stack profile of the run to
+    #[cfg(feature = "profile")]
+    pub profile: Option<String>,
+}
+
+impl Arguments {
+    /// parse the arguments this process was started with
+    pub fn from_env() -> Result<Self, Error> {
+        let mut arguments = Arguments::default();
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    arguments.config_path = Some(required_value(&arg, args.next())?);
+                }
+                "--config-json" => {
+                    arguments.config_json = Some(required_value(&arg, args.next())?);
+                }
+                "--diff" => {
+                    let old_bundle_path = required_value(&arg, args.next())?;
+                    let new_bundle_path = args.next().ok_or_else(|| Error::InvalidArguments("--diff requires an old and a new bundle".to_owned()))?;
+                    arguments.diff = Some((old_bundle_path, new_bundle_path));
+                }
+                "--dump-uptime" => {
+                    arguments.dump_uptime = true;
+                }
+                "--explain" => {
+                    arguments.explain = Some(required_value(&arg, args.next())?);
+                }
+                "--list-users" => {
+                    arguments.list_users = true;
+                }
+                #[cfg(feature = "profile")]
+                "--profile" => {
+                    arguments.profile = Some(required_value(&arg, args.next())?);
+                }
+                #[cfg(not(feature = "profile"))]
+                "--profile" => return Err(Error::InvalidArguments("--profile is only available when built with `--features profile`".to_owned())),
+                "--print-sql" => {
+                    arguments.print_sql = true;
+                }
+                "--quiet" => {
+                    arguments.quiet = true;
+                }
+                "--self-check" => {
+                    arguments.self_check = true;
+                }
+                #[cfg(feature = "serve")]
+                "--serve" => {
+                    arguments.serve = Some(required_value(&arg, args.next())?);
+                }
+                #[cfg(not(feature = "serve"))]
+                "--serve" => return Err(Error::InvalidArguments("--serve is only available when built with `--features serve`".to_owned())),
+                _ => return Err(Error::InvalidArguments(format!("unrecognized argument: {arg}"))),
+            }
+        }
+        if arguments.config_path.is_some() && arguments.config_json.is_some() {
+            return Err(Error::InvalidArguments("--config and --config-json can't be used together".to_owned()));
+        }
+        Ok(arguments)
+    }
+}
+
+/// get the value following a flag, or complain that it's missing
+fn required_value(flag: &str, value: Option<String>) -> Result<String, Error> {
+    value.ok_or_else(|| Error::InvalidArguments(format!("{flag} requires a value")))
+}
@@ -2,21 +2,518 @@
 // This file is part of vrcx-optimal-time.
 // vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
+use serde::de::{self, Visitor};
+use serde::Deserialize as _;
 use serde_derive::Deserialize;
 
+use crate::json::{JsonError, JsonValue};
+
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Configuration {
     pub your_user_id: String,
-    pub vrcx_db_path: String,
+    pub vrcx_db_path: Option<PathList>,
+    pub wal_snapshot_threshold_mb: Option<u64>,
     pub friend_ids: Option<HashSet<String>>,
-    pub vrcx_running_detection_threshold_minutes: u32,
+    pub friend_ids_by_display_name: Option<bool>,
+    pub friend_ids_file: Option<String>,
+    pub friend_weights: Option<HashMap<String, f64>>,
+    pub user_id_aliases: Option<HashMap<String, String>>,
+    pub exclude_self: Option<bool>,
+    pub compare_friend_ids_a: Option<HashSet<String>>,
+    pub compare_friend_ids_b: Option<HashSet<String>>,
+    pub overlap_friend_ids: Option<Vec<String>>,
+    pub absent_friend_id: Option<String>,
+    pub fail_on_unmatched_friend_ids: Option<bool>,
+    pub self_activity_table: Option<SelfActivityTable>,
+    pub vrcx_running_detection_threshold_minutes: Option<u32>,
+    pub assume_always_running: Option<bool>,
+    pub partial_bucket_threshold_fraction: Option<f64>,
+    pub max_tracked_dates_per_bucket: Option<u32>,
+    pub collapse_events_within_seconds: Option<u32>,
+    pub extend_final_session_to_now: Option<bool>,
     pub bucket_duration_minutes: u32,
-    pub normalize: bool,
+    pub align_to_hour: Option<bool>,
+    pub normalize: NormalizationMode,
+    pub normalization_baseline: Option<NormalizationBaseline>,
     pub start_time: Option<String>,
+    pub end_time: Option<String>,
+    pub clamp_sessions_to_range: Option<bool>,
+    pub exclude_current_week: Option<bool>,
+    pub maximum_session_hours: Option<f64>,
+    pub recency_linear_weeks: Option<u32>,
+    pub recency_half_life_weeks: Option<u32>,
     pub minimum_bucket_activations: Option<u32>,
+    pub low_confidence_threshold: Option<u32>,
+    pub smoothing_radius: Option<usize>,
     pub no_data_returns_zero: Option<bool>,
     pub print_statistics: Option<bool>,
+    pub statistics_minimum_events: Option<usize>,
+    pub stats_hours: Option<[u32; 2]>,
     pub print_runtime: Option<bool>,
+    pub input_format: Option<InputFormat>,
+    pub csv_online_offline_path: Option<String>,
+    pub csv_all_events_path: Option<String>,
+    pub online_event_types: Option<Vec<String>>,
+    pub offline_event_types: Option<Vec<String>>,
+    pub output_format: Option<OutputFormat>,
+    pub output_file: Option<String>,
+    pub filtered_output_file: Option<String>,
+    pub filtered_minimum_bucket_activations: Option<u32>,
+    pub bundle_file: Option<String>,
+    pub day_block_start_hours: Option<Vec<u32>>,
+    pub highlight_window_minutes: Option<u32>,
+    pub range_tolerance: Option<f64>,
+    pub transpose: Option<bool>,
+    pub time_format_12h: Option<bool>,
+    pub label_style: Option<LabelStyle>,
+    pub decimal_separator: Option<char>,
+    pub decimal_places: Option<usize>,
+    pub split_by_month: Option<bool>,
+    pub chunk_count: Option<u32>,
+    pub window_weeks: Option<u32>,
+    pub window_step_weeks: Option<u32>,
+    pub window_output_dir: Option<String>,
+    pub recommendation_count: Option<usize>,
+    pub recommendation_weekdays: Option<Vec<String>>,
+    pub recommend_min_friends: Option<f64>,
+    pub stale_weekday_days: Option<u32>,
+    pub recommendation_sort: Option<RecommendationSort>,
+    pub recommendation_rank: Option<RecommendationRank>,
+    pub calendar_path: Option<String>,
+    pub calendar_threshold: Option<f64>,
+    pub friend_calendar_path: Option<String>,
+    pub friend_calendar_threshold: Option<f64>,
+    pub weight_by_overlap: Option<bool>,
+    pub closed_bucket_boundaries: Option<bool>,
+    pub infer_session_starts: Option<bool>,
+    pub verbose: Option<bool>,
+    pub strict: Option<bool>,
+    pub warnings_format: Option<WarningsFormat>,
+    /// the keys exactly as they were given, for recording what produced a result
+    #[serde(skip)]
+    pub table: toml::Table,
+}
+
+impl Configuration {
+    /// parse a configuration from TOML, rejecting any keys we don't recognize
+    pub fn parse(config_string: &str) -> Result<Self, ConfigError> {
+        let table: toml::Table = toml::from_str(config_string).map_err(ConfigError::Parse)?;
+        Self::from_table(table)
+    }
+
+    /// parse a configuration given on the command line, which is JSON if it's an object and TOML otherwise
+    pub fn parse_inline(config_string: &str) -> Result<Self, ConfigError> {
+        // a TOML document can't start with a brace, so there's no ambiguity
+        if config_string.trim_start().starts_with('{') {
+            Self::parse_json(config_string)
+        } else {
+            Self::parse(config_string)
+        }
+    }
+
+    /// parse a configuration from a JSON object with the same keys as the TOML config, rejecting any keys we don't recognize
+    pub fn parse_json(config_string: &str) -> Result<Self, ConfigError> {
+        let json = JsonValue::parse(config_string).map_err(ConfigError::Json)?;
+        match json.into_toml() {
+            Some(toml::Value::Table(table)) => Self::from_table(table),
+            _ => Err(ConfigError::NotAnObject),
+        }
+    }
+
+    fn from_table(table: toml::Table) -> Result<Self, ConfigError> {
+        // check for unknown keys ourselves first, as we can give much better error messages than serde can
+        let field_names = field_names();
+        let unknown_keys: Vec<UnknownKey> = table.keys()
+            .filter(|key| !field_names.contains(&key.as_str()))
+            .map(|key| UnknownKey {
+                key: key.to_owned(),
+                suggestion: closest_field_name(key, field_names),
+            })
+            .collect();
+        if !unknown_keys.is_empty() {
+            return Err(ConfigError::UnknownKeys(unknown_keys));
+        }
+
+        let mut config = Configuration::deserialize(table.clone()).map_err(ConfigError::Parse)?;
+        config.table = table;
+        Ok(config)
+    }
+}
+
+/// a problem encountered while loading the configuration
+pub enum ConfigError {
+    Parse(toml::de::Error),
+    Json(JsonError),
+    NotAnObject,
+    UnknownKeys(Vec<UnknownKey>),
+}
+
+/// a config key that doesn't correspond to any [`Configuration`] field
+pub struct UnknownKey {
+    key: String,
+    suggestion: Option<&'static str>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Parse(e) => write!(f, "could not parse config: {e}"),
+            ConfigError::Json(e) => write!(f, "could not parse JSON config: {e}"),
+            ConfigError::NotAnObject => write!(f, "JSON config must be an object"),
+            ConfigError::UnknownKeys(unknown_keys) => {
+                write!(f, "config contains unrecognized keys:")?;
+                for unknown_key in unknown_keys {
+                    write!(f, "\n  `{}`", unknown_key.key)?;
+                    if let Some(suggestion) = unknown_key.suggestion {
+                        write!(f, " (did you mean `{suggestion}`?)")?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// find the field name most similar to an unknown key, if any is reasonably close
+fn closest_field_name(key: &str, field_names: &'static [&'static str]) -> Option<&'static str> {
+    let max_distance = (key.chars().count() / 3).max(2);
+    field_names.iter()
+        .map(|field_name| (edit_distance(key, field_name), *field_name))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, field_name)| field_name)
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous_row: Vec<usize> = (0..=b.len()).collect();
+    let mut current_row: Vec<usize> = vec![0; b.len() + 1];
+    for (i, a_char) in a.chars().enumerate() {
+        current_row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution_cost = usize::from(a_char != *b_char);
+            current_row[j + 1] = (previous_row[j] + substitution_cost)
+                .min(previous_row[j + 1] + 1)
+                .min(current_row[j] + 1);
+        }
+        std::mem::swap(&mut previous_row, &mut current_row);
+    }
+    previous_row[b.len()]
+}
+
+/// Names of every [`Configuration`] field. These are pulled out of the derived `Deserialize` impl so that this list can't
+/// drift out of date as fields are added.
+fn field_names() -> &'static [&'static str] {
+    let mut extractor = FieldNameExtractor(&[]);
+    // this always fails, as the extractor refuses to actually deserialize anything
+    let _ = Configuration::deserialize(&mut extractor);
+    extractor.0
+}
+
+/// a deserializer that records the field names it's asked for, then bails out
+struct FieldNameExtractor(&'static [&'static str]);
+
+impl<'de> de::Deserializer<'de> for &mut FieldNameExtractor {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("expected a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], _visitor: V) -> Result<V::Value, Self::Error> {
+        self.0 = fields;
+        Err(de::Error::custom("field names extracted"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit unit_struct
+        newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// where VRCX events are read from
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InputFormat {
+    /// the VRCX sqlite database at `vrcx_db_path`
+    #[default]
+    Sqlite,
+    /// CSV exports at `csv_online_offline_path` and `csv_all_events_path`
+    Csv,
+}
+
+/// a VRCX feed table whose events can be counted in place of friend sessions
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfActivityTable {
+    /// avatar change events
+    FeedAvatar,
+    /// world and instance change events
+    FeedGps,
+}
+
+impl SelfActivityTable {
+    /// the table name, minus the per-user prefix
+    pub const fn table_suffix(self) -> &'static str {
+        match self {
+            Self::FeedAvatar => "feed_avatar",
+            Self::FeedGps => "feed_gps",
+        }
+    }
+}
+
+/// how the bucket grid is written to stdout
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// one value per bucket, after normalization
+    #[default]
+    Table,
+    /// the raw `online_count` and `total_dates` for each bucket, with no normalization or filtering applied
+    Raw,
+    /// each day collapsed into morning, afternoon, evening, and night
+    Blocks,
+    /// one line per weekday, with a block character per bucket
+    Sparkline,
+    /// each friend's single best bucket
+    Friends,
+    /// how much each bucket's friend online count varies from date to date
+    Stddev,
+    /// each bucket's mean friend online count divided by its standard error
+    Reliability,
+    /// the same values as `Table`, as a Markdown table
+    Markdown,
+    /// each weekday's best contiguous window of `highlight_window_minutes`
+    Highlights,
+    /// the same values as `Table`, as a binary NumPy array
+    Npy,
+    /// each weekday as runs of consecutive buckets with values within `range_tolerance` of each other
+    Ranges,
+}
+
+/// how each bucket's row (or column, if transposed) is labeled
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelStyle {
+    /// the bucket's start time, like `18:30`
+    #[default]
+    Clock,
+    /// the bucket's start as a number of minutes after midnight, like `1110`
+    Minutes,
+}
+
+/// how warnings are printed at the end of a run
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WarningsFormat {
+    /// a "Warning: ..." line each
+    #[default]
+    Text,
+    /// a single JSON object listing every warning
+    Json,
+}
+
+/// what activity-normalized bucket values are divided by
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalizationBaseline {
+    /// each bucket's own number of VRCX-active dates
+    #[default]
+    Bucket,
+    /// the largest number of VRCX-active dates of any bucket
+    Maximum,
+    /// the median number of VRCX-active dates across buckets that were ever active
+    Median,
+}
+
+/// the order the recommended times are listed in
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecommendationSort {
+    /// best value first
+    #[default]
+    Value,
+    /// soonest first
+    Time,
+    /// the buckets VRCX was active for on the most dates first
+    Confidence,
+}
+
+/// what the recommended times are picked by
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecommendationRank {
+    /// the highest output value
+    #[default]
+    Value,
+    /// the highest mean friend online count relative to its standard error
+    Reliability,
+    /// the lowest output value, for when you'd rather nobody was around
+    Quiet,
+}
+
+/// how bucket values are normalized before being output
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NormalizationMode {
+    /// output raw friend online counts
+    None,
+    /// divide friend online counts by the number of dates VRCX was active for the bucket
+    Activity,
+    /// percentage of VRCX-active dates on which at least one friend was online for the bucket
+    Probability,
+    /// like `Activity`, but dates VRCX only ran for part of the bucket count fractionally, and so do friends
+    Coverage,
+    /// like `Activity`, but with the weekly mean subtracted, so values show how much busier or quieter than usual a bucket is
+    Delta,
+    /// divide friend online counts by how many times the bucket's weekday occurred in the analyzed dates
+    Weekday,
+}
+
+impl NormalizationMode {
+    /// the name `normalize` gives this mode in the config
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Activity => "activity",
+            Self::Probability => "probability",
+            Self::Coverage => "coverage",
+            Self::Delta => "delta",
+            Self::Weekday => "weekday",
+        }
+    }
+}
+
+/// a single path, or a list of them
+pub enum PathList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl PathList {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::One(path) => vec![path],
+            Self::Many(paths) => paths,
+        }
+    }
+}
+
+/// Written by hand rather than derived as an untagged enum, so a mistake names what was expected instead of "data did not
+/// match any variant".
+impl<'de> de::Deserialize<'de> for PathList {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PathListVisitor;
+
+        impl<'de> Visitor<'de> for PathListVisitor {
+            type Value = PathList;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a path, or a list of paths")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(PathList::One(value.to_owned()))
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut paths = Vec::new();
+                while let Some(path) = seq.next_element()? {
+                    paths.push(path);
+                }
+                Ok(PathList::Many(paths))
+            }
+        }
+
+        deserializer.deserialize_any(PathListVisitor)
+    }
+}
+
+/// `normalize` accepts either a legacy boolean, where `true` means `"activity"`, or a mode name. This is written by hand
+/// so a mistake lists every accepted value.
+impl<'de> de::Deserialize<'de> for NormalizationMode {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NormalizationModeVisitor;
+
+        impl Visitor<'_> for NormalizationModeVisitor {
+            type Value = NormalizationMode;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str(r#"true, false, "none", "activity", "probability", "coverage", "delta", or "weekday""#)
+            }
+
+            fn visit_bool<E: de::Error>(self, value: bool) -> Result<Self::Value, E> {
+                Ok(if value { NormalizationMode::Activity } else { NormalizationMode::None })
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                [
+                    NormalizationMode::None,
+                    NormalizationMode::Activity,
+                    NormalizationMode::Probability,
+                    NormalizationMode::Coverage,
+                    NormalizationMode::Delta,
+                    NormalizationMode::Weekday,
+                ]
+                    .into_iter()
+                    .find(|mode| mode.name() == value)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+            }
+        }
+
+        deserializer.deserialize_any(NormalizationModeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_inline_accepts_json_and_toml() {
+        let json = Configuration::parse_inline(r#" {"your_user_id": "usr_1", "vrcx_running_detection_threshold_minutes": 5, "bucket_duration_minutes": 30, "normalize": false}"#).unwrap();
+        let toml = Configuration::parse_inline("your_user_id = \"usr_1\"\nvrcx_running_detection_threshold_minutes = 5\nbucket_duration_minutes = 30\nnormalize = false").unwrap();
+        for config in [json, toml] {
+            assert_eq!(config.your_user_id, "usr_1");
+            assert_eq!(config.bucket_duration_minutes, 30);
+        }
+        assert!(matches!(Configuration::parse_inline("[1, 2]"), Err(ConfigError::Parse(_))));
+        assert!(matches!(Configuration::parse_inline("{\"your_user_id\": 01}"), Err(ConfigError::Json(_))));
+    }
+
+    #[test]
+    fn normalize_and_path_list_errors_name_the_expected_values() {
+        let config = |extra: &str| Configuration::parse(&format!("your_user_id = \"usr_1\"\nvrcx_running_detection_threshold_minutes = 5\nbucket_duration_minutes = 30\n{extra}"));
+        let error = |extra: &str| match config(extra) {
+            Err(ConfigError::Parse(e)) => e.message().to_owned(),
+            Err(e) => panic!("{extra}: {e}"),
+            Ok(_) => panic!("{extra} should be rejected"),
+        };
+
+        for (value, mode) in [("true", NormalizationMode::Activity), ("false", NormalizationMode::None), ("\"none\"", NormalizationMode::None), ("\"delta\"", NormalizationMode::Delta), ("\"weekday\"", NormalizationMode::Weekday)] {
+            assert!(config(&format!("normalize = {value}")).unwrap().normalize == mode, "{value}");
+        }
+        let message = error("normalize = \"bogus\"");
+        assert!(message.contains(r#"invalid value: string "bogus", expected true, false, "none", "activity", "probability", "coverage", "delta", or "weekday""#), "{message}");
+        let message = error("normalize = 1");
+        assert!(message.contains("expected true, false, \"none\""), "{message}");
+
+        let paths = |extra: &str| config(&format!("normalize = false\n{extra}")).unwrap().vrcx_db_path.map(PathList::into_vec);
+        assert_eq!(paths("vrcx_db_path = \"a.sqlite3\""), Some(vec!["a.sqlite3".to_owned()]));
+        assert_eq!(paths("vrcx_db_path = [\"a.sqlite3\", \"b.sqlite3\"]"), Some(vec!["a.sqlite3".to_owned(), "b.sqlite3".to_owned()]));
+        let message = error("normalize = false\nvrcx_db_path = 1");
+        assert!(message.contains("invalid type: integer `1`, expected a path, or a list of paths"), "{message}");
+        let message = error("normalize = false\nvrcx_db_path = [1]");
+        assert!(message.contains("expected a string"), "{message}");
+    }
 }
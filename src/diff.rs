@@ -0,0 +1,176 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+//! `--diff`: compare two bundles written by `bundle_file`, to see how a heatmap shifted between runs without reading any
+//! databases again.
+
+use std::fmt::Write;
+use std::fs;
+
+use crate::config::LabelStyle;
+use crate::error::Error;
+use crate::json::JsonValue;
+use crate::output::{write_table, NumberFormat, TableStyle, ValueGrid, ValueKind};
+
+/// how many of the biggest changes the summary lists
+const BIGGEST_CHANGE_COUNT: usize = 5;
+
+/// the parts of a bundle needed to compare it to another
+struct Bundle {
+    days: Vec<String>,
+    buckets: Vec<String>,
+    /// the grid as it was output, indexed by `[day][bucket]`
+    values: ValueGrid,
+    /// the number of dates VRCX was active for each bucket, indexed by `[day][bucket]`
+    active_dates: Vec<Vec<i64>>,
+    /// the bundle's effective `minimum_bucket_activations`
+    minimum_bucket_activations: i64,
+    /// the bundle's effective `decimal_places`, if it had one
+    decimal_places: Option<usize>,
+}
+
+impl Bundle {
+    fn read(path: &str) -> Result<Self, Error> {
+        let invalid = |message: &str| Error::Input(format!("{path} is not a bundle written by bundle_file: {message}"));
+        let text = fs::read_to_string(path).map_err(|e| Error::Input(format!("{path}: {e}")))?;
+        let json = JsonValue::parse(&text).map_err(|e| Error::Input(format!("{path}: {e}")))?;
+
+        let strings = |name: &str| -> Result<Vec<String>, Error> {
+            match json.get(name) {
+                Some(JsonValue::Array(values)) => values.iter()
+                    .map(|value| match value {
+                        JsonValue::String(value) => Ok(value.clone()),
+                        _ => Err(invalid(&format!("{name} should only hold strings"))),
+                    })
+                    .collect(),
+                _ => Err(invalid(&format!("{name} is missing"))),
+            }
+        };
+        let days = strings("days")?;
+        let buckets = strings("buckets")?;
+        let grid = |name: &str| -> Result<Vec<Vec<&JsonValue>>, Error> {
+            let shape_error = || invalid(&format!("{name} should have a row of {} buckets for each of the {} days", buckets.len(), days.len()));
+            let Some(JsonValue::Array(rows)) = json.get(name) else {
+                return Err(invalid(&format!("{name} is missing")));
+            };
+            if rows.len() != days.len() {
+                return Err(shape_error());
+            }
+            rows.iter()
+                .map(|row| match row {
+                    JsonValue::Array(cells) if cells.len() == buckets.len() => Ok(cells.iter().collect()),
+                    _ => Err(shape_error()),
+                })
+                .collect()
+        };
+        let values = grid("values")?.into_iter()
+            .map(|row| row.into_iter()
+                .map(|cell| match cell {
+                    JsonValue::Null => Ok(None),
+                    cell => cell.as_f64().map(Some).ok_or_else(|| invalid("values should only hold numbers and nulls")),
+                })
+                .collect::<Result<Vec<_>, _>>())
+            .collect::<Result<ValueGrid, _>>()?;
+        let active_dates = grid("active_dates")?.into_iter()
+            .map(|row| row.into_iter()
+                .map(|cell| match cell {
+                    JsonValue::Integer(cell) => Ok(*cell),
+                    _ => Err(invalid("active_dates should only hold whole numbers")),
+                })
+                .collect::<Result<Vec<_>, _>>())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let Some(effective_config) = json.get("effective_config") else {
+            return Err(invalid("effective_config is missing"));
+        };
+        let minimum_bucket_activations = match effective_config.get("minimum_bucket_activations") {
+            Some(JsonValue::Integer(minimum_bucket_activations)) => *minimum_bucket_activations,
+            _ => return Err(invalid("effective_config.minimum_bucket_activations should be a whole number")),
+        };
+        let decimal_places = match effective_config.get("decimal_places") {
+            Some(JsonValue::Null) => None,
+            Some(JsonValue::Integer(decimal_places)) => Some(usize::try_from(*decimal_places).map_err(|_| invalid("effective_config.decimal_places can't be negative"))?),
+            _ => return Err(invalid("effective_config.decimal_places should be a whole number or null")),
+        };
+        Ok(Self {
+            days,
+            buckets,
+            values,
+            active_dates,
+            minimum_bucket_activations,
+            decimal_places,
+        })
+    }
+
+    /// whether a bucket was active on enough dates to have a value
+    fn is_activated(&self, day_index: usize, bucket_index: usize) -> bool {
+        self.active_dates[day_index][bucket_index] >= self.minimum_bucket_activations
+    }
+}
+
+/// Compare two bundles, giving a grid of how much each bucket's value changed from `old_path` to `new_path`, followed by
+/// the buckets that gained or lost enough activity to have a value, and the biggest changes. Buckets without a value in
+/// either bundle are left blank in the grid. Numbers are written to the new bundle's `decimal_places`.
+pub fn bundle_diff(old_path: &str, new_path: &str) -> Result<String, Error> {
+    let old = Bundle::read(old_path)?;
+    let new = Bundle::read(new_path)?;
+    if old.days != new.days || old.buckets != new.buckets {
+        return Err(Error::Input(format!("{old_path} and {new_path} have different buckets, so they can't be compared. Both need the same bucket_duration_minutes, align_to_hour, label_style, and time_format_12h settings.")));
+    }
+
+    let number_format = NumberFormat { decimal_separator: '.', decimal_places: new.decimal_places };
+    let format_change = |change: f64| {
+        let change_text = number_format.format(change, ValueKind::Measure);
+        if change > 0.0 { format!("+{change_text}") } else { change_text }
+    };
+    let changes: ValueGrid = old.values.iter().zip(&new.values)
+        .map(|(old_values_for_day, new_values_for_day)| old_values_for_day.iter().zip(new_values_for_day)
+            .map(|(old_value, new_value)| old_value.zip(*new_value).map(|(old_value, new_value)| new_value - old_value))
+            .collect())
+        .collect();
+
+    let mut out = String::new();
+    let style = TableStyle {
+        time_format_12h: false,
+        label_style: LabelStyle::default(),
+        transpose: false,
+        number_format,
+        value_kind: ValueKind::Measure,
+        markdown: false,
+    };
+    write_table(&mut out, &new.buckets, &new.days, style, |bucket_index, day_index| {
+        changes[day_index][bucket_index].map(format_change).unwrap_or_default()
+    });
+
+    let bucket_name = |day_index: usize, bucket_index: usize| format!("{} {}", new.days[day_index], new.buckets[bucket_index]);
+    let positions = || (0..new.days.len()).flat_map(|day_index| (0..new.buckets.len()).map(move |bucket_index| (day_index, bucket_index)));
+    let bucket_list = |positions: Vec<(usize, usize)>| if positions.is_empty() {
+        "none".to_owned()
+    } else {
+        positions.into_iter().map(|(day_index, bucket_index)| bucket_name(day_index, bucket_index)).collect::<Vec<_>>().join(", ")
+    };
+    writeln!(out).unwrap();
+    let newly_activated = positions().filter(|(day_index, bucket_index)| !old.is_activated(*day_index, *bucket_index) && new.is_activated(*day_index, *bucket_index)).collect();
+    writeln!(out, "Newly above minimum_bucket_activations: {}", bucket_list(newly_activated)).unwrap();
+    let newly_deactivated = positions().filter(|(day_index, bucket_index)| old.is_activated(*day_index, *bucket_index) && !new.is_activated(*day_index, *bucket_index)).collect();
+    writeln!(out, "Dropped below minimum_bucket_activations: {}", bucket_list(newly_deactivated)).unwrap();
+
+    // the earliest bucket in the week wins ties
+    let mut biggest_changes: Vec<(usize, usize, f64)> = positions()
+        .filter_map(|(day_index, bucket_index)| changes[day_index][bucket_index].map(|change| (day_index, bucket_index, change)))
+        .filter(|(_, _, change)| *change != 0.0)
+        .collect();
+    biggest_changes.sort_by(|(_, _, a), (_, _, b)| b.abs().total_cmp(&a.abs()));
+    if biggest_changes.is_empty() {
+        writeln!(out, "Biggest changes: none").unwrap();
+    } else {
+        writeln!(out, "Biggest changes:").unwrap();
+        for (day_index, bucket_index, change) in biggest_changes.into_iter().take(BIGGEST_CHANGE_COUNT) {
+            let old_value = number_format.format(old.values[day_index][bucket_index].unwrap(), ValueKind::Measure);
+            let new_value = number_format.format(new.values[day_index][bucket_index].unwrap(), ValueKind::Measure);
+            writeln!(out, "  {}: {old_value} to {new_value} ({})", bucket_name(day_index, bucket_index), format_change(change)).unwrap();
+        }
+    }
+    Ok(out)
+}
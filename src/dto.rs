@@ -2,35 +2,227 @@
 // This file is part of vrcx-optimal-time.
 // vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 
 use chrono::{DateTime, Duration, Local, Utc};
+use rusqlite::types::{FromSqlError, Type, ValueRef};
 
 use crate::constants::{COLUMN_INDEX_CREATED_AT, COLUMN_INDEX_DISPLAY_NAME, COLUMN_INDEX_EVENT_TYPE, COLUMN_INDEX_USER_ID};
 
 /// value of a bucket. This represents an n-minute window on a certain day of the week. For example, 8:00 to 8:10 on a Monday.
 #[derive(Clone, Default)]
 pub struct BucketValue {
-    /// total number of online friends seen for this bucket
-    pub online_count: u32,
-    /// records individual dates VRCX has been active on for this bucket
-    pub vrcx_activity_dates: HashSet<DateTime<Local>>,
+    /// total number of online friends seen for this bucket. This is fractional when friends are weighted.
+    pub online_count: f64,
+    /// records individual dates VRCX has been active on for this bucket. This is ordered so that iteration is deterministic.
+    /// If `date_sample` is set, this is only a sample of them.
+    pub vrcx_activity_dates: BTreeSet<DateTime<Local>>,
+    /// friend online counts for this bucket, broken down by date
+    pub online_counts_by_date: BTreeMap<DateTime<Local>, f64>,
+    /// the fraction of this bucket VRCX was running for on each date, from 0 to 1. This is only tracked for coverage
+    /// normalization.
+    pub vrcx_coverage_by_date: BTreeMap<DateTime<Local>, f64>,
+    /// if set, only a bounded sample of the dates is kept, and the date counts are estimated from it
+    pub date_sample: Option<DateSample>,
+}
+
+/// Bounds how many dates a bucket keeps, for `max_tracked_dates_per_bucket`. This is reservoir sampling where each date's
+/// priority is a hash of it, keeping the `limit` dates with the lowest priorities: a date registered twice gets the same
+/// priority both times, so it can't be counted twice, and the sample doesn't depend on the order dates arrive in. The
+/// number of distinct dates can then be estimated from how densely the kept priorities are packed.
+#[derive(Clone)]
+pub struct DateSample {
+    limit: usize,
+    /// the priority of every date in `vrcx_activity_dates`
+    priorities: BTreeSet<(u64, DateTime<Local>)>,
+    /// set once a date has been left out, after which counts are estimates
+    overflowed: bool,
+    /// the earliest and latest dates ever registered, which are tracked exactly as the sample may leave them out
+    first_date: Option<DateTime<Local>>,
+    last_date: Option<DateTime<Local>>,
+}
+
+impl DateSample {
+    /// keep at most `limit` dates, which must be at least 2
+    pub fn new(limit: usize) -> Self {
+        Self { limit, priorities: BTreeSet::new(), overflowed: false, first_date: None, last_date: None }
+    }
+
+    /// A pseudorandom but fixed priority for a date, so the same dates are sampled on every run and with every build.
+    /// This is splitmix64's finalizer, as `DefaultHasher` is allowed to change between Rust releases.
+    fn priority(datetime: DateTime<Local>) -> u64 {
+        let mut z = (datetime.timestamp_millis() as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// the highest priority kept, once the sample is full
+    fn threshold(&self) -> Option<u64> {
+        (self.priorities.len() >= self.limit).then(|| self.priorities.last().unwrap().0)
+    }
+
+    /// whether a date would be kept if it was registered, so data for dates the sample can't hold isn't kept either
+    fn admits(&self, datetime: DateTime<Local>) -> bool {
+        self.threshold().is_none_or(|threshold| Self::priority(datetime) < threshold)
+    }
 }
 
 impl BucketValue {
-    /// indicate that a friend is online during this bucket
-    pub fn increment(&mut self) {
-        self.online_count += 1;
+    /// indicate that some (possibly fractional) number of friends were online during this bucket on the provided date
+    pub fn add_online_count(&mut self, datetime: DateTime<Local>, count: f64) {
+        self.online_count += count;
+        if self.date_sample.as_ref().is_none_or(|date_sample| date_sample.admits(datetime)) {
+            *self.online_counts_by_date.entry(datetime).or_default() += count;
+        }
     }
 
     /// remember that VRCX was running during the provided date for this bucket
     pub fn register_date(&mut self, datetime: DateTime<Local>) {
+        let Some(date_sample) = &mut self.date_sample else {
+            self.vrcx_activity_dates.insert(datetime);
+            return;
+        };
+        date_sample.first_date = Some(date_sample.first_date.map_or(datetime, |first_date| first_date.min(datetime)));
+        date_sample.last_date = Some(date_sample.last_date.map_or(datetime, |last_date| last_date.max(datetime)));
+        if self.vrcx_activity_dates.contains(&datetime) {
+            return;
+        }
+        if !date_sample.admits(datetime) {
+            date_sample.overflowed = true;
+            return;
+        }
+        date_sample.priorities.insert((DateSample::priority(datetime), datetime));
         self.vrcx_activity_dates.insert(datetime);
+        if date_sample.priorities.len() > date_sample.limit {
+            // the evicted date's friend counts still count toward online_count, just not toward the per-date breakdown
+            let (_, evicted) = date_sample.priorities.pop_last().unwrap();
+            date_sample.overflowed = true;
+            self.vrcx_activity_dates.remove(&evicted);
+            self.online_counts_by_date.remove(&evicted);
+            self.vrcx_coverage_by_date.remove(&evicted);
+        }
+    }
+
+    /// the earliest date VRCX was running for this bucket
+    pub fn first_date(&self) -> Option<DateTime<Local>> {
+        match &self.date_sample {
+            Some(date_sample) => date_sample.first_date,
+            None => self.vrcx_activity_dates.first().copied(),
+        }
+    }
+
+    /// the latest date VRCX was running for this bucket
+    pub fn last_date(&self) -> Option<DateTime<Local>> {
+        match &self.date_sample {
+            Some(date_sample) => date_sample.last_date,
+            None => self.vrcx_activity_dates.last().copied(),
+        }
+    }
+
+    /// whether `vrcx_activity_dates` is only a sample, so date counts are estimates
+    pub fn is_sampled(&self) -> bool {
+        self.date_sample.as_ref().is_some_and(|date_sample| date_sample.overflowed)
+    }
+
+    /// Remember that VRCX was running for some fraction of this bucket on the provided date. Fractions from overlapping
+    /// ranges, such as two machines running at once, are capped so a date never counts for more than the whole bucket.
+    pub fn add_coverage(&mut self, datetime: DateTime<Local>, fraction: f64) {
+        let coverage = self.vrcx_coverage_by_date.entry(datetime).or_default();
+        *coverage = (*coverage + fraction).min(1.0);
     }
 
-    /// number of distinct dates VRCX was running during for this bucket
+    /// the number of dates VRCX was running for this bucket, with partly covered dates counting fractionally
+    pub fn total_coverage(&self) -> f64 {
+        self.vrcx_coverage_by_date.values().sum()
+    }
+
+    /// Number of distinct dates VRCX was running during for this bucket. If only a sample of the dates was kept, this is
+    /// estimated from how densely the sample's priorities are packed: `limit` dates spread over a `u` fraction of all
+    /// possible priorities suggest about `(limit - 1) / u` dates in total.
     pub fn total_dates(&self) -> usize {
-        self.vrcx_activity_dates.len()
+        match &self.date_sample {
+            Some(date_sample) if date_sample.overflowed => {
+                let threshold_fraction = date_sample.threshold().unwrap() as f64 / u64::MAX as f64;
+                let estimate = (date_sample.limit - 1) as f64 / threshold_fraction;
+                // a sample can't stand for fewer dates than it holds
+                (estimate.round() as usize).max(self.vrcx_activity_dates.len())
+            }
+            _ => self.vrcx_activity_dates.len(),
+        }
+    }
+
+    /// The number of dates VRCX was running for this bucket that match `predicate`. If only a sample of the dates was kept,
+    /// this scales the matches in the sample up to the estimated total.
+    pub fn count_dates(&self, predicate: impl Fn(&DateTime<Local>) -> bool) -> usize {
+        self.scale_sample_count(self.vrcx_activity_dates.iter().filter(|date| predicate(date)).count())
+    }
+
+    /// number of distinct dates at least one friend was online during for this bucket. Friends with a weight of zero don't
+    /// count.
+    pub fn total_online_dates(&self) -> usize {
+        self.scale_sample_count(self.online_counts_by_date.values().filter(|count| **count > 0.0).count())
+    }
+
+    /// scale a count of dates in the sample up to the estimated number across every date
+    fn scale_sample_count(&self, count: usize) -> usize {
+        if self.is_sampled() {
+            (count as f64 * self.total_dates() as f64 / self.vrcx_activity_dates.len() as f64).round() as usize
+        } else {
+            count
+        }
+    }
+
+    /// The mean friend online count across the dates VRCX was active for this bucket, with each date counting for
+    /// `date_weight` of it in both the total count and the number of dates. `None` if no date has any weight.
+    pub fn weighted_online_count_mean(&self, date_weight: impl Fn(DateTime<Local>) -> f64) -> Option<f64> {
+        let (weighted_count, total_weight) = self.vrcx_activity_dates.iter()
+            .map(|date| (date_weight(*date), self.online_counts_by_date.get(date).copied().unwrap_or(0.0)))
+            .fold((0.0, 0.0), |(weighted_count, total_weight), (weight, count)| (weighted_count + weight * count, total_weight + weight));
+        (total_weight > 0.0).then(|| weighted_count / total_weight)
+    }
+
+    /// Like [`Self::weighted_online_count_mean`], but for the fraction of dates at least one friend was online, from 0 to 1
+    pub fn weighted_online_date_fraction(&self, date_weight: impl Fn(DateTime<Local>) -> f64) -> Option<f64> {
+        let (online_weight, total_weight) = self.vrcx_activity_dates.iter()
+            .map(|date| (date_weight(*date), self.online_counts_by_date.get(date).is_some_and(|count| *count > 0.0)))
+            .fold((0.0, 0.0), |(online_weight, total_weight), (weight, online)| (online_weight + if online { weight } else { 0.0 }, total_weight + weight));
+        (total_weight > 0.0).then(|| online_weight / total_weight)
+    }
+
+    /// The population standard deviation of the friend online count across the dates VRCX was active for this bucket,
+    /// where dates with no friends online count as zero. `None` if VRCX was never active for this bucket. If only a sample
+    /// of the dates was kept, the deviations are averaged over the sample.
+    pub fn online_count_standard_deviation(&self) -> Option<f64> {
+        let date_count = self.total_dates() as f64;
+        if date_count == 0.0 {
+            return None;
+        }
+        let mean = self.online_count / date_count;
+        let sum_of_squares: f64 = self.vrcx_activity_dates.iter()
+            .map(|date| self.online_counts_by_date.get(date).copied().unwrap_or(0.0) - mean)
+            .map(|deviation| deviation * deviation)
+            .sum();
+        Some((sum_of_squares / self.vrcx_activity_dates.len() as f64).sqrt())
+    }
+
+    /// The mean friend online count divided by its standard error, which is high for buckets that are both busy and
+    /// consistently so across many dates. The standard error uses the sample standard deviation, so this is `None` unless
+    /// VRCX was active on at least two dates. A bucket that had the same non-zero count on every date is infinitely
+    /// reliable.
+    pub fn online_count_reliability(&self) -> Option<f64> {
+        let date_count = self.total_dates() as f64;
+        if date_count < 2.0 {
+            return None;
+        }
+        let mean = self.online_count / date_count;
+        if mean == 0.0 {
+            return Some(0.0);
+        }
+        let sample_variance = self.online_count_standard_deviation()?.powi(2) * date_count / (date_count - 1.0);
+        let standard_error = (sample_variance / date_count).sqrt();
+        Some(mean / standard_error)
     }
 }
 
@@ -42,19 +234,18 @@ pub struct Row {
     pub event_type: OnlineOfflineEventType,
 }
 
-impl TryFrom<&rusqlite::Row<'_>> for Row {
-    type Error = rusqlite::Error;
-
-    fn try_from(row: &rusqlite::Row<'_>) -> Result<Self, Self::Error> {
-        let created_at: String = row.get(COLUMN_INDEX_CREATED_AT)?;
-        let created_at: DateTime<Utc> = created_at.parse::<DateTime<Utc>>().unwrap();
+impl Row {
+    /// read a row of the online/offline query, recognizing its event type with `event_type_names`
+    pub fn from_sql_row(row: &rusqlite::Row<'_>, event_type_names: &EventTypeNames) -> Result<Self, rusqlite::Error> {
+        let created_at = created_at_from_row(row)?;
 
         let user_id: String = row.get(COLUMN_INDEX_USER_ID)?;
 
         let display_name: String = row.get(COLUMN_INDEX_DISPLAY_NAME)?;
 
         let event_type: String = row.get(COLUMN_INDEX_EVENT_TYPE)?;
-        let event_type: OnlineOfflineEventType = event_type.as_str().try_into()?;
+        let event_type: OnlineOfflineEventType = event_type_names.event_type(&event_type)
+            .ok_or_else(|| rusqlite::Error::InvalidColumnType(COLUMN_INDEX_EVENT_TYPE, event_type.clone(), rusqlite::types::Type::Text))?;
 
         Ok(Self {
             created_at,
@@ -65,20 +256,66 @@ impl TryFrom<&rusqlite::Row<'_>> for Row {
     }
 }
 
+/// Integer `created_at` values at least this large are epoch milliseconds, and smaller ones are epoch seconds. As seconds
+/// this is the year 5138, and as milliseconds it's 1973, well before VRCX existed, so there's no overlap in practice.
+const EPOCH_MILLISECONDS_THRESHOLD: i64 = 100_000_000_000;
+
+/// Read the `created_at` column of a row. VRCX stores it as an RFC 3339 string, but integer epoch seconds or milliseconds
+/// are understood too, in case some schema version stores them that way. Which form a value is in is decided by its
+/// SQLite type, so tables can differ.
+pub fn created_at_from_row(row: &rusqlite::Row<'_>) -> Result<DateTime<Utc>, rusqlite::Error> {
+    match row.get_ref(COLUMN_INDEX_CREATED_AT)? {
+        ValueRef::Text(text) => {
+            let text = std::str::from_utf8(text).map_err(rusqlite::Error::Utf8Error)?;
+            text.parse::<DateTime<Utc>>()
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(COLUMN_INDEX_CREATED_AT, Type::Text, Box::new(e)))
+        }
+        ValueRef::Integer(epoch) => {
+            timestamp_from_epoch(epoch).ok_or_else(|| rusqlite::Error::FromSqlConversionFailure(COLUMN_INDEX_CREATED_AT, Type::Integer, Box::new(FromSqlError::OutOfRange(epoch))))
+        }
+        value => Err(rusqlite::Error::InvalidColumnType(COLUMN_INDEX_CREATED_AT, "created_at".to_owned(), value.data_type())),
+    }
+}
+
+/// Convert an integer `created_at` to a time, reading it as epoch milliseconds or seconds depending on its size. This is
+/// `None` if the time is out of range.
+pub fn timestamp_from_epoch(epoch: i64) -> Option<DateTime<Utc>> {
+    if epoch.abs() >= EPOCH_MILLISECONDS_THRESHOLD {
+        DateTime::from_timestamp_millis(epoch)
+    } else {
+        DateTime::from_timestamp(epoch, 0)
+    }
+}
+
 /// the type of an online/offline event
 pub enum OnlineOfflineEventType {
     Online,
     Offline,
 }
 
-impl TryFrom<&str> for OnlineOfflineEventType {
-    type Error = rusqlite::Error;
+/// the strings in the online/offline `type` column that mean each event type
+#[derive(Clone)]
+pub struct EventTypeNames {
+    online: Vec<String>,
+    offline: Vec<String>,
+}
+
+impl EventTypeNames {
+    pub const fn new(online: Vec<String>, offline: Vec<String>) -> Self {
+        Self {
+            online,
+            offline,
+        }
+    }
 
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match value {
-            "Online" => Ok(Self::Online),
-            "Offline" => Ok(Self::Offline),
-            _ => Err(rusqlite::Error::InvalidColumnType(COLUMN_INDEX_EVENT_TYPE, value.to_string(), rusqlite::types::Type::Text))
+    /// the event type a `type` column value means, or `None` if it isn't one we recognize
+    pub fn event_type(&self, value: &str) -> Option<OnlineOfflineEventType> {
+        if self.online.iter().any(|name| name == value) {
+            Some(OnlineOfflineEventType::Online)
+        } else if self.offline.iter().any(|name| name == value) {
+            Some(OnlineOfflineEventType::Offline)
+        } else {
+            None
         }
     }
 }
@@ -109,7 +346,7 @@ pub enum VrcxStartStopEventType {
     Stop,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub struct TimeSpan {
     pub start: DateTime<Utc>,
     pub stop: DateTime<Utc>,
@@ -131,3 +368,62 @@ impl TimeSpan {
         self.stop.signed_duration_since(self.start)
     }
 }
+
+impl fmt::Display for TimeSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} to {}", self.start.to_rfc3339(), self.stop.to_rfc3339())
+    }
+}
+
+/// the optional bounds of the time range being analyzed
+#[derive(Copy, Clone, Default)]
+pub struct AnalysisRange {
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+}
+
+impl AnalysisRange {
+    /// true if either bound is set
+    pub const fn is_bounded(self) -> bool {
+        self.start.is_some() || self.end.is_some()
+    }
+
+    /// check if a timestamp falls within this range
+    pub fn contains(self, timestamp: DateTime<Utc>) -> bool {
+        self.start.is_none_or(|start| start <= timestamp) && self.end.is_none_or(|end| timestamp <= end)
+    }
+
+    /// clamp a time span to this range. The result may be non-positive if the span lies entirely outside the range.
+    pub fn clamp(self, time_span: TimeSpan) -> TimeSpan {
+        let start = self.start.map_or(time_span.start, |start| start.max(time_span.start));
+        let stop = self.end.map_or(time_span.stop, |end| end.min(time_span.stop));
+        TimeSpan::new(start, stop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn epoch_seconds_and_milliseconds() {
+        let millisecond_timestamp = DateTime::parse_from_rfc3339("1973-03-03T09:46:40Z").unwrap().with_timezone(&Utc);
+        assert_eq!(timestamp_from_epoch(EPOCH_MILLISECONDS_THRESHOLD), Some(millisecond_timestamp));
+        let second_timestamp = DateTime::parse_from_rfc3339("5138-11-16T09:46:39Z").unwrap().with_timezone(&Utc);
+        assert_eq!(timestamp_from_epoch(EPOCH_MILLISECONDS_THRESHOLD - 1), Some(second_timestamp));
+
+        let vrcx_era = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        assert_eq!(timestamp_from_epoch(1_704_067_200), Some(vrcx_era));
+        assert_eq!(timestamp_from_epoch(1_704_067_200_000), Some(vrcx_era));
+        assert_eq!(timestamp_from_epoch(0), DateTime::from_timestamp(0, 0));
+        assert_eq!(timestamp_from_epoch(i64::MAX), None);
+    }
+
+    #[test]
+    fn date_sample_priority_is_fixed() {
+        // the first output of splitmix64 seeded with 0, which every implementation agrees on
+        let epoch = DateTime::from_timestamp(0, 0).unwrap().with_timezone(&Local);
+        assert_eq!(DateSample::priority(epoch), 0xe220_a839_7b1d_cdaf);
+        assert_ne!(DateSample::priority(epoch + Duration::milliseconds(1)), DateSample::priority(epoch));
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+use std::{fmt, io};
+
+use crate::config::ConfigError;
+
+/// A problem that prevents us from producing results. Each variant maps to a distinct process exit code so that scripts
+/// can tell setup problems apart. Panics still exit with code 101, and indicate a bug.
+pub enum Error {
+    /// the command-line arguments could not be understood
+    InvalidArguments(String),
+    /// the config file could not be read
+    ConfigNotFound(io::Error),
+    /// the config could not be parsed
+    ConfigParse(ConfigError),
+    /// the config parsed, but contains a value we can't use
+    ConfigInvalid(String),
+    /// the VRCX database or CSV input could not be found or opened
+    DatabaseNotFound(String),
+    /// the VRCX database doesn't contain the tables we expect for the configured user id
+    TablesMissing(String),
+    /// the VRCX tables exist, but don't look the way we expect
+    UnexpectedSchema(String),
+    /// there were no events to analyze
+    NoData,
+    /// reading the input failed partway through
+    Input(String),
+    /// an output file could not be written
+    Output(String),
+}
+
+impl Error {
+    /// the process exit code for this error
+    pub const fn exit_code(&self) -> u8 {
+        match self {
+            Error::Input(_) => 1,
+            Error::InvalidArguments(_) => 2,
+            Error::ConfigNotFound(_) => 3,
+            Error::ConfigParse(_) | Error::ConfigInvalid(_) => 4,
+            Error::DatabaseNotFound(_) => 5,
+            Error::TablesMissing(_) | Error::UnexpectedSchema(_) => 6,
+            Error::NoData => 7,
+            Error::Output(_) => 8,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidArguments(message) => write!(f, "{message}"),
+            Error::ConfigNotFound(e) => write!(f, "could not read config: {e}"),
+            Error::ConfigParse(e) => write!(f, "{e}"),
+            Error::ConfigInvalid(message) => write!(f, "invalid config: {message}"),
+            Error::DatabaseNotFound(message) => write!(f, "could not open input: {message}"),
+            Error::TablesMissing(message) => write!(f, "expected VRCX tables are missing ({message}). Is your_user_id correct?"),
+            Error::UnexpectedSchema(message) => write!(f, "the VRCX database has an unexpected schema ({message}). It may be from an unsupported VRCX version"),
+            Error::NoData => write!(f, "there are no VRCX events to analyze"),
+            Error::Input(message) => write!(f, "could not read input: {message}"),
+            Error::Output(message) => write!(f, "could not write output: {message}"),
+        }
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        Error::ConfigParse(e)
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+//! `--explain`: a step-by-step account of how a single bucket's output value was computed, for when a number looks wrong.
+
+use std::fmt::Write;
+
+use chrono::{NaiveTime, Timelike, Weekday};
+use num_traits::cast::FromPrimitive;
+
+use crate::analysis::{Analysis, Settings};
+use crate::config::NormalizationMode;
+use crate::constants::MINUTES_PER_HOUR;
+use crate::error::Error;
+use crate::layout::BucketLayout;
+use crate::output::{baseline_activity_count, bucket_output_values, mean_value, weekday_occurrence_counts};
+
+/// the `(day_index, bucket_index)` of the bucket a weekday and time like `saturday 21:00` or `Sat 9:30 PM` falls in
+pub fn parse_bucket_position(bucket_layout: &BucketLayout, text: &str) -> Result<(usize, usize), Error> {
+    let invalid = || Error::InvalidArguments(format!("--explain expects a weekday and time, like \"saturday 21:00\", but got \"{text}\""));
+    let (weekday, time) = text.trim().split_once(char::is_whitespace).ok_or_else(invalid)?;
+    let weekday: Weekday = weekday.parse().map_err(|_| invalid())?;
+    let time = time.trim();
+    let time = NaiveTime::parse_from_str(time, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%I:%M %p"))
+        .map_err(|_| invalid())?;
+    let minutes_of_day = time.hour() * MINUTES_PER_HOUR + time.minute();
+    Ok((usize::try_from(weekday.num_days_from_monday()).unwrap(), bucket_layout.bucket_index(minutes_of_day)))
+}
+
+/// describe where a bucket's output value came from, from the raw counts through to any thresholds that suppressed it
+pub fn explain_bucket(settings: &Settings, analysis: &Analysis, day_index: usize, bucket_index: usize) -> String {
+    const DATE_FORMAT: &str = "%Y-%m-%d %H:%M";
+    let bucket_layout = &settings.bucket_layout;
+    let bucket_value = &analysis.buckets[day_index][bucket_index];
+    let online_count = bucket_value.online_count;
+    let total_dates = bucket_value.total_dates();
+    let mut out = String::new();
+
+    let weekday = Weekday::from_usize(day_index).unwrap();
+    writeln!(out, "Bucket: {weekday} {} ({} minutes)", bucket_layout.label(bucket_index, settings.time_format_12h), bucket_layout.duration(bucket_index).num_minutes()).unwrap();
+    writeln!(out, "online_count: {online_count}").unwrap();
+    writeln!(out, "total_dates: {total_dates}").unwrap();
+    if total_dates != 0 {
+        if bucket_value.is_sampled() {
+            writeln!(out, "total_dates is estimated, as max_tracked_dates_per_bucket only kept a sample of {} dates. The sampled dates, with the friend online count for each:", bucket_value.vrcx_activity_dates.len()).unwrap();
+        } else {
+            writeln!(out, "Dates VRCX was active, with the friend online count for each:").unwrap();
+        }
+        for date in &bucket_value.vrcx_activity_dates {
+            let count = bucket_value.online_counts_by_date.get(date).copied().unwrap_or(0.0);
+            writeln!(out, "  {}\t{count}", date.format(DATE_FORMAT)).unwrap();
+        }
+    }
+
+    let minimum_bucket_activations = settings.minimum_bucket_activations;
+    if u32::try_from(total_dates).unwrap() < minimum_bucket_activations {
+        writeln!(out, "Suppressed: active on {total_dates} dates, fewer than minimum_bucket_activations ({minimum_bucket_activations}), so this bucket has no data.").unwrap();
+    } else {
+        let baseline_dates = baseline_activity_count(settings.normalization_baseline, &analysis.buckets);
+        let dates = baseline_dates.unwrap_or(total_dates);
+        let dates_description = if baseline_dates.is_some() { "the normalization_baseline date count" } else { "total_dates" };
+        let step = match settings.normalize {
+            NormalizationMode::None => format!("normalize is off, so the value is the online_count: {online_count}"),
+            NormalizationMode::Activity => format!("online_count / {dates_description} = {online_count} / {dates} = {}", online_count / dates as f64),
+            NormalizationMode::Probability => {
+                let online_dates = bucket_value.total_online_dates();
+                format!("100 * dates with a friend online / total_dates = 100 * {online_dates} / {total_dates} = {}", 100.0 * online_dates as f64 / total_dates as f64)
+            }
+            NormalizationMode::Coverage => {
+                let coverage = bucket_value.total_coverage();
+                format!("online_count / dates weighted by how much of the bucket VRCX saw = {online_count} / {coverage} = {}", online_count / coverage)
+            }
+            NormalizationMode::Delta => {
+                let mean = mean_value(&bucket_output_values(NormalizationMode::Activity, settings.normalization_baseline, minimum_bucket_activations, settings.recency_half_life, &analysis.buckets)).unwrap_or(0.0);
+                format!("online_count / {dates_description} - the weekly mean = {online_count} / {dates} - {mean} = {}", online_count / dates as f64 - mean)
+            }
+            NormalizationMode::Weekday => {
+                let occurrences = weekday_occurrence_counts(&analysis.buckets)[day_index].max(1);
+                format!("online_count / times {weekday} occurred = {online_count} / {occurrences} = {}", online_count / occurrences as f64)
+            }
+        };
+        let step = match settings.recency_half_life {
+            Some(recency_half_life) if matches!(settings.normalize, NormalizationMode::Activity | NormalizationMode::Probability | NormalizationMode::Delta) => format!(
+                "recency_half_life_weeks ({}) weights each date by 0.5^(weeks before the latest date / {0}) in both the friend count and the number of dates, so instead of {step}, the value is the weighted version of that.",
+                recency_half_life.num_weeks(),
+            ),
+            _ => step,
+        };
+        writeln!(out, "{step}").unwrap();
+        if let Some(smoothing_radius) = settings.smoothing_radius {
+            writeln!(out, "smoothing_radius ({smoothing_radius}) then blends in the neighboring buckets, weighted by their total_dates.").unwrap();
+        }
+        if settings.compare_friend_ids.is_some() {
+            writeln!(out, "compare_friend_ids_b's value for this bucket is then subtracted.").unwrap();
+        }
+    }
+    if let Some(low_confidence_threshold) = settings.low_confidence_threshold {
+        if u32::try_from(total_dates).unwrap() < low_confidence_threshold {
+            writeln!(out, "Flagged as low confidence: active on {total_dates} dates, fewer than low_confidence_threshold ({low_confidence_threshold}).").unwrap();
+        }
+    }
+
+    let value = settings.output_values(&analysis.buckets, analysis.comparison_buckets.as_deref())[day_index][bucket_index];
+    match value {
+        Some(value) => writeln!(out, "Output value: {value}").unwrap(),
+        None if settings.no_data_returns_zero => writeln!(out, "Output value: 0 (no data, and no_data_returns_zero is set)").unwrap(),
+        None => writeln!(out, "Output value: none").unwrap(),
+    }
+    out
+}
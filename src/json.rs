@@ -0,0 +1,435 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+//! A minimal JSON reader and writer. We only need JSON in a few places, so this avoids pulling in another dependency.
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// a parsed JSON value
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    /// object members, in the order they appeared
+    Object(Vec<(String, JsonValue)>),
+}
+
+/// a problem encountered while parsing JSON
+#[derive(Debug)]
+pub struct JsonError {
+    message: String,
+    /// character offset into the input where the problem was detected
+    offset: usize,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at character {}", self.message, self.offset)
+    }
+}
+
+impl JsonValue {
+    /// parse a complete JSON document
+    pub fn parse(text: &str) -> Result<Self, JsonError> {
+        let mut parser = Parser {
+            chars: text.chars().peekable(),
+            offset: 0,
+        };
+        let value = parser.parse_value()?;
+        parser.skip_whitespace();
+        if parser.chars.peek().is_some() {
+            return Err(parser.error("unexpected trailing characters"));
+        }
+        Ok(value)
+    }
+
+    /// the value of an object member, if this is an object with that member
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members.iter().find(|(member_key, _)| member_key == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// this value as a number, if it's one. Whole floats are written without a fractional part, so they read back as integers.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            JsonValue::Integer(value) => Some(*value as f64),
+            JsonValue::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Convert from a TOML value. JSON has no dates, so TOML dates and times become strings in their TOML format.
+    pub fn from_toml(value: toml::Value) -> Self {
+        match value {
+            toml::Value::Boolean(value) => JsonValue::Bool(value),
+            toml::Value::Integer(value) => JsonValue::Integer(value),
+            toml::Value::Float(value) => JsonValue::Float(value),
+            toml::Value::String(value) => JsonValue::String(value),
+            toml::Value::Datetime(value) => JsonValue::String(value.to_string()),
+            toml::Value::Array(values) => JsonValue::Array(values.into_iter().map(JsonValue::from_toml).collect()),
+            toml::Value::Table(table) => JsonValue::Object(table.into_iter()
+                .map(|(key, value)| (key, JsonValue::from_toml(value)))
+                .collect()),
+        }
+    }
+
+    /// Convert to the equivalent TOML value. TOML has no null, so nulls are dropped from objects and arrays, which matches
+    /// how an omitted key is treated for our optional config fields.
+    pub fn into_toml(self) -> Option<toml::Value> {
+        match self {
+            JsonValue::Null => None,
+            JsonValue::Bool(value) => Some(toml::Value::Boolean(value)),
+            JsonValue::Integer(value) => Some(toml::Value::Integer(value)),
+            JsonValue::Float(value) => Some(toml::Value::Float(value)),
+            JsonValue::String(value) => Some(toml::Value::String(value)),
+            JsonValue::Array(values) => Some(toml::Value::Array(values.into_iter().filter_map(JsonValue::into_toml).collect())),
+            JsonValue::Object(members) => Some(toml::Value::Table(members.into_iter()
+                .filter_map(|(key, value)| value.into_toml().map(|value| (key, value)))
+                .collect())),
+        }
+    }
+}
+
+/// writes compact JSON. Floats always keep a decimal point or exponent so they read back as floats. Non-finite floats
+/// have no JSON representation, so they're written as null.
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonValue::Null => write!(f, "null"),
+            JsonValue::Bool(value) => write!(f, "{value}"),
+            JsonValue::Integer(value) => write!(f, "{value}"),
+            JsonValue::Float(value) if value.is_finite() => write!(f, "{value:?}"),
+            JsonValue::Float(_) => write!(f, "null"),
+            JsonValue::String(value) => write_json_string(f, value),
+            JsonValue::Array(values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                write!(f, "]")
+            }
+            JsonValue::Object(members) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in members.iter().enumerate() {
+                    if index != 0 {
+                        write!(f, ",")?;
+                    }
+                    write_json_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_json_string(f: &mut fmt::Formatter<'_>, string: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in string.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", u32::from(c))?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    offset: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> JsonError {
+        JsonError {
+            message: message.to_owned(),
+            offset: self.offset,
+        }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c.is_some() {
+            self.offset += 1;
+        }
+        c
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| matches!(c, ' ' | '\t' | '\n' | '\r')) {
+            self.next();
+        }
+    }
+
+    fn expect_literal(&mut self, literal: &str, value: JsonValue) -> Result<JsonValue, JsonError> {
+        for expected in literal.chars() {
+            if self.next() != Some(expected) {
+                return Err(self.error(&format!("expected `{literal}`")));
+            }
+        }
+        Ok(value)
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue, JsonError> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('n') => self.expect_literal("null", JsonValue::Null),
+            Some('t') => self.expect_literal("true", JsonValue::Bool(true)),
+            Some('f') => self.expect_literal("false", JsonValue::Bool(false)),
+            Some('"') => self.parse_string().map(JsonValue::String),
+            Some('[') => self.parse_array(),
+            Some('{') => self.parse_object(),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.parse_number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    /// Parse a number, following the JSON grammar exactly: no leading zeros, no leading `+`, and digits on both sides of a
+    /// decimal point.
+    fn parse_number(&mut self) -> Result<JsonValue, JsonError> {
+        let mut text = String::new();
+        if self.chars.peek() == Some(&'-') {
+            text.push('-');
+            self.next();
+        }
+        // the integer part is a lone zero, or digits that don't start with one
+        match self.chars.peek().copied() {
+            Some('0') => {
+                text.push('0');
+                self.next();
+                if self.chars.peek().is_some_and(char::is_ascii_digit) {
+                    return Err(self.error("leading zeros aren't allowed in numbers"));
+                }
+            }
+            Some('1'..='9') => {
+                self.push_digits(&mut text);
+            }
+            _ => return Err(self.error("invalid number")),
+        }
+        let mut is_float = false;
+        if self.chars.peek() == Some(&'.') {
+            is_float = true;
+            text.push('.');
+            self.next();
+            if !self.push_digits(&mut text) {
+                return Err(self.error("invalid number"));
+            }
+        }
+        if let Some(e @ ('e' | 'E')) = self.chars.peek().copied() {
+            is_float = true;
+            text.push(e);
+            self.next();
+            if let Some(sign @ ('+' | '-')) = self.chars.peek().copied() {
+                text.push(sign);
+                self.next();
+            }
+            if !self.push_digits(&mut text) {
+                return Err(self.error("invalid number"));
+            }
+        }
+        if is_float {
+            text.parse::<f64>().map(JsonValue::Float).map_err(|_| self.error("invalid number"))
+        } else {
+            text.parse::<i64>().map(JsonValue::Integer).map_err(|_| self.error("number out of range"))
+        }
+    }
+
+    /// move any digits from the input onto `text`, returning whether there were any
+    fn push_digits(&mut self, text: &mut String) -> bool {
+        let start_len = text.len();
+        while let Some(c) = self.chars.peek().copied().filter(char::is_ascii_digit) {
+            text.push(c);
+            self.next();
+        }
+        text.len() != start_len
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        // consume the opening quote
+        self.next();
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => match self.next() {
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some('/') => string.push('/'),
+                    Some('b') => string.push('\u{8}'),
+                    Some('f') => string.push('\u{c}'),
+                    Some('n') => string.push('\n'),
+                    Some('r') => string.push('\r'),
+                    Some('t') => string.push('\t'),
+                    Some('u') => {
+                        let high = self.parse_hex_escape()?;
+                        let code_point = if (0xD800..0xDC00).contains(&high) {
+                            // this is the first half of a surrogate pair, so the second half must follow
+                            if self.next() != Some('\\') || self.next() != Some('u') {
+                                return Err(self.error("unpaired surrogate in string"));
+                            }
+                            let low = self.parse_hex_escape()?;
+                            if !(0xDC00..0xE000).contains(&low) {
+                                return Err(self.error("unpaired surrogate in string"));
+                            }
+                            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                        } else {
+                            high
+                        };
+                        string.push(char::from_u32(code_point).ok_or_else(|| self.error("invalid unicode escape"))?);
+                    }
+                    _ => return Err(self.error("invalid escape sequence")),
+                },
+                Some(c) if c < '\u{20}' => return Err(self.error("control characters in strings must be escaped")),
+                Some(c) => string.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn parse_hex_escape(&mut self) -> Result<u32, JsonError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let digit = self.next().and_then(|c| c.to_digit(16)).ok_or_else(|| self.error("invalid unicode escape"))?;
+            value = value * 16 + digit;
+        }
+        Ok(value)
+    }
+
+    fn parse_array(&mut self) -> Result<JsonValue, JsonError> {
+        // consume the opening bracket
+        self.next();
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&']') {
+            self.next();
+            return Ok(JsonValue::Array(values));
+        }
+        loop {
+            values.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(JsonValue::Array(values)),
+                _ => return Err(self.error("expected `,` or `]`")),
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<JsonValue, JsonError> {
+        // consume the opening brace
+        self.next();
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.next();
+            return Ok(JsonValue::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.chars.peek() != Some(&'"') {
+                return Err(self.error("expected a string key"));
+            }
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            if self.next() != Some(':') {
+                return Err(self.error("expected `:`"));
+            }
+            let value = self.parse_value()?;
+            members.push((key, value));
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => {}
+                Some('}') => return Ok(JsonValue::Object(members)),
+                _ => return Err(self.error("expected `,` or `}`")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> JsonValue {
+        JsonValue::parse(text).unwrap_or_else(|e| panic!("{text}: {e}"))
+    }
+
+    fn string(value: &str) -> JsonValue {
+        JsonValue::String(value.to_owned())
+    }
+
+    #[test]
+    fn parse_literals_and_containers() {
+        assert_eq!(parse(" null "), JsonValue::Null);
+        assert_eq!(parse("[true, false, []]"), JsonValue::Array(vec![JsonValue::Bool(true), JsonValue::Bool(false), JsonValue::Array(Vec::new())]));
+        assert_eq!(parse("{\"b\": 1, \"a\": {}}"), JsonValue::Object(vec![("b".to_owned(), JsonValue::Integer(1)), ("a".to_owned(), JsonValue::Object(Vec::new()))]));
+        assert_eq!(parse("{\"a\": [1]}").get("a"), Some(&JsonValue::Array(vec![JsonValue::Integer(1)])));
+    }
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse("0"), JsonValue::Integer(0));
+        assert_eq!(parse("-0"), JsonValue::Integer(0));
+        assert_eq!(parse("123"), JsonValue::Integer(123));
+        assert_eq!(parse("-9223372036854775808"), JsonValue::Integer(i64::MIN));
+        assert_eq!(parse("0.5"), JsonValue::Float(0.5));
+        assert_eq!(parse("-1.25e2"), JsonValue::Float(-125.0));
+        assert_eq!(parse("1E-2"), JsonValue::Float(0.01));
+        assert_eq!(parse("2e+3"), JsonValue::Float(2000.0));
+        for invalid in ["01", "-01", "00", "+1", "-", "1.", ".5", "1e", "1e+", "--1", "1-2", "9223372036854775808"] {
+            assert!(JsonValue::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn parse_string_escapes() {
+        assert_eq!(parse(r#""a\"b\\c\/d\b\f\n\r\t""#), string("a\"b\\c/d\u{8}\u{c}\n\r\t"));
+        assert_eq!(parse(r#""é\u0000""#), string("\u{e9}\u{0}"));
+        // U+1F600 as a surrogate pair
+        assert_eq!(parse(r#""\ud83d\ude00""#), string("\u{1f600}"));
+        assert_eq!(parse("\"\u{1f600}\""), string("\u{1f600}"));
+        for invalid in [r#""\ud83d""#, r#""\ud83dx""#, r#""\ud83dA""#, r#""\ude00""#, r#""\x""#, r#""\u12g4""#, "\"unterminated", "\"tab\there\"", "\"new\nline\""] {
+            assert!(JsonValue::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn parse_rejects_trailing_garbage() {
+        for invalid in ["1 2", "{} x", "[1],", "nullx", "\"a\"\"b\"", "", "   ", "[1,]", "{\"a\":1,}", "{a:1}", "tru"] {
+            assert!(JsonValue::parse(invalid).is_err(), "{invalid}");
+        }
+        assert!(JsonValue::parse(" {} \n").is_ok());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let value = JsonValue::Object(vec![
+            ("string".to_owned(), string("quote \" backslash \\ newline \n tab \t bell \u{7} astral \u{1f600}")),
+            ("integers".to_owned(), JsonValue::Array(vec![JsonValue::Integer(0), JsonValue::Integer(-42), JsonValue::Integer(i64::MAX)])),
+            ("floats".to_owned(), JsonValue::Array(vec![JsonValue::Float(0.1), JsonValue::Float(-2.5), JsonValue::Float(1e300)])),
+            ("nested".to_owned(), JsonValue::Array(vec![JsonValue::Null, JsonValue::Bool(true), JsonValue::Object(Vec::new())])),
+        ]);
+        assert_eq!(parse(&value.to_string()), value);
+        assert_eq!(JsonValue::Float(3.0).to_string(), "3.0");
+        assert_eq!(JsonValue::Float(1e300).to_string(), "1e300");
+        assert_eq!(JsonValue::Float(f64::NAN).to_string(), "null");
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+use chrono::{Datelike, DateTime, Duration, Local, Timelike};
+use chrono::naive::NaiveTime;
+
+use crate::config::LabelStyle;
+use crate::constants::{MINUTES_PER_DAY, MINUTES_PER_HOUR, SECONDS_PER_MINUTE};
+
+/// How a day is divided into buckets. Every day of the week uses the same layout.
+#[derive(Clone)]
+pub struct BucketLayout {
+    /// the minute of the day each bucket starts at, ascending and starting with 0
+    bucket_starts: Vec<u32>,
+}
+
+impl BucketLayout {
+    /// Equal-length buckets. `bucket_duration_minutes` must perfectly divide a day.
+    pub fn uniform(bucket_duration_minutes: u32) -> Self {
+        debug_assert!(MINUTES_PER_DAY.is_multiple_of(bucket_duration_minutes));
+        Self {
+            bucket_starts: (0..MINUTES_PER_DAY).step_by(usize::try_from(bucket_duration_minutes).unwrap()).collect(),
+        }
+    }
+
+    /// Buckets of roughly `bucket_duration_minutes` that snap to clock hours:
+    /// - For durations under an hour, buckets restart at the top of every hour, so the last bucket of each hour is cut
+    ///   short if the duration doesn't divide an hour. With 45 minutes, each hour has a 45 minute and a 15 minute bucket.
+    /// - For durations of an hour or more, each bucket boundary is rounded to the nearest hour (half an hour rounds up),
+    ///   and boundaries that round to the same hour are merged. With 90 minutes, buckets alternate between 2 and 1 hours.
+    ///
+    /// Either way, the last bucket of the day always ends at midnight, and is shorter than the others if the duration
+    /// doesn't fit. Buckets never span midnight.
+    pub fn aligned_to_hour(bucket_duration_minutes: u32) -> Self {
+        debug_assert!(bucket_duration_minutes > 0);
+        let mut bucket_starts: Vec<u32> = Vec::new();
+        if bucket_duration_minutes < MINUTES_PER_HOUR {
+            for hour_start in (0..MINUTES_PER_DAY).step_by(usize::try_from(MINUTES_PER_HOUR).unwrap()) {
+                bucket_starts.extend((0..MINUTES_PER_HOUR).step_by(usize::try_from(bucket_duration_minutes).unwrap()).map(|offset| hour_start + offset));
+            }
+        } else {
+            for unaligned_start in (0..MINUTES_PER_DAY).step_by(usize::try_from(bucket_duration_minutes).unwrap()) {
+                let start = (unaligned_start + MINUTES_PER_HOUR / 2) / MINUTES_PER_HOUR * MINUTES_PER_HOUR;
+                if start < MINUTES_PER_DAY && bucket_starts.last() != Some(&start) {
+                    bucket_starts.push(start);
+                }
+            }
+        }
+        Self {
+            bucket_starts,
+        }
+    }
+
+    pub fn buckets_per_day(&self) -> usize {
+        self.bucket_starts.len()
+    }
+
+    /// the minute of the day a bucket starts at
+    pub fn start_minutes(&self, bucket_index: usize) -> u32 {
+        self.bucket_starts[bucket_index]
+    }
+
+    /// the time of day a bucket starts at
+    pub fn start_time(&self, bucket_index: usize) -> NaiveTime {
+        NaiveTime::from_num_seconds_from_midnight_opt(self.start_minutes(bucket_index) * SECONDS_PER_MINUTE, 0).unwrap()
+    }
+
+    /// how long a bucket lasts
+    pub fn duration(&self, bucket_index: usize) -> Duration {
+        let end_minutes = self.bucket_starts.get(bucket_index + 1).copied().unwrap_or(MINUTES_PER_DAY);
+        Duration::minutes(i64::from(end_minutes - self.start_minutes(bucket_index)))
+    }
+
+    /// find the index of the bucket containing a minute of the day
+    pub fn bucket_index(&self, minutes_of_day: u32) -> usize {
+        self.bucket_starts.partition_point(|start| *start <= minutes_of_day) - 1
+    }
+
+    /// find the `(day_index, bucket_index)` of the bucket a local time falls in
+    pub fn position(&self, time: DateTime<Local>) -> (usize, usize) {
+        let day_index = usize::try_from(time.weekday().num_days_from_monday()).unwrap();
+        let minutes_of_day = u32::try_from(time.time().signed_duration_since(NaiveTime::default()).num_minutes()).unwrap();
+        (day_index, self.bucket_index(minutes_of_day))
+    }
+
+    /// truncate a local time to the start of the bucket it falls in
+    pub fn bucket_start(&self, time: DateTime<Local>) -> DateTime<Local> {
+        let (_, bucket_index) = self.position(time);
+        let time_into_bucket = time.time().signed_duration_since(self.start_time(bucket_index));
+        time - time_into_bucket
+    }
+
+    /// the start of the bucket following the one a local time falls in
+    pub fn next_bucket_start(&self, time: DateTime<Local>) -> DateTime<Local> {
+        let (_, bucket_index) = self.position(time);
+        self.bucket_start(time) + self.duration(bucket_index)
+    }
+
+    /// label a bucket by its start time, in the format [`time_label`] uses
+    pub fn label(&self, bucket_index: usize, time_format_12h: bool) -> String {
+        time_label(self.start_time(bucket_index), time_format_12h)
+    }
+
+    /// a label for every bucket in a day, either its start time or how many minutes after midnight it starts
+    pub fn labels(&self, label_style: LabelStyle, time_format_12h: bool) -> Vec<String> {
+        (0..self.buckets_per_day())
+            .map(|bucket_index| match label_style {
+                LabelStyle::Clock => self.label(bucket_index, time_format_12h),
+                LabelStyle::Minutes => self.start_minutes(bucket_index).to_string(),
+            })
+            .collect()
+    }
+}
+
+/// format a time of day as either 24-hour `HH:MM` or 12-hour `hh:MM AM`
+pub fn time_label(time: NaiveTime, time_format_12h: bool) -> String {
+    if time_format_12h {
+        time.format("%I:%M %p").to_string()
+    } else {
+        format!("{:02}:{:02}", time.hour(), time.minute())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::constants::DAYS_PER_WEEK;
+
+    use super::*;
+
+    /// uniform layouts, and aligned ones that are shorter than an hour, don't divide an hour, are longer than an hour, and
+    /// don't divide a day
+    fn layouts() -> Vec<(String, BucketLayout)> {
+        let uniform = [1, 10, 60, 1440].map(|minutes| (format!("uniform({minutes})"), BucketLayout::uniform(minutes)));
+        let aligned = [1, 7, 45, 60, 90, 100, 1000].map(|minutes| (format!("aligned_to_hour({minutes})"), BucketLayout::aligned_to_hour(minutes)));
+        uniform.into_iter().chain(aligned).collect()
+    }
+
+    /// Monday, local time. January has no DST transitions in any common zone.
+    fn monday_midnight() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn bucket_starts_and_durations_round_trip() {
+        for (name, layout) in layouts() {
+            let mut expected_start_minutes = 0;
+            for bucket_index in 0..layout.buckets_per_day() {
+                let start_minutes = layout.start_minutes(bucket_index);
+                let duration_minutes = u32::try_from(layout.duration(bucket_index).num_minutes()).unwrap();
+                assert_eq!(start_minutes, expected_start_minutes, "{name}: bucket {bucket_index} doesn't start where the one before ended");
+                assert!(duration_minutes > 0, "{name}: bucket {bucket_index} is empty");
+                assert_eq!(layout.bucket_index(start_minutes), bucket_index, "{name}: first minute of bucket {bucket_index}");
+                assert_eq!(layout.bucket_index(start_minutes + duration_minutes - 1), bucket_index, "{name}: last minute of bucket {bucket_index}");
+                assert_eq!(layout.start_time(bucket_index), NaiveTime::from_hms_opt(start_minutes / MINUTES_PER_HOUR, start_minutes % MINUTES_PER_HOUR, 0).unwrap(), "{name}: bucket {bucket_index}");
+                expected_start_minutes += duration_minutes;
+            }
+            assert_eq!(expected_start_minutes, MINUTES_PER_DAY, "{name}: the last bucket doesn't end at midnight");
+            assert_eq!(layout.bucket_index(MINUTES_PER_DAY - 1), layout.buckets_per_day() - 1, "{name}: last minute of the day");
+        }
+    }
+
+    #[test]
+    fn every_minute_falls_in_its_bucket() {
+        let day_start = monday_midnight() + Duration::days(2);
+        for (name, layout) in layouts() {
+            for minutes in 0..MINUTES_PER_DAY {
+                // partway into the minute, so truncation is exercised as well
+                let time = day_start + Duration::minutes(i64::from(minutes)) + Duration::seconds(17);
+                let (day_index, bucket_index) = layout.position(time);
+                assert_eq!((day_index, bucket_index), (2, layout.bucket_index(minutes)), "{name}: {time}");
+                let bucket_start = layout.bucket_start(time);
+                assert_eq!(bucket_start, day_start + Duration::minutes(i64::from(layout.start_minutes(bucket_index))), "{name}: {time}");
+                assert_eq!(layout.position(bucket_start), (day_index, bucket_index), "{name}: {time}");
+                assert_eq!(layout.bucket_start(bucket_start), bucket_start, "{name}: {time}");
+                assert_eq!(layout.next_bucket_start(time), bucket_start + layout.duration(bucket_index), "{name}: {time}");
+                assert!(bucket_start <= time && time < layout.next_bucket_start(time), "{name}: {time}");
+            }
+        }
+    }
+
+    #[test]
+    fn next_bucket_start_walks_every_bucket_of_the_week() {
+        for (name, layout) in layouts() {
+            let mut time = monday_midnight();
+            for day_index in 0..DAYS_PER_WEEK {
+                for bucket_index in 0..layout.buckets_per_day() {
+                    assert_eq!(layout.position(time), (day_index, bucket_index), "{name}: {time}");
+                    time = layout.next_bucket_start(time);
+                }
+            }
+            // the last bucket of Sunday wraps around to the first bucket of Monday
+            assert_eq!(time, monday_midnight() + Duration::weeks(1), "{name}");
+            assert_eq!(layout.position(time), (0, 0), "{name}");
+        }
+    }
+
+    #[test]
+    fn aligned_last_bucket_is_cut_short_at_midnight() {
+        let layout = BucketLayout::aligned_to_hour(45);
+        let last_bucket_index = layout.buckets_per_day() - 1;
+        assert_eq!(layout.buckets_per_day(), 48);
+        assert_eq!(layout.start_minutes(last_bucket_index), 23 * MINUTES_PER_HOUR + 45);
+        assert_eq!(layout.duration(last_bucket_index), Duration::minutes(15));
+
+        let layout = BucketLayout::aligned_to_hour(90);
+        let last_bucket_index = layout.buckets_per_day() - 1;
+        assert_eq!(layout.start_minutes(last_bucket_index), 23 * MINUTES_PER_HOUR);
+        assert_eq!(layout.duration(last_bucket_index), Duration::hours(1));
+        let in_last_bucket = monday_midnight() + Duration::minutes(i64::from(23 * MINUTES_PER_HOUR + 30));
+        assert_eq!(layout.next_bucket_start(in_last_bucket), monday_midnight() + Duration::days(1));
+    }
+}
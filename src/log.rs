@@ -0,0 +1,29 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+//! Non-fatal messages on stderr: statistics, warnings, and the like. `--quiet` turns these off so scripts only get the
+//! results on stdout. Errors are always printed, and don't go through here.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// `eprintln!`, unless `--quiet` was passed
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !$crate::log::is_quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use info;
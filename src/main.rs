@@ -2,470 +2,788 @@
 // This file is part of vrcx-optimal-time.
 // vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
 
-use std::collections::{HashMap, HashSet};
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
 use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::mem;
+use std::process::ExitCode;
 use std::time::Instant;
 
-use chrono::{Datelike, DateTime, Duration, DurationRound, Local, Timelike, Utc, Weekday};
-use chrono::naive::NaiveTime;
+use chrono::{Datelike, DateTime, Duration, Local, Utc, Weekday};
 use num_traits::cast::FromPrimitive;
-use rusqlite::{Connection, DropBehavior, OpenFlags};
-
-use config::Configuration;
-
-use crate::constants::{COLUMN_INDEX_CREATED_AT, DAYS_PER_WEEK, MILLISECONDS_PER_HOUR, MINUTES_PER_DAY, SECONDS_PER_MINUTE};
-use crate::dto::{BucketValue, OnlineOfflineEventType, Row, TimeSpan, VrcxStartStopEvent, VrcxStartStopEventType};
 
+use analysis::{analyze, read_all_events, day_start, week_start, Analysis, EventGapPercentiles, Settings, SlidingWindows};
+use cli::Arguments;
+use config::{Configuration, NormalizationMode, OutputFormat, RecommendationRank};
+
+use crate::bucketing::{buckets_in_span, build_daily_buckets, split_buckets_by_month};
+use crate::bundle::bundle_json;
+use crate::calendar::{calendar_ics, friend_calendar_ics};
+use crate::constants::{DAYS_PER_WEEK, MILLISECONDS_PER_HOUR, MILLISECONDS_PER_MINUTE, MINUTES_PER_DAY, MINUTES_PER_HOUR};
+use crate::diff::bundle_diff;
+use crate::error::Error;
+use crate::explain::{explain_bucket, parse_bucket_position};
+use crate::dto::{AnalysisRange, BucketValue, TimeSpan};
+use crate::layout::BucketLayout;
+use crate::log::info;
+use crate::npy::npy_bytes;
+use crate::output::{reliability_values, standard_deviation_values, write_day_blocks, write_friend_peaks, write_highlights, write_ranges, write_raw_buckets, write_sparklines, write_value_grid};
+use crate::recommend::{print_recommendations, recommend_upcoming_buckets};
+use crate::uptime::{ClampStats, VrcxUptime};
+use crate::warnings::{WarningKind, Warnings};
+
+mod analysis;
+mod anomalies;
+mod bucketing;
+mod bundle;
+mod calendar;
+mod cli;
 mod config;
+mod diff;
 mod dto;
 mod constants;
+mod error;
+mod explain;
+mod json;
+mod layout;
+mod log;
+mod names;
+mod npy;
+mod output;
+mod profile;
+mod recommend;
+#[cfg(feature = "serve")]
+mod serve;
+mod source;
+mod uptime;
+mod warnings;
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
 
-fn main() {
+fn run() -> Result<(), Error> {
     // record application start time
     let application_start_time = Instant::now();
 
-    // load the config
-    let config_string = fs::read_to_string("config.toml").unwrap();
-    let config: Configuration = toml::from_str(&config_string).unwrap();
-
-    // derive constants from config
-    let (buckets_per_day, buckets_per_day_remainder) = (MINUTES_PER_DAY / config.bucket_duration_minutes, MINUTES_PER_DAY % config.bucket_duration_minutes);
-    assert_eq!(buckets_per_day_remainder, 0, "bucket_duration_minutes does not perfectly divide a day");
-    let buckets_per_day: usize = usize::try_from(buckets_per_day).unwrap();
-    let bucket_duration_seconds: u32 = config.bucket_duration_minutes * SECONDS_PER_MINUTE;
-    let bucket_duration: Duration = Duration::minutes(i64::from(config.bucket_duration_minutes));
-    let vrcx_running_detection_threshold: Duration = Duration::minutes(i64::from(config.vrcx_running_detection_threshold_minutes));
-    let start_time = config.start_time.map(|t| DateTime::parse_from_rfc3339(&t).unwrap().with_timezone(&Utc));
-    let minimum_bucket_activations = config.minimum_bucket_activations.unwrap_or(1).max(1);
-    let no_data_returns_zero = config.no_data_returns_zero.unwrap_or(false);
-    let should_print_statistics = config.print_statistics.unwrap_or(false);
-    let should_print_runtime = config.print_runtime.unwrap_or(false);
-
-    // open the sqlite database
-    let mut db = Connection::open_with_flags(
-        config.vrcx_db_path,
-        OpenFlags::SQLITE_OPEN_READ_ONLY
-            | OpenFlags::SQLITE_OPEN_URI
-            | OpenFlags::SQLITE_OPEN_NO_MUTEX).unwrap();
-
-    // set up data structures we'll need for the VRCX running analysis
-    let mut buckets = build_daily_buckets(buckets_per_day);
-    let mut vrcx_start_stop_events: Vec<VrcxStartStopEvent> = Vec::new();
-    let first_event_timestamp: Option<DateTime<Utc>>;
-    let last_event_timestamp: Option<DateTime<Utc>>;
-    let all_event_count: usize;
-    let mut online_offline_event_count: usize = 0;
-
-    // build and run the all events query
-    let stripped_user_id = config.your_user_id.replace(['-', '_'], "");
-    let all_events_statement = format!("select created_at from {stripped_user_id}_feed_avatar union select created_at from {stripped_user_id}_feed_gps union select created_at from {stripped_user_id}_feed_online_offline union select created_at from {stripped_user_id}_feed_status union select created_at from {stripped_user_id}_friend_log_history order by created_at asc;");
-
-    // run a big transactional read
-    {
-        let mut transaction = db.transaction().unwrap();
-        transaction.set_drop_behavior(DropBehavior::Commit);
-        let mut all_events_statement = transaction.prepare(&all_events_statement).unwrap();
-        let all_event_timestamps = all_events_statement.query_map((), parse_created_at).unwrap();
-        let all_event_timestamps: Vec<DateTime<Utc>> = all_event_timestamps
-            .map(|event| event.unwrap())
-            .collect();
+    let arguments = Arguments::from_env()?;
+    log::set_quiet(arguments.quiet);
+    // declared before the span so the span ends, and is counted, before the profile is written
+    #[cfg(feature = "profile")]
+    let _recording = arguments.profile.clone().map(profile::start);
+    let _span = profile::span("run");
+
+    // this only reads the bundles, so it doesn't need a config or database
+    if let Some((old_bundle_path, new_bundle_path)) = &arguments.diff {
+        print!("{}", bundle_diff(old_bundle_path, new_bundle_path)?);
+        return Ok(());
+    }
 
-        all_event_count = all_event_timestamps.len();
-        first_event_timestamp = all_event_timestamps.first().map(|ts| ts.to_owned());
-        last_event_timestamp = all_event_timestamps.last().map(|ts| ts.to_owned());
-
-        // process all event timestamps
-        let mut vrcx_running: bool = false;
-        for window in all_event_timestamps.windows(2) {
-            match window {
-                &[event_timestamp_1, event_timestamp_2] => {
-                    let duration = event_timestamp_2.signed_duration_since(event_timestamp_1);
-                    assert!(duration >= Duration::zero()); // assert that data is, in fact, ascending
-                    if duration <= vrcx_running_detection_threshold && duration >= Duration::zero() {
-                        // we can skip over zero-length durations
-                        // duration between events was within the threshold, so assume VRCX is running for this entire time range
-
-                        if !vrcx_running {
-                            // vrcx just started running
-
-                            // the previous event should have been a stop event (or empty)
-                            debug_assert!(vrcx_start_stop_events.last().is_none() || matches!(vrcx_start_stop_events.last(), Some(VrcxStartStopEvent{ event: VrcxStartStopEventType::Stop, .. })));
-
-                            vrcx_running = true;
-                            vrcx_start_stop_events.push(VrcxStartStopEvent::start(event_timestamp_1));
-                        } // else, if vrcx was already running there's nothing for us to do
-
-                        // use any VRCX events available to reason that VRCX is running during a given time range
-                        let time_span = TimeSpan::new(event_timestamp_1, event_timestamp_2);
-                        register_bucket_dates_for_range(bucket_duration, config.bucket_duration_minutes, time_span, buckets.as_mut_slice());
-                    } else if vrcx_running {
-                        // duration was outside threshold, so assume VRCX is *not* running for this range (which may be quite long)
-                        // also, VRCX was running in the previous range, therefore we need to push a stop event
-
-                        // the previous event should have been a start event
-                        debug_assert!(matches!(vrcx_start_stop_events.last(), Some(VrcxStartStopEvent{ event: VrcxStartStopEventType::Start, .. })));
-
-                        vrcx_running = false;
-                        vrcx_start_stop_events.push(VrcxStartStopEvent::stop(event_timestamp_1));
-                    }
-                }
-                _ => unreachable!()
-            }
-        }
+    // load the config
+    let config = if let Some(config_json) = &arguments.config_json {
+        Configuration::parse_inline(config_json)?
+    } else {
+        let config_string = match arguments.config_path.as_deref() {
+            Some("-") => io::read_to_string(io::stdin()),
+            Some(config_path) => fs::read_to_string(config_path),
+            None => fs::read_to_string("config.toml"),
+        }.map_err(Error::ConfigNotFound)?;
+        Configuration::parse(&config_string)?
+    };
 
-        // push the final stop event, if needed
-        if !matches!(vrcx_start_stop_events.last().unwrap().event, VrcxStartStopEventType::Stop) {
-            vrcx_start_stop_events.push(VrcxStartStopEvent::stop(*all_event_timestamps.last().unwrap()));
-        }
+    let mut settings = Settings::from_config(config)?;
+    settings.print_sql = arguments.print_sql;
+    settings.self_check = arguments.self_check;
+
+    if settings.verbose {
+        let buckets_per_day = settings.bucket_layout.buckets_per_day();
+        let total_buckets = DAYS_PER_WEEK * buckets_per_day;
+        // this only covers the fixed-size part of each bucket: the per-date collections grow as data is collected
+        let bucket_memory_bytes = total_buckets * mem::size_of::<BucketValue>() + DAYS_PER_WEEK * mem::size_of::<Vec<BucketValue>>();
+        let bucket_minutes = plural(settings.bucket_duration_minutes.try_into().unwrap(), "minute", "minutes");
+        let bucket_size_description = if settings.align_to_hour {
+            format!("roughly {bucket_minutes} each, aligned to clock hours")
+        } else {
+            format!("{bucket_minutes} each")
+        };
+        info!("Using {} per day of {bucket_size_description}, for a {DAYS_PER_WEEK}x{buckets_per_day} grid of {total_buckets} buckets. Empty buckets take {:.1} KiB; this grows with the number of dates recorded.", plural(buckets_per_day, "bucket", "buckets"), bucket_memory_bytes as f64 / 1024.0);
+    }
 
-        // build and run the online/offline query
-        let online_offline_statement = format!("select created_at, user_id, display_name, type from {stripped_user_id}_feed_online_offline order by created_at asc");
-        let mut online_offline_statement = transaction.prepare(&online_offline_statement).unwrap();
-        let user_online_offline_events = online_offline_statement.query_map((), |row| Row::try_from(row)).unwrap();
+    if arguments.list_users {
+        return list_users(&settings);
+    }
 
-        // set up data structures we'll need for the online/offline analysis
-        let mut user_online_time: HashMap<String, DateTime<Utc>> = HashMap::new();
+    #[cfg(feature = "serve")]
+    if let Some(address) = &arguments.serve {
+        return serve::serve(address, &settings);
+    }
 
-        // process the user online/offline events
-        for row in user_online_offline_events {
-            let row = row.unwrap();
-            online_offline_event_count += 1;
+    // parsed up front, so a typo doesn't wait for the whole analysis to be reported
+    let explain_position = arguments.explain.as_deref().map(|explain| parse_bucket_position(&settings.bucket_layout, explain)).transpose()?;
 
-            // apply start_time filter
-            if start_time.map_or(false, |start| start > row.created_at) {
-                continue;
-            }
+    let analysis = {
+        let _span = profile::span("analyze");
+        analyze(&settings)?
+    };
+    if arguments.dump_uptime {
+        dump_uptime(&analysis.vrcx_uptime);
+        return Ok(());
+    }
+    if let Some((day_index, bucket_index)) = explain_position {
+        print!("{}", explain_bucket(&settings, &analysis, day_index, bucket_index));
+        return Ok(());
+    }
+    let mut warnings = Warnings::default();
 
-            if is_user_allowed(&row.user_id, &config.friend_ids) {
-                match row.event_type {
-                    OnlineOfflineEventType::Online => {
-                        // it is intentional that this overwrites previous Online events,
-                        // because given two Online events in a row we should drop the first one
-                        user_online_time.insert(row.user_id, row.created_at);
-                    }
-                    OnlineOfflineEventType::Offline => {
-                        let online_time = user_online_time.remove(&row.user_id);
-                        if let Some(online_time) = online_time {
-                            let offline_time = row.created_at;
-                            let time_span = TimeSpan::new(online_time, offline_time);
-                            if time_span.stop < time_span.start {
-                                panic!("Got a negative ({}ms) duration for {}. This should not happen.", time_span.duration().num_milliseconds(), row.display_name);
-                            }
-                            if time_span.stop > time_span.start {
-                                if let Ok(events) = clamp_range_to_vrcx_uptime(time_span, vrcx_start_stop_events.as_slice()) {
-                                    // perfect, we got a usable event. We need to update buckets!
-                                    for time_span in events.into_iter() {
-                                        if time_span.is_negative_or_zero() {
-                                            panic!("Got a non-positive clamped duration ({}ms) for {}. This should not happen if my clamping code actually works.", time_span.duration().num_milliseconds(), row.display_name);
-                                        }
-                                        update_bucket_counts_for_range(bucket_duration, config.bucket_duration_minutes, time_span, buckets.as_mut_slice());
-                                    }
-                                } // else, the range is too long, so drop the event
-                            } // else, the time_span doesn't have positive duration so we skip it. Note that this also drops the Online event, because a simultaneous Online+Offline event is nonsensical.
-                        } // else, no matching online time, so drop the event
-                    }
-                };
-            }
+    if settings.verbose {
+        let ClampStats { too_long, full_overlap, front_clamp, tail_clamp, split, dropped, inferred_start, .. } = analysis.clamp_stats;
+        if settings.maximum_session_duration.is_some() {
+            info!("Discarded {too_long} sessions longer than maximum_session_hours.");
+        }
+        if let Some(event_collapse_window) = settings.event_collapse_window {
+            info!("Collapsed {} events that came within {}s of an earlier event.", analysis.collapsed_event_count, event_collapse_window.num_seconds());
+        }
+        if settings.infer_session_starts {
+            info!("Inferred the start of {inferred_start} sessions from when VRCX started, as they had no online event.");
+        }
+        info!("Clamping sessions to VRCX uptime: {full_overlap} fully overlapped, {front_clamp} had their start clamped, {tail_clamp} had their end clamped, {split} were split across uptime gaps, and {dropped} were dropped.");
+        if let Some(friend_ids) = &analysis.friend_ids {
+            let mut friend_names: Vec<&str> = friend_ids.iter().map(|user_id| analysis.display_names.label(user_id)).collect();
+            friend_names.sort_unstable();
+            info!("Only counting these friends: {}", friend_names.join(", "));
         }
     }
 
-    if should_print_statistics {
-        print_statistics(bucket_duration_seconds, start_time, first_event_timestamp, last_event_timestamp, all_event_count, online_offline_event_count, &buckets);
+    if settings.self_check {
+        info!("Self-check passed: clamping agreed with brute force for all {} sessions.", analysis.clamp_stats.clamped_session_count());
     }
 
-    // output the results
-    print_buckets(bucket_duration_seconds, buckets_per_day, config.normalize, minimum_bucket_activations, no_data_returns_zero, buckets);
+    for friend_name in &analysis.unresolved_friend_names {
+        warnings.push(WarningKind::FriendNames, format!("friend_ids has \"{friend_name}\", but nobody in the online/offline events has had that display name, so it was left out. Run with --list-users to see the names in your data."));
+    }
+    for (friend_name, user_ids) in &analysis.ambiguous_friend_names {
+        warnings.push(WarningKind::FriendNames, format!("friend_ids has \"{friend_name}\", but {} users have had that display name, so it was left out. Put the right one's id in friend_ids instead: {}", user_ids.len(), user_ids.join(", ")));
+    }
+    for name in &analysis.unmatched_friend_filters {
+        warnings.push(WarningKind::UnmatchedFriendIds, format!("{name} didn't match any online/offline events, so the results are empty. The ids may be stale or mistyped; run with --list-users to see the ids in your data."));
+    }
 
-    if should_print_runtime {
-        eprintln!("Finished in {:.3}s.", application_start_time.elapsed().as_millis() as f64 / 1000.0);
+    if analysis.future_event_count != 0 {
+        warn_about_future_events(&analysis, &mut warnings);
     }
-}
 
-/// clamps a time range to when VRCX was running
-/// if vrcx was running for the entire range, returns the input range
-/// otherwise, return the range truncated to when VRCX was known to be running
-/// if the range cannot be truncated, returns Err
-fn clamp_range_to_vrcx_uptime(time_span: TimeSpan, vrcx_start_stop_events: &[VrcxStartStopEvent]) -> Result<Vec<TimeSpan>, ()> {
-    // Compute index of the VRCX start/stop event preceding this time range.
-    let start_idx = vrcx_start_stop_events.binary_search_by_key(&time_span.start, |event| event.timestamp)
-        .unwrap_or_else(|insert_idx| insert_idx.checked_sub(1).unwrap());
-
-    // Compute index of the VRCX start/stop event following this time range.
-    // In certain edge cases (VRCX is currently running?) this might be out of the slice bounds.
-    let stop_idx = vrcx_start_stop_events.binary_search_by_key(&time_span.stop, |event| event.timestamp)
-        .unwrap_or_else(|insert_idx| insert_idx);
-
-    // now, if VRCX was running the entire time, then all of following should be true
-    // A) start_idx == stop_idx - 1
-    // B) vrcx_start_stop_events[start_idx] is a start event
-    // C) vrcx_start_stop_events[stop_idx] is a stop event
-
-    // VRCX was not running at the beginning of the event
-    // [..., vrcx_stop, event_start, ...]
-    if !matches!(vrcx_start_stop_events[start_idx].event, VrcxStartStopEventType::Start) {
-        // also, VRCX started after the event ended
-        return if stop_idx >= vrcx_start_stop_events.len() || !matches!(vrcx_start_stop_events[stop_idx].event, VrcxStartStopEventType::Stop) {
-            // yeah, I have no idea how to deal with this
-            // [..., vrcx_stop, event_start, ..., event_stop, vrcx_start, ...]
-
-            // debug print the mystery event:
-            // println!(
-            //     "wat:\n  vrcx_stop={}\n  event_start={}\n  events={}\n  event_stop={}\n  vrcx_start={}",
-            //     vrcx_start_stop_events[start_idx].timestamp,
-            //     time_span.start,
-            //     stop_idx - start_idx - 1,
-            //     time_span.start,
-            //     vrcx_start_stop_events[stop_idx].timestamp,
-            // );
-            Err(())
-        } else {
-            // VRCX was not running at the beginning of the event, but the stop is normal
-            // [..., vrcx_stop, event_start, ..., vrcx_start, event_stop, vrcx_stop, ...]
-            // we can use the tail end of this event
-            let event_2_start = &vrcx_start_stop_events[stop_idx - 1];
-            assert!(matches!(event_2_start.event, VrcxStartStopEventType::Start));
-            Ok(vec![TimeSpan::new(event_2_start.timestamp, time_span.stop)])
-        };
+    if let Some(event_gap_percentiles) = &analysis.event_gap_percentiles {
+        if settings.verbose {
+            info!("Gaps between VRCX events: median {}s, 90th percentile {}s.", event_gap_percentiles.median.num_seconds(), event_gap_percentiles.percentile_90.num_seconds());
+        }
+        if settings.vrcx_running_detection_threshold < event_gap_percentiles.median {
+            warn_about_low_detection_threshold(settings.vrcx_running_detection_threshold, event_gap_percentiles, &mut warnings);
+        }
     }
 
-    // VRCX started after the event ended, but the start is normal
-    // [..., vrcx_start, event_start, vrcx_stop, ..., event_stop, vrcx_start, ...]
-    if stop_idx >= vrcx_start_stop_events.len() || !matches!(vrcx_start_stop_events[stop_idx].event, VrcxStartStopEventType::Stop) {
-        // we can use the front end of this event
-        let event_1_stop = &vrcx_start_stop_events[start_idx + 1];
-        assert!(matches!(event_1_stop.event, VrcxStartStopEventType::Stop));
-        return Ok(vec![TimeSpan::new(time_span.start, event_1_stop.timestamp)]);
-    }
-
-    // VRCX restarted during the event
-    // [..., vrcx_start, event_start, vrcx_stop, ..., vrcx_start, event_stop, vrcx_stop, ...]
-    if start_idx != stop_idx.checked_sub(1).unwrap() {
-        // we can use both ends of the event as separate events
-        let event_1_stop = &vrcx_start_stop_events[start_idx + 1];
-        assert!(matches!(event_1_stop.event, VrcxStartStopEventType::Stop));
-        let event_2_start = &vrcx_start_stop_events[stop_idx - 1];
-        assert!(matches!(event_2_start.event, VrcxStartStopEventType::Start));
-        return Ok(vec![
-            TimeSpan::new(time_span.start, event_1_stop.timestamp),
-            TimeSpan::new(event_2_start.timestamp, time_span.stop),
-        ]);
+    if settings.print_statistics && analysis.all_event_count < settings.statistics_minimum_events {
+        info!("Only {} VRCX events were found, which is insufficient data for meaningful statistics.", analysis.all_event_count);
+    } else if settings.print_statistics {
+        let mut statistics = String::new();
+        write_statistics(&mut statistics, &settings.bucket_layout, settings.analysis_range, settings.stats_hours, &analysis, Utc::now(), &mut warnings);
+        for line in statistics.lines() {
+            info!("{line}");
+        }
     }
 
-    // none of the edge cases occurred
-    // [..., vrcx_start, event_start, event_stop, vrcx_stop, ...]
-    Ok(vec![time_span])
-}
+    if matches!(settings.output_format, OutputFormat::Table | OutputFormat::Markdown) && settings.normalize != NormalizationMode::None && settings.minimum_bucket_activations == 1 {
+        warn_about_single_activation_buckets(&analysis.buckets, &mut warnings);
+    }
 
-/// build buckets according to configured bucket size
-fn build_daily_buckets(buckets_per_day: usize) -> Vec<Vec<BucketValue>> {
-    vec![vec![BucketValue::default(); buckets_per_day]; DAYS_PER_WEEK]
-}
+    warn_about_anomalies(&analysis, &mut warnings);
 
-/// update bucket counts that a provided range encompasses
-fn update_bucket_counts_for_range(bucket_duration: Duration, bucket_duration_minutes: u32, time_span: TimeSpan, buckets: &mut [Vec<BucketValue>]) {
-    let end_time = time_span.stop.with_timezone(&Local);
-    let mut start_time = time_span.start.with_timezone(&Local);
-    start_time = start_time.duration_trunc(bucket_duration).unwrap();
+    warn_about_empty_grid(&settings, &analysis, &mut warnings);
 
-    while start_time < end_time {
-        let weekday = start_time.weekday();
-        let day_index = usize::try_from(weekday.num_days_from_monday()).unwrap();
-        let time = start_time.time();
-        let minutes_of_day = u32::try_from(time.signed_duration_since(NaiveTime::default()).num_minutes()).unwrap();
-        let bucket_index = usize::try_from(minutes_of_day / bucket_duration_minutes).unwrap();
+    let stale_weekdays = settings.stale_weekdays(&analysis.buckets);
+    if let Some(stale_weekdays) = &stale_weekdays {
+        warn_about_stale_weekdays(settings.stale_weekday_threshold.unwrap(), stale_weekdays, &mut warnings);
+    }
 
-        // increment the friend online count
-        buckets[day_index][bucket_index].increment();
+    // output the results
+    let _output_span = profile::span("output");
+    let Analysis { buckets, comparison_buckets, .. } = &analysis;
+    if let Some(sliding_windows) = &settings.sliding_windows {
+        write_sliding_windows(&settings, &analysis, sliding_windows)?;
+    } else {
+        write_output(&settings, &analysis, stale_weekdays.as_deref(), settings.output_file.as_deref())?;
+        if let Some(filtered_output) = &settings.filtered_output {
+            let filtered_settings = Settings {
+                minimum_bucket_activations: filtered_output.minimum_bucket_activations,
+                ..settings.clone()
+            };
+            write_output(&filtered_settings, &analysis, stale_weekdays.as_deref(), Some(&filtered_output.output_file))?;
+        }
+    }
 
-        // we're assuming that VRCX is actually running for this whole range, so update the VRCX running dates as well...
-        buckets[day_index][bucket_index].register_date(start_time);
+    if let Some(recommendation_count) = settings.recommendation_count {
+        let mut values = settings.output_values(buckets, comparison_buckets.as_deref());
+        if settings.recommendation_rank == RecommendationRank::Quiet {
+            // a bucket that only looks quiet because VRCX has barely seen it is no recommendation at all
+            if let Some(low_confidence) = settings.low_confidence_flags(buckets, comparison_buckets.as_deref()) {
+                for (values_for_day, low_confidence_for_day) in values.iter_mut().zip(low_confidence) {
+                    for (value, low_confidence) in values_for_day.iter_mut().zip(low_confidence_for_day) {
+                        if low_confidence {
+                            *value = None;
+                        }
+                    }
+                }
+            }
+        }
+        // stale weekdays are left out, as their values could be months out of date
+        let recommendation_weekdays: Option<Vec<Weekday>> = match &stale_weekdays {
+            Some(stale_weekdays) => Some((0..DAYS_PER_WEEK)
+                .map(|day| Weekday::from_usize(day).unwrap())
+                .filter(|weekday| settings.recommendation_weekdays.as_ref().is_none_or(|weekdays| weekdays.contains(weekday)))
+                .filter(|weekday| !stale_weekdays[usize::try_from(weekday.num_days_from_monday()).unwrap()])
+                .collect()),
+            None => settings.recommendation_weekdays.clone(),
+        };
+        let recommendations = recommend_upcoming_buckets(&settings.bucket_layout, Local::now(), recommendation_weekdays.as_deref(), settings.recommend_min_friends, settings.recommendation_rank, &values, buckets);
+        print_recommendations(recommendation_count, settings.recommendation_sort, settings.recommendation_rank, settings.number_format(), settings.value_kind(), &recommendations);
+    }
+
+    if let Some((calendar_path, calendar_threshold)) = &settings.calendar_export {
+        let values = settings.output_values(buckets, comparison_buckets.as_deref());
+        let ics = calendar_ics(&settings.bucket_layout, *calendar_threshold, &values, Local::now().date_naive());
+        fs::write(calendar_path, ics).map_err(|e| Error::Output(format!("{calendar_path}: {e}")))?;
+    }
+    if let Some((friend_calendar_path, friend_calendar_threshold)) = &settings.friend_calendar_export {
+        let ics = friend_calendar_ics(&settings.bucket_layout, *friend_calendar_threshold, &analysis.display_names, analysis.friend_grids.as_deref().unwrap(), Local::now().date_naive());
+        fs::write(friend_calendar_path, ics).map_err(|e| Error::Output(format!("{friend_calendar_path}: {e}")))?;
+    }
 
-        start_time += bucket_duration;
+    if let Some(bundle_file) = &settings.bundle_file {
+        let bundle = bundle_json(&settings, &analysis, Utc::now());
+        fs::write(bundle_file, bundle.to_string()).map_err(|e| Error::Output(format!("{bundle_file}: {e}")))?;
     }
+
+    warnings.print(settings.warnings_format);
+
+    if settings.print_runtime {
+        info!("Finished in {:.3}s.", application_start_time.elapsed().as_millis() as f64 / 1000.0);
+    }
+
+    Ok(())
 }
 
-/// register this range's dates as active for the relevant buckets
-fn register_bucket_dates_for_range(bucket_duration: Duration, bucket_duration_minutes: u32, time_span: TimeSpan, buckets: &mut [Vec<BucketValue>]) {
-    let end_time = time_span.stop.with_timezone(&Local);
-    let start_time = time_span.start.with_timezone(&Local);
-    let first_bucket_start_time = start_time.duration_trunc(bucket_duration).unwrap();
-    // start at first WHOLE bucket
-    let mut current_time = if first_bucket_start_time == start_time {
-        first_bucket_start_time
+/// Write the whole analysis in the configured output format to `output_file`, or stdout if there isn't one, split by month
+/// or into chunks if configured
+fn write_output(settings: &Settings, analysis: &Analysis, stale_weekdays: Option<&[bool]>, output_file: Option<&str>) -> Result<(), Error> {
+    let Analysis { buckets, comparison_buckets, .. } = analysis;
+    let mut out: Vec<u8> = Vec::new();
+    if settings.split_by_month {
+        let buckets_per_day = settings.bucket_layout.buckets_per_day();
+        let monthly_buckets = split_buckets_by_month(buckets_per_day, buckets);
+        let monthly_comparison_buckets = comparison_buckets.as_deref().map(|comparison_buckets| split_buckets_by_month(buckets_per_day, comparison_buckets));
+        let empty_buckets = build_daily_buckets(buckets_per_day);
+        for (index, ((year, month), buckets)) in monthly_buckets.iter().enumerate() {
+            write_section_heading(settings.output_format, index, &format!("{year:04}-{month:02}"), &mut out);
+            let comparison_buckets = monthly_comparison_buckets.as_ref()
+                .map(|monthly_comparison_buckets| monthly_comparison_buckets.get(&(*year, *month)).unwrap_or(&empty_buckets).as_slice());
+            write_grid(settings, analysis, buckets, comparison_buckets, None, &mut out);
+        }
+    } else if let Some(chunk_count) = settings.chunk_count {
+        write_chunks(settings, analysis, chunk_count, &mut out);
     } else {
-        let second_bucket_start_time = first_bucket_start_time + bucket_duration;
+        write_grid(settings, analysis, buckets, comparison_buckets.as_deref(), stale_weekdays, &mut out);
+    }
+    emit_output(output_file, &out)
+}
 
-        // handle the first, partial bucket
-        let first_bucket_duration = TimeSpan::new(first_bucket_start_time.with_timezone(&Utc), second_bucket_start_time.with_timezone(&Utc)).duration();
-        if first_bucket_duration > bucket_duration / 2 {
-            register_bucket_date(bucket_duration_minutes, second_bucket_start_time, buckets);
+/// Split the history from the first to the last date VRCX was active into `chunk_count` contiguous runs of whole days, as
+/// close to equal in length as possible, and write a grid for each, labeled with its first and last date. If there are
+/// fewer days than chunks, the empty chunks are skipped.
+fn write_chunks(settings: &Settings, analysis: &Analysis, chunk_count: u32, out: &mut Vec<u8>) {
+    let buckets_per_day = settings.bucket_layout.buckets_per_day();
+    let activity_dates = || analysis.buckets.iter().flatten().flat_map(|bucket_value| bucket_value.vrcx_activity_dates.iter());
+    let (Some(first_date), Some(last_date)) = (activity_dates().min(), activity_dates().max()) else {
+        info!("No VRCX activity to split into chunks.");
+        return;
+    };
+    let first_day = first_date.date_naive();
+    let total_days = (last_date.date_naive() - first_day).num_days() + 1;
+    let chunk_start_day = |chunk_index: u32| first_day + Duration::days(total_days * i64::from(chunk_index) / i64::from(chunk_count));
+    let mut written_chunks = 0;
+    for chunk_index in 0..chunk_count {
+        let (start_day, end_day) = (chunk_start_day(chunk_index), chunk_start_day(chunk_index + 1));
+        if start_day == end_day {
+            continue;
         }
+        let chunk_span = TimeSpan::new(day_start(start_day), day_start(end_day));
+        let chunk_buckets = buckets_in_span(buckets_per_day, chunk_span, &analysis.buckets);
+        let chunk_comparison_buckets = analysis.comparison_buckets.as_deref().map(|comparison_buckets| buckets_in_span(buckets_per_day, chunk_span, comparison_buckets));
+        write_section_heading(settings.output_format, written_chunks, &format!("{start_day} to {}", end_day - Duration::days(1)), out);
+        write_grid(settings, analysis, &chunk_buckets, chunk_comparison_buckets.as_deref(), None, out);
+        written_chunks += 1;
+    }
+}
 
-        second_bucket_start_time
-    };
+/// label the grid for a month or chunk, separating it from the one before if there is one
+fn write_section_heading(output_format: OutputFormat, index: usize, label: &str, out: &mut Vec<u8>) {
+    if index != 0 {
+        out.push(b'\n');
+    }
+    if output_format == OutputFormat::Markdown {
+        // a heading, and the blank line a table needs to not run into the text above it
+        out.extend_from_slice(format!("### {label}\n\n").as_bytes());
+    } else {
+        out.extend_from_slice(format!("{label}\n").as_bytes());
+    }
+}
 
-    // process each WHOLE bucket
-    while current_time < end_time {
-        register_bucket_date(bucket_duration_minutes, current_time, buckets);
-        current_time += bucket_duration;
+/// Write the configured output format for some buckets, diffed against comparison buckets if we're comparing friend sets.
+/// Weekdays flagged in `stale_weekdays` are labeled as such.
+fn write_grid(
+    settings: &Settings,
+    analysis: &Analysis,
+    buckets: &[Vec<BucketValue>],
+    comparison_buckets: Option<&[Vec<BucketValue>]>,
+    stale_weekdays: Option<&[bool]>,
+    out: &mut Vec<u8>,
+) {
+    let mut text = String::new();
+    let text_out = &mut text;
+    match settings.output_format {
+        OutputFormat::Table | OutputFormat::Markdown => write_value_grid(
+            text_out,
+            &settings.bucket_layout,
+            settings.table_style(),
+            settings.no_data_returns_zero,
+            &settings.output_values(buckets, comparison_buckets),
+            settings.low_confidence_flags(buckets, comparison_buckets).as_deref(),
+            stale_weekdays,
+        ),
+        OutputFormat::Raw => write_raw_buckets(text_out, &settings.bucket_layout, settings.table_style(), buckets),
+        OutputFormat::Blocks => write_day_blocks(
+            text_out,
+            &settings.bucket_layout,
+            settings.table_style(),
+            settings.normalize,
+            &settings.day_block_start_hours,
+            settings.no_data_returns_zero,
+            buckets,
+        ),
+        OutputFormat::Sparkline => write_sparklines(text_out, &settings.output_values(buckets, comparison_buckets)),
+        OutputFormat::Highlights => write_highlights(text_out, &settings.bucket_layout, settings.time_format_12h, settings.highlight_window, &settings.output_values(buckets, comparison_buckets)),
+        OutputFormat::Stddev => write_value_grid(
+            text_out,
+            &settings.bucket_layout,
+            settings.table_style(),
+            settings.no_data_returns_zero,
+            &standard_deviation_values(settings.minimum_bucket_activations, buckets),
+            settings.low_confidence_flags(buckets, None).as_deref(),
+            stale_weekdays,
+        ),
+        OutputFormat::Reliability => write_value_grid(
+            text_out,
+            &settings.bucket_layout,
+            settings.table_style(),
+            settings.no_data_returns_zero,
+            &reliability_values(settings.minimum_bucket_activations, buckets),
+            settings.low_confidence_flags(buckets, None).as_deref(),
+            stale_weekdays,
+        ),
+        OutputFormat::Ranges => write_ranges(
+            text_out,
+            &settings.bucket_layout,
+            settings.table_style(),
+            settings.range_tolerance,
+            settings.no_data_returns_zero,
+            &settings.output_values(buckets, comparison_buckets),
+        ),
+        OutputFormat::Npy => out.extend(npy_bytes(&settings.output_values(buckets, comparison_buckets), settings.no_data_returns_zero)),
+        // friends output can't be split by month or into windows, so these are always the whole analysis
+        OutputFormat::Friends => write_friend_peaks(text_out, &settings.bucket_layout, settings.time_format_12h, &analysis.display_names, analysis.friend_peaks.as_deref().unwrap()),
     }
+    out.extend_from_slice(text.as_bytes());
+}
 
-    // handle any remaining time
-    let last_bucket_start_time = current_time;
-    let last_bucket_duration = TimeSpan::new(last_bucket_start_time.with_timezone(&Utc), time_span.stop).duration();
-    if last_bucket_duration > bucket_duration / 2 {
-        register_bucket_date(bucket_duration_minutes, last_bucket_start_time, buckets);
+/// write the output to `output_file` if one is configured, or stdout otherwise
+fn emit_output(output_file: Option<&str>, out: &[u8]) -> Result<(), Error> {
+    match output_file {
+        Some(output_file) => fs::write(output_file, out).map_err(|e| Error::Output(format!("{output_file}: {e}"))),
+        None => io::stdout().write_all(out).map_err(|e| Error::Output(format!("stdout: {e}"))),
     }
 }
 
-#[inline]
-fn register_bucket_date(bucket_duration_minutes: u32, bucket_time: DateTime<Local>, buckets: &mut [Vec<BucketValue>]) {
-    let weekday = bucket_time.weekday();
-    let day_index = usize::try_from(weekday.num_days_from_monday()).unwrap();
-    let time = bucket_time.time();
-    let minutes_of_day = u32::try_from(time.signed_duration_since(NaiveTime::default()).num_minutes()).unwrap();
-    let bucket_index = usize::try_from(minutes_of_day / bucket_duration_minutes).unwrap();
-    buckets[day_index][bucket_index].register_date(bucket_time);
+/// Write a grid for each sliding window to its own numbered file, starting from the Monday of the week the earliest VRCX
+/// activity falls in. Only windows that fit entirely before the end of the last active week are written, except that the
+/// first window is always written so a short history still produces something.
+fn write_sliding_windows(settings: &Settings, analysis: &Analysis, sliding_windows: &SlidingWindows) -> Result<(), Error> {
+    let buckets_per_day = settings.bucket_layout.buckets_per_day();
+    let activity_dates = || analysis.buckets.iter().flatten().flat_map(|bucket_value| bucket_value.vrcx_activity_dates.iter());
+    let (Some(first_date), Some(last_date)) = (activity_dates().min(), activity_dates().max()) else {
+        info!("No VRCX activity to split into windows.");
+        return Ok(());
+    };
+    let first_monday = first_date.date_naive() - Duration::days(i64::from(first_date.weekday().num_days_from_monday()));
+    let last_week_end = week_start(last_date.date_naive()) + Duration::weeks(1);
+    let extension = match settings.output_format {
+        OutputFormat::Markdown => "md",
+        OutputFormat::Npy => "npy",
+        _ => "tsv",
+    };
+    fs::create_dir_all(&sliding_windows.output_dir).map_err(|e| Error::Output(format!("{}: {e}", sliding_windows.output_dir)))?;
+
+    for window_index in 0.. {
+        let window_start_date = first_monday + Duration::weeks(i64::from(window_index) * i64::from(sliding_windows.step_weeks));
+        let window_span = TimeSpan::new(week_start(window_start_date), week_start(window_start_date + Duration::weeks(i64::from(sliding_windows.window_weeks))));
+        if window_index != 0 && window_span.stop > last_week_end {
+            break;
+        }
+        let window_buckets = buckets_in_span(buckets_per_day, window_span, &analysis.buckets);
+        let window_comparison_buckets = analysis.comparison_buckets.as_deref().map(|comparison_buckets| buckets_in_span(buckets_per_day, window_span, comparison_buckets));
+        let mut out: Vec<u8> = Vec::new();
+        write_grid(settings, analysis, &window_buckets, window_comparison_buckets.as_deref(), None, &mut out);
+        let path = Path::new(&sliding_windows.output_dir).join(format!("window-{:04}.{extension}", window_index + 1));
+        fs::write(&path, out).map_err(|e| Error::Output(format!("{}: {e}", path.display())))?;
+        if settings.verbose {
+            info!("Wrote {} ({} to {})", path.display(), window_start_date, window_span.stop.with_timezone(&Local).date_naive());
+        }
+    }
+    Ok(())
 }
 
-fn print_statistics(
-    bucket_duration_seconds: u32,
-    start_time: Option<DateTime<Utc>>,
-    first_event_timestamp: Option<DateTime<Utc>>,
-    last_event_timestamp: Option<DateTime<Utc>>,
-    all_event_count: usize,
-    online_offline_event_count: usize,
-    buckets: &[Vec<BucketValue>],
+/// the share of dropped sessions above which `print_statistics` suggests the uptime detection is misconfigured
+const DROPPED_SESSION_WARNING_PERCENT: f64 = 10.0;
+
+/// Write a summary of the data, one line per figure. Ages, like how old the last VRCX data is, are measured from
+/// `current_time`, so the same data always gives the same output for the same clock.
+fn write_statistics(
+    out: &mut String,
+    bucket_layout: &BucketLayout,
+    analysis_range: AnalysisRange,
+    stats_hours: Option<(u32, u32)>,
+    analysis: &Analysis,
+    current_time: DateTime<Utc>,
+    warnings: &mut Warnings,
 ) {
-    let current_time = Utc::now();
+    let Analysis { first_event_timestamp, last_event_timestamp, all_event_count, online_offline_event_count, buckets, .. } = analysis;
+
+    writeln!(out, "Processed {all_event_count} timestamps and {online_offline_event_count} online/offline events.").unwrap();
+
+    let session_count = analysis.clamp_stats.clamped_session_count();
+    let ClampStats { dropped, dropped_duration, .. } = analysis.clamp_stats;
+    if session_count != 0 {
+        let dropped_hours: f64 = f64::from_i64(dropped_duration.num_milliseconds()).unwrap() / f64::from(MILLISECONDS_PER_HOUR);
+        let dropped_percent: f64 = 100.0 * dropped as f64 / session_count as f64;
+        writeln!(out, "Dropped {dropped} of {session_count} friend sessions ({dropped_percent:.1}%, {dropped_hours:.2} hours) because neither end fell within VRCX uptime.").unwrap();
+        if dropped_percent > DROPPED_SESSION_WARNING_PERCENT {
+            warnings.push(WarningKind::DroppedSessions, format!("{dropped_percent:.1}% of friend sessions were dropped. If VRCX was really running during them, vrcx_running_detection_threshold_minutes may be too low for how often your VRCX records events."));
+        }
+    }
 
-    eprintln!("Processed {all_event_count} timestamps and {online_offline_event_count} online/offline events.");
+    // The uptime figures only count buckets starting within stats_hours, and the time ranges they're compared against are
+    // scaled down to the same share of each day.
+    let in_stats_hours = |bucket_index: usize| stats_hours.is_none_or(|(start_hour, end_hour)| {
+        let start_minutes = bucket_layout.start_minutes(bucket_index);
+        let (mask_start_minutes, mask_end_minutes) = (start_hour * MINUTES_PER_HOUR, end_hour * MINUTES_PER_HOUR);
+        if mask_start_minutes < mask_end_minutes {
+            mask_start_minutes <= start_minutes && start_minutes < mask_end_minutes
+        } else {
+            // the hours wrap past midnight
+            mask_start_minutes <= start_minutes || start_minutes < mask_end_minutes
+        }
+    });
+    let stats_day_fraction: f64 = (0..bucket_layout.buckets_per_day())
+        .filter(|bucket_index| in_stats_hours(*bucket_index))
+        .map(|bucket_index| f64::from_i64(bucket_layout.duration(bucket_index).num_minutes()).unwrap())
+        .sum::<f64>() / f64::from(MINUTES_PER_DAY);
+    let range_description = match stats_hours {
+        Some((start_hour, end_hour)) => format!(" within stats_hours ({start_hour}:00 to {end_hour}:00)"),
+        None => String::new(),
+    };
 
-    if let Some(first_event_timestamp) = first_event_timestamp {
-        if let Some(last_event_timestamp) = last_event_timestamp {
+    if let Some(first_event_timestamp) = *first_event_timestamp {
+        if let Some(last_event_timestamp) = *last_event_timestamp {
             let vrcx_duration: Duration = current_time.signed_duration_since(first_event_timestamp);
-            let vrcx_hours: f64 = f64::from_i64(vrcx_duration.num_milliseconds()).unwrap() / f64::from(MILLISECONDS_PER_HOUR);
+            let vrcx_hours: f64 = stats_day_fraction * f64::from_i64(vrcx_duration.num_milliseconds()).unwrap() / f64::from(MILLISECONDS_PER_HOUR);
 
             let vrcx_offline_duration = current_time.signed_duration_since(last_event_timestamp);
             let vrcx_offline_hours: f64 = f64::from_i64(vrcx_offline_duration.num_milliseconds()).unwrap() / f64::from(MILLISECONDS_PER_HOUR);
 
-            let activations: usize = buckets.iter().flatten()
-                .map(|bucket_value| bucket_value.total_dates())
+            let active_duration: Duration = buckets.iter()
+                .flat_map(|buckets_for_day| buckets_for_day.iter().enumerate())
+                .filter(|(bucket_index, _)| in_stats_hours(*bucket_index))
+                .map(|(bucket_index, bucket_value)| bucket_layout.duration(bucket_index) * i32::try_from(bucket_value.total_dates()).unwrap())
                 .sum();
-            let active_seconds: i64 = i64::try_from(activations).unwrap() * i64::from(bucket_duration_seconds);
-            let active_duration: Duration = Duration::seconds(active_seconds);
             let active_hours: f64 = f64::from_i64(active_duration.num_milliseconds()).unwrap() / f64::from(MILLISECONDS_PER_HOUR);
 
             let active_percent: f64 = 100.0 * active_hours / vrcx_hours;
 
-            eprintln!("VRCX range: {vrcx_hours:.2} hours. VRCX actually active for {active_hours:.2} hours. That's {active_percent:.1}% uptime. Last VRCX data is {vrcx_offline_hours:.2} hours old.");
+            writeln!(out, "VRCX range: {vrcx_hours:.2} hours{range_description}. VRCX actually active for {active_hours:.2} hours. That's {active_percent:.1}% uptime. Last VRCX data is {vrcx_offline_hours:.2} hours old.").unwrap();
         }
     }
 
-    if let Some(start_time) = start_time {
-        let desired_duration = current_time.signed_duration_since(start_time);
-        let desired_hours: f64 = f64::from_i64(desired_duration.num_milliseconds()).unwrap() / f64::from(MILLISECONDS_PER_HOUR);
-
-        let activations: usize = buckets.iter().flatten()
-            .flat_map(|bucket_value| bucket_value.vrcx_activity_dates.iter())
-            .filter(|time| time >= &&start_time)
-            .count();
-        let active_seconds: i64 = i64::try_from(activations).unwrap() * i64::from(bucket_duration_seconds);
-        let active_duration: Duration = Duration::seconds(active_seconds);
+    if analysis_range.is_bounded() {
+        let desired_start_time = analysis_range.start.or(*first_event_timestamp).unwrap_or(current_time);
+        let desired_end_time = analysis_range.end.unwrap_or(current_time);
+        let desired_duration = desired_end_time.signed_duration_since(desired_start_time);
+        let desired_hours: f64 = stats_day_fraction * f64::from_i64(desired_duration.num_milliseconds()).unwrap() / f64::from(MILLISECONDS_PER_HOUR);
+
+        let active_duration: Duration = buckets.iter()
+            .flat_map(|buckets_for_day| buckets_for_day.iter().enumerate())
+            .filter(|(bucket_index, _)| in_stats_hours(*bucket_index))
+            .map(|(bucket_index, bucket_value)| {
+                let activations = bucket_value.count_dates(|time| analysis_range.contains(time.with_timezone(&Utc)));
+                bucket_layout.duration(bucket_index) * i32::try_from(activations).unwrap()
+            })
+            .sum();
         let active_hours: f64 = f64::from_i64(active_duration.num_milliseconds()).unwrap() / f64::from(MILLISECONDS_PER_HOUR);
 
         let active_percent: f64 = 100.0 * active_hours / desired_hours;
 
-        eprintln!("Desired range: {desired_hours:.2} hours. VRCX actually active for {active_hours:.2} hours. That's {active_percent:.1}% uptime.");
+        writeln!(out, "Desired range: {desired_hours:.2} hours{range_description}. VRCX actually active for {active_hours:.2} hours. That's {active_percent:.1}% uptime.").unwrap();
     }
 }
 
-/// print bucket data to console
-fn print_buckets(
-    bucket_duration_seconds: u32,
-    buckets_per_day: usize,
-    normalize: bool,
-    minimum_bucket_activations: u32,
-    no_data_returns_zero: bool,
-    buckets: Vec<Vec<BucketValue>>,
-) {
-    // header
-    print!("bucket");
-    for day in 0..DAYS_PER_WEEK {
-        let weekday = Weekday::from_usize(day).unwrap();
-        print!("\t{weekday}");
-    }
-    println!();
-
-    for bucket_index in 0..buckets_per_day {
-        print!("{}", bucket_index_to_label(bucket_duration_seconds, bucket_index));
-        for day in 0..DAYS_PER_WEEK {
-            let buckets_for_day = buckets.get(day).unwrap();
-            let bucket_value = buckets_for_day.get(bucket_index).unwrap();
-            let online_count = bucket_value.online_count;
-
-            let vrcx_activity_count = bucket_value.total_dates();
-            if vrcx_activity_count == 0 && online_count != 0 {
-                panic!("We somehow have vrcx_activity_count={vrcx_activity_count} and online_count={online_count}, which is nonsensical.");
-            }
+/// Print every distinct user id and display name pair from the online/offline events, sorted by display name, so people
+/// can find the ids to put in `friend_ids`. A user who changed their display name is listed once per name.
+fn list_users(settings: &Settings) -> Result<(), Error> {
+    let event_data = read_all_events(settings)?;
+    let users: BTreeSet<(&str, &str)> = event_data.iter()
+        .flat_map(|event_data| &event_data.online_offline_rows)
+        .map(|row| (row.display_name.as_str(), row.user_id.as_str()))
+        .collect();
+    println!("user_id\tdisplay_name");
+    for (display_name, user_id) in users {
+        println!("{user_id}\t{display_name}");
+    }
+    Ok(())
+}
 
-            if u32::try_from(vrcx_activity_count).unwrap() < minimum_bucket_activations {
-                // not enough activity, so return no data
-                if no_data_returns_zero {
-                    print!("\t0");
-                } else {
-                    print!("\t");
-                }
-            } else if normalize {
-                // we're normalizing, so we have to do floating point math
-                // we'll just do this in a completely separate branch than the un-normalized stuff
-
-                /* This next line requires some explanation. TL;DR: it's to account for bias in when data is recorded.
-                 *
-                 * Imagine you started using VRCX 100 weeks ago (nearly two years). You don't always run VRCX, because you
-                 * turn your computer off sometimes. Lets say that on Saturdays you have a 90% chance of having VRCX running,
-                 * while on Wednesdays you only have a 5% chance. Lets call a bucket "active" for a day if VRCX was running.
-                 * This means a given Saturday bucket would have been active for ~90 days, but a Wednesday bucket would only have
-                 * been active for ~5 days.
-                 *
-                 * Next, imagine you have a friend who has zero reason to their schedule, and has a perfectly equal chance of being online
-                 * at any given time. Without accounting for the bias introduced by when you run VRCX, this friend would appear 18x more
-                 * active on Sundays than Wednesdays, which is clearly not true. So you'd see say, 180 hits for Sunday and 10 hits for Wednesday.
-                 *
-                 * The solution is to record the number of days for which a bucket is "active", and divide the friend online count by that activity count.
-                 * This normalizes the data. For Sunday, 180 / 90 = 2. For Wednesday, 10 / 5 = 2.
-                 */
-                let normalized_online_activity: f64 = f64::from(online_count) / f64::from_usize(vrcx_activity_count).unwrap();
-                print!("\t{normalized_online_activity}");
-            } else {
-                // we aren't normalizing, so we just return the online_count integer
-                print!("\t{online_count}");
-            }
-        }
-        println!();
+/// Print every VRCX uptime interval as TSV in local time, with its length in hours, followed by a total on stderr. These are
+/// the intervals friend sessions get clamped to.
+fn dump_uptime(vrcx_uptime: &VrcxUptime) {
+    const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+    let mut total_duration = Duration::zero();
+    println!("start\tstop\tduration_hours");
+    for interval in vrcx_uptime.intervals() {
+        let hours = f64::from_i64(interval.duration().num_milliseconds()).unwrap() / f64::from(MILLISECONDS_PER_HOUR);
+        println!("{}\t{}\t{hours:.2}", interval.start.with_timezone(&Local).format(TIME_FORMAT), interval.stop.with_timezone(&Local).format(TIME_FORMAT));
+        total_duration += interval.duration();
+    }
+    let total_hours = f64::from_i64(total_duration.num_milliseconds()).unwrap() / f64::from(MILLISECONDS_PER_HOUR);
+    info!("{} uptime intervals, totaling {total_hours:.2} hours.", vrcx_uptime.intervals().len());
+}
+
+/// Warn about events dated in the future. VRCX stores `created_at` as UTC, so this shouldn't happen unless a VRCX version
+/// started storing local times, in which case everything would be silently shifted by the UTC offset.
+fn warn_about_future_events(analysis: &Analysis, warnings: &mut Warnings) {
+    let last_event_timestamp = analysis.last_event_timestamp.unwrap();
+    let hours_ahead = f64::from_i64(last_event_timestamp.signed_duration_since(Utc::now()).num_milliseconds()).unwrap() / f64::from(MILLISECONDS_PER_HOUR);
+    let utc_offset = Local::now().offset().to_string();
+    warnings.push(WarningKind::FutureEvents, format!("{} events are dated in the future, the latest by {hours_ahead:.2} hours. VRCX timestamps are expected to be UTC; if they're actually local time, the results are shifted by your UTC offset ({utc_offset}). Check your system clock and VRCX version.", analysis.future_event_count));
+}
+
+/// Warn that the VRCX running detection threshold is shorter than the typical gap between events, which splits uptime into
+/// fragments too small to hold most friend sessions. The 90th percentile gap is suggested instead, as it bridges the
+/// normal gaps without also bridging the times VRCX was really closed.
+fn warn_about_low_detection_threshold(vrcx_running_detection_threshold: Duration, event_gap_percentiles: &EventGapPercentiles, warnings: &mut Warnings) {
+    let threshold_minutes = vrcx_running_detection_threshold.num_minutes();
+    let median_minutes = f64::from_i64(event_gap_percentiles.median.num_milliseconds()).unwrap() / f64::from(MILLISECONDS_PER_MINUTE);
+    let suggested_minutes = (event_gap_percentiles.percentile_90.num_milliseconds() + i64::from(MILLISECONDS_PER_MINUTE) - 1) / i64::from(MILLISECONDS_PER_MINUTE);
+    let suggested_minutes = suggested_minutes.max(threshold_minutes + 1);
+    warnings.push(WarningKind::LowDetectionThreshold, format!("vrcx_running_detection_threshold_minutes ({threshold_minutes}) is shorter than the median gap between VRCX events ({median_minutes:.1} minutes), so VRCX uptime is probably split into tiny fragments and many sessions are dropped. Consider setting it to {suggested_minutes} (the 90th percentile gap) or higher."));
+}
+
+/// Warn about data that made no sense and was skipped, with the first anomaly as an example
+fn warn_about_anomalies(analysis: &Analysis, warnings: &mut Warnings) {
+    if let Some(first_message) = analysis.anomalies.first_message() {
+        warnings.push(WarningKind::DataAnomalies, format!("Skipped {} item(s) of VRCX data that made no sense, so results may be slightly off. The first was: {first_message} Set strict = true to stop on the first one instead.", analysis.anomalies.count()));
+    }
+}
+
+/// Warn if not a single bucket has a friend online count, which is almost always a misconfiguration rather than a real
+/// result, and would otherwise just print a grid of zeros or nothing at all. Buckets may still have VRCX activity dates,
+/// as those come from every event, not just the friend sessions being counted.
+fn warn_about_empty_grid(settings: &Settings, analysis: &Analysis, warnings: &mut Warnings) {
+    let is_empty = |buckets: &[Vec<BucketValue>]| buckets.iter().flatten().all(|bucket_value| bucket_value.online_count == 0.0);
+    if !is_empty(&analysis.buckets) || !analysis.comparison_buckets.as_deref().is_none_or(is_empty) {
+        return;
+    }
+    let mut causes = vec!["your_user_id doesn't match the database", "vrcx_db_path points at the wrong database"];
+    if settings.friend_ids.is_some() || settings.compare_friend_ids.is_some() || settings.overlap_friend_ids.is_some() || settings.absent_friend_ids.is_some() {
+        causes.push("the friend filters don't match anyone");
+    }
+    if settings.analysis_range.is_bounded() {
+        causes.push("start_time or end_time leave out all of the data");
     }
+    warnings.push(WarningKind::EmptyGrid, format!("No bucket has anyone online, so there's nothing to show. This is usually a misconfiguration: check whether {}.", causes.join(", or ")));
 }
 
-/// convert a bucket index into a label string
-fn bucket_index_to_label(bucket_duration_seconds: u32, bucket_index: usize) -> String {
-    let time = bucket_index_to_time(bucket_duration_seconds, bucket_index);
-    format!("{:02}:{:02}", time.hour(), time.minute())
+/// Warn about weekdays VRCX hasn't been active on recently. Their values reflect how things used to be, which may no
+/// longer hold.
+fn warn_about_stale_weekdays(stale_weekday_threshold: Duration, stale_weekdays: &[bool], warnings: &mut Warnings) {
+    let stale_weekday_names: Vec<String> = stale_weekdays.iter().enumerate()
+        .filter(|(_, stale)| **stale)
+        .map(|(day, _)| Weekday::from_usize(day).unwrap().to_string())
+        .collect();
+    if !stale_weekday_names.is_empty() {
+        warnings.push(WarningKind::StaleWeekdays, format!("VRCX hasn't been active on {} in the last {} days, so their values may be out of date. They're marked as stale and won't be recommended.", stale_weekday_names.join(", "), stale_weekday_threshold.num_days()));
+    }
 }
 
-/// convert a bucket index to the time of day
-fn bucket_index_to_time(bucket_duration_seconds: u32, bucket_index: usize) -> NaiveTime {
-    let seconds_from_midnight = bucket_duration_seconds * u32::try_from(bucket_index).unwrap();
-    NaiveTime::from_num_seconds_from_midnight_opt(seconds_from_midnight, 0).unwrap()
+/// Warn if any buckets with exactly one activation will be normalized. Such buckets divide by 1, which produces misleading
+/// spikes, so we suggest a `minimum_bucket_activations` floor based on how many activations a typical bucket has.
+fn warn_about_single_activation_buckets(buckets: &[Vec<BucketValue>], warnings: &mut Warnings) {
+    let mut total_dates: Vec<usize> = buckets.iter().flatten()
+        .map(|bucket_value| bucket_value.total_dates())
+        .filter(|total_dates| *total_dates != 0)
+        .collect();
+    let single_activation_bucket_count = total_dates.iter().filter(|total_dates| **total_dates == 1).count();
+    if single_activation_bucket_count == 0 {
+        return;
+    }
+
+    total_dates.sort_unstable();
+    let median_total_dates = total_dates[total_dates.len() / 2];
+    // a quarter of the typical sample size keeps most buckets while dropping the noisiest ones
+    let suggested_minimum = (median_total_dates / 4).max(2);
+    let single_activation_buckets = plural(single_activation_bucket_count, "bucket was", "buckets were");
+    let median_dates = plural(median_total_dates, "date", "dates");
+    warnings.push(WarningKind::SingleActivationBuckets, format!("{single_activation_buckets} only active on a single date, so normalizing them may produce misleading spikes. Consider setting minimum_bucket_activations to {suggested_minimum} or higher (the median bucket was active on {median_dates})."));
 }
 
-/// check if a given user has been filtered out by our configuration
-fn is_user_allowed(user_id: &str, friend_ids: &Option<HashSet<String>>) -> bool {
-    // if friend ids is unset, then allow every user id
-    friend_ids.as_ref().map_or(true, |friend_ids| friend_ids.contains(user_id))
+/// a count followed by whichever of `singular` or `plural` agrees with it
+fn plural(count: usize, singular: &str, plural: &str) -> String {
+    format!("{count} {}", if count == 1 { singular } else { plural })
 }
 
-/// parse a timestamp from a sqlite result
-fn parse_created_at(row: &rusqlite::Row<'_>) -> Result<DateTime<Utc>, rusqlite::Error> {
-    let created_at: String = row.get(COLUMN_INDEX_CREATED_AT)?;
-    Ok(created_at.parse::<DateTime<Utc>>().unwrap())
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Timelike};
+
+    use crate::analysis::analyze_events;
+    use crate::dto::{OnlineOfflineEventType, Row};
+    use crate::source::EventData;
+
+    use super::*;
+
+    /// the settings for `extra_config` on top of the few keys every config needs. The database is never opened.
+    fn settings(extra_config: &str) -> Settings {
+        let config = format!("your_user_id = \"usr_me\"\nvrcx_db_path = \"VRCX.sqlite3\"\nvrcx_running_detection_threshold_minutes = 30\n{extra_config}");
+        Settings::from_config(Configuration::parse(&config).unwrap()).unwrap()
+    }
+
+    /// A local time in February 2024, which has no DST transitions in any common zone. The fixture is laid out in local
+    /// time so it falls in the same buckets whatever the time zone.
+    fn local(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(2024, 2, day, hour, minute, 0).unwrap().with_timezone(&Utc)
+    }
+
+    /// the clock every fixture is analyzed at, early on the Sunday after the fixture
+    fn fixture_current_time() -> DateTime<Utc> {
+        local(11, 0, 0)
+    }
+
+    /// a few days of VRCX running in stretches, with several friends coming and going, starting Monday at 12:03
+    fn fixture_events() -> Vec<EventData> {
+        let start = local(5, 12, 3);
+        let all_event_timestamps = (0..3 * 24 * 12)
+            .map(|index| start + Duration::minutes(5 * index))
+            // VRCX is closed from 03:00 to 12:00 every day
+            .filter(|timestamp| (timestamp.with_timezone(&Local).hour() + 24 - 12) % 24 < 15)
+            .collect();
+        let row = |minutes: i64, user_id: &str, event_type: OnlineOfflineEventType| Row {
+            created_at: start + Duration::minutes(minutes),
+            user_id: user_id.to_owned(),
+            display_name: user_id.trim_start_matches("usr_").to_owned(),
+            event_type,
+        };
+        let mut online_offline_rows = Vec::new();
+        for day in 0..3 {
+            let day_minutes = day * 24 * 60;
+            for (friend_index, user_id) in ["usr_a", "usr_b", "usr_c", "usr_d", "usr_e"].into_iter().enumerate() {
+                let online_minutes = day_minutes + 17 * i64::try_from(friend_index).unwrap() + 7 * day;
+                online_offline_rows.push(row(online_minutes, user_id, OnlineOfflineEventType::Online));
+                online_offline_rows.push(row(online_minutes + 95 + 31 * day, user_id, OnlineOfflineEventType::Offline));
+            }
+        }
+        online_offline_rows.sort_by_key(|row| row.created_at);
+        vec![EventData {
+            all_event_timestamps,
+            online_offline_rows,
+            self_activity_timestamps: Vec::new(),
+        }]
+    }
+
+    fn fixture_output(settings: &Settings) -> Vec<u8> {
+        let analysis = analyze_events(settings, fixture_events(), fixture_current_time()).unwrap();
+        let mut out = Vec::new();
+        write_grid(settings, &analysis, &analysis.buckets, analysis.comparison_buckets.as_deref(), None, &mut out);
+        out
+    }
+
+    #[test]
+    fn repeated_runs_give_identical_output() {
+        let configs = [
+            "bucket_duration_minutes = 30\nnormalize = true\nweight_by_overlap = true\nfriend_weights = { usr_a = 0.3, usr_b = 1.7, usr_c = 0.1, usr_d = 2.9, usr_e = 1.1 }\ndecimal_places = 17",
+            "bucket_duration_minutes = 45\nalign_to_hour = true\nnormalize = false\noutput_format = \"raw\"",
+            "bucket_duration_minutes = 60\nnormalize = true\noutput_format = \"friends\"",
+        ];
+        for config in configs {
+            let settings = settings(config);
+            let first_output = fixture_output(&settings);
+            assert!(!first_output.is_empty());
+            for _ in 0..5 {
+                assert_eq!(String::from_utf8_lossy(&first_output), String::from_utf8_lossy(&fixture_output(&settings)), "output changed between runs for config:\n{config}");
+            }
+        }
+    }
+
+    fn fixture_statistics(settings: &Settings) -> Vec<String> {
+        let current_time = fixture_current_time();
+        let analysis = analyze_events(settings, fixture_events(), current_time).unwrap();
+        let mut statistics = String::new();
+        write_statistics(&mut statistics, &settings.bucket_layout, settings.analysis_range, settings.stats_hours, &analysis, current_time, &mut Warnings::default());
+        statistics.lines().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn statistics_are_measured_from_current_time() {
+        let statistics = fixture_statistics(&settings("bucket_duration_minutes = 60\nnormalize = true"));
+        // the events run from Monday 12:03 to Thursday 02:58, with VRCX active from 12:00 to 03:00 each day
+        assert_eq!(statistics, [
+            "Processed 540 timestamps and 30 online/offline events.",
+            "Dropped 0 of 15 friend sessions (0.0%, 0.00 hours) because neither end fell within VRCX uptime.",
+            "VRCX range: 131.95 hours. VRCX actually active for 45.00 hours. That's 34.1% uptime. Last VRCX data is 69.03 hours old.",
+        ]);
+    }
+
+    #[test]
+    fn statistics_for_analysis_range_and_stats_hours() {
+        // Tuesday and Wednesday, ending a millisecond early as end_time includes a bucket starting exactly at it
+        let start_time = local(6, 0, 0).to_rfc3339();
+        let end_time = (local(8, 0, 0) - Duration::milliseconds(1)).to_rfc3339();
+        let config = format!("bucket_duration_minutes = 60\nnormalize = true\nstart_time = \"{start_time}\"\nend_time = \"{end_time}\"");
+        let statistics = fixture_statistics(&settings(&config));
+        // VRCX was active from midnight to 03:00 and from 12:00 to midnight on both days
+        assert_eq!(statistics[3], "Desired range: 48.00 hours. VRCX actually active for 30.00 hours. That's 62.5% uptime.");
+
+        // only 12:00 to 18:00 counts, so a quarter of each day
+        let statistics = fixture_statistics(&settings(&format!("{config}\nstats_hours = [12, 18]")));
+        assert_eq!(statistics[2], "VRCX range: 32.99 hours within stats_hours (12:00 to 18:00). VRCX actually active for 18.00 hours. That's 54.6% uptime. Last VRCX data is 69.03 hours old.");
+        assert_eq!(statistics[3], "Desired range: 12.00 hours within stats_hours (12:00 to 18:00). VRCX actually active for 12.00 hours. That's 100.0% uptime.");
+    }
+
+    #[test]
+    fn plural_agrees_with_count() {
+        assert_eq!(plural(1, "minute", "minutes"), "1 minute");
+        assert_eq!(plural(0, "date", "dates"), "0 dates");
+        assert_eq!(plural(5, "bucket was", "buckets were"), "5 buckets were");
+    }
 }
@@ -0,0 +1,60 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+use std::collections::{BTreeSet, HashMap};
+
+use chrono::{DateTime, Utc};
+
+use crate::dto::Row;
+
+/// Maps user ids to human-readable display names. User ids are opaque, so anything that shows a user should go through
+/// here instead of printing the raw id.
+#[derive(Default)]
+pub struct DisplayNames {
+    /// user id to the most recently seen display name, and when it was seen
+    names: HashMap<String, (DateTime<Utc>, String)>,
+}
+
+impl DisplayNames {
+    /// Collect display names from the online/offline events. If a user renamed themselves, the most recent name wins.
+    pub fn from_rows(rows: &[Row]) -> Self {
+        let mut names: HashMap<String, (DateTime<Utc>, String)> = HashMap::new();
+        for row in rows {
+            match names.get_mut(&row.user_id) {
+                Some((seen_at, display_name)) => {
+                    if row.created_at >= *seen_at {
+                        *seen_at = row.created_at;
+                        display_name.clone_from(&row.display_name);
+                    }
+                }
+                None => {
+                    names.insert(row.user_id.clone(), (row.created_at, row.display_name.clone()));
+                }
+            }
+        }
+        Self {
+            names,
+        }
+    }
+
+    /// the display name for a user id, if we've seen that user
+    pub fn get(&self, user_id: &str) -> Option<&str> {
+        self.names.get(user_id).map(|(_, display_name)| display_name.as_str())
+    }
+
+    /// the display name for a user id, falling back to the id itself for users we've never seen
+    pub fn label<'a>(&'a self, user_id: &'a str) -> &'a str {
+        self.get(user_id).unwrap_or(user_id)
+    }
+}
+
+/// Every user id seen with each display name, for finding friends by name. A user who renamed themselves can be found by
+/// any name they've used, and a name several users have used maps to all of them.
+pub fn user_ids_by_display_name(rows: &[Row]) -> HashMap<&str, BTreeSet<&str>> {
+    let mut user_ids: HashMap<&str, BTreeSet<&str>> = HashMap::new();
+    for row in rows {
+        user_ids.entry(row.display_name.as_str()).or_default().insert(row.user_id.as_str());
+    }
+    user_ids
+}
@@ -0,0 +1,40 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+//! NumPy `.npy` export, so the grid can be loaded with `numpy.load` without any parsing.
+
+use crate::output::ValueGrid;
+
+/// the start of every `.npy` file, followed by format version 1.0
+const NPY_MAGIC: &[u8] = b"\x93NUMPY\x01\x00";
+
+/// The header plus magic is padded to a multiple of this. Version 1.0 only requires 16, but NumPy itself pads to 64 so
+/// the data is aligned for memory mapping.
+const NPY_HEADER_ALIGNMENT: usize = 64;
+
+/// Build a `.npy` file holding the values as a `[day][bucket]` array of little-endian `f64`, so `numpy.load` gives an array
+/// of shape `(7, buckets_per_day)`. Buckets with no data are NaN, or 0 if `no_data_returns_zero` is set.
+pub fn npy_bytes(values: &ValueGrid, no_data_returns_zero: bool) -> Vec<u8> {
+    let buckets_per_day = values.first().map_or(0, Vec::len);
+    let mut header = format!("{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, {buckets_per_day}), }}", values.len());
+    // the header length is a u16 after the magic, and the header ends in a newline
+    let unpadded_length = NPY_MAGIC.len() + 2 + header.len() + 1;
+    let padded_length = unpadded_length.div_ceil(NPY_HEADER_ALIGNMENT) * NPY_HEADER_ALIGNMENT;
+    header.extend(std::iter::repeat_n(' ', padded_length - unpadded_length));
+    header.push('\n');
+
+    let mut npy = Vec::with_capacity(padded_length + values.len() * buckets_per_day * size_of::<f64>());
+    npy.extend_from_slice(NPY_MAGIC);
+    npy.extend_from_slice(&u16::try_from(header.len()).unwrap().to_le_bytes());
+    npy.extend_from_slice(header.as_bytes());
+    for value in values.iter().flatten() {
+        let value = match value {
+            Some(value) => *value,
+            None if no_data_returns_zero => 0.0,
+            None => f64::NAN,
+        };
+        npy.extend_from_slice(&value.to_le_bytes());
+    }
+    npy
+}
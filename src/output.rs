@@ -0,0 +1,578 @@
+// Copyright 2022-2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+use std::fmt::Write;
+
+use chrono::{Datelike, DateTime, Duration, Local, NaiveTime, Weekday};
+use num_traits::cast::FromPrimitive;
+
+use crate::analysis::FriendPeak;
+use crate::config::{LabelStyle, NormalizationBaseline, NormalizationMode};
+use crate::constants::{DAYS_PER_WEEK, MINUTES_PER_HOUR};
+use crate::dto::BucketValue;
+use crate::layout::{time_label, BucketLayout};
+use crate::names::DisplayNames;
+
+/// Output values indexed by `[day][bucket]`. `None` means a bucket has no data, either because it didn't have enough VRCX
+/// activity or because one side of a comparison had no data for it.
+pub type ValueGrid = Vec<Vec<Option<f64>>>;
+
+/// full weekday names, starting on Monday
+const WEEKDAY_NAMES: [&str; DAYS_PER_WEEK] = ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"];
+
+/// the names of the blocks of the day used by [`write_day_blocks`], in order
+pub const DAY_BLOCK_NAMES: [&str; 4] = ["morning", "afternoon", "evening", "night"];
+
+/// compute the value we output for each bucket
+pub fn bucket_output_values(
+    normalize: NormalizationMode,
+    normalization_baseline: NormalizationBaseline,
+    minimum_bucket_activations: u32,
+    recency_half_life: Option<Duration>,
+    buckets: &[Vec<BucketValue>],
+) -> ValueGrid {
+    let baseline_dates = baseline_activity_count(normalization_baseline, buckets);
+    // dates are aged relative to the latest one in the grid, so the most recent date always counts fully
+    let recency = recency_half_life.zip(buckets.iter().flatten().filter_map(BucketValue::last_date).max());
+    let weekday_occurrences = (normalize == NormalizationMode::Weekday).then(|| weekday_occurrence_counts(buckets));
+    let mut values = buckets.iter()
+        .enumerate()
+        .map(|(day, buckets_for_day)| buckets_for_day.iter()
+            .map(|bucket_value| match weekday_occurrences {
+                // the bucket still needs enough activity to be trusted, but the count is divided by the calendar instead
+                Some(weekday_occurrences) => bucket_output_value(NormalizationMode::None, None, minimum_bucket_activations, None, bucket_value)
+                    .map(|online_count| online_count / f64::from_usize(weekday_occurrences[day].max(1)).unwrap()),
+                None => bucket_output_value(normalize, baseline_dates, minimum_bucket_activations, recency, bucket_value),
+            })
+            .collect())
+        .collect();
+    if normalize == NormalizationMode::Delta {
+        subtract_mean(&mut values);
+    }
+    values
+}
+
+/// How many times each weekday occurred from the first to the last date VRCX was active for any bucket, inclusive, indexed
+/// by day. Unlike `total_dates()` this counts every calendar day in that range, whether or not VRCX was running, so it
+/// only corrects for the range not being a whole number of weeks.
+pub fn weekday_occurrence_counts(buckets: &[Vec<BucketValue>]) -> [usize; DAYS_PER_WEEK] {
+    let mut occurrences = [0; DAYS_PER_WEEK];
+    let first_date = buckets.iter().flatten().filter_map(BucketValue::first_date).min();
+    let last_date = buckets.iter().flatten().filter_map(BucketValue::last_date).max();
+    if let (Some(first_date), Some(last_date)) = (first_date, last_date) {
+        for date in first_date.date_naive().iter_days().take_while(|date| *date <= last_date.date_naive()) {
+            occurrences[usize::try_from(date.weekday().num_days_from_monday()).unwrap()] += 1;
+        }
+    }
+    occurrences
+}
+
+/// Subtract the mean of every value with data from each of them, so they become signed deviations from the average.
+/// Values without data stay without data.
+fn subtract_mean(values: &mut ValueGrid) {
+    let Some(mean) = mean_value(values) else {
+        return;
+    };
+    for value in values.iter_mut().flatten().flatten() {
+        *value -= mean;
+    }
+}
+
+/// the mean of every value with data, or `None` if none have any
+pub fn mean_value(values: &ValueGrid) -> Option<f64> {
+    let (sum, count) = values.iter()
+        .flatten()
+        .flatten()
+        .fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+    (count != 0).then(|| sum / f64::from(count))
+}
+
+/// The single activity count every bucket is normalized against, or `None` to use each bucket's own count. Buckets VRCX
+/// was never active for are ignored, and if there are no active buckets at all we fall back to per-bucket counts.
+pub fn baseline_activity_count(normalization_baseline: NormalizationBaseline, buckets: &[Vec<BucketValue>]) -> Option<usize> {
+    let activity_counts = || -> Vec<usize> {
+        buckets.iter()
+            .flatten()
+            .map(BucketValue::total_dates)
+            .filter(|count| *count != 0)
+            .collect()
+    };
+    match normalization_baseline {
+        NormalizationBaseline::Bucket => None,
+        NormalizationBaseline::Maximum => activity_counts().into_iter().max(),
+        NormalizationBaseline::Median => {
+            let mut activity_counts = activity_counts();
+            activity_counts.sort_unstable();
+            activity_counts.get(activity_counts.len() / 2).copied()
+        }
+    }
+}
+
+/// How much a date counts for when dates decay with age, halving every `half_life` before `latest_date`
+pub fn recency_decay_weight(half_life: Duration, latest_date: DateTime<Local>, date: DateTime<Local>) -> f64 {
+    let age = latest_date.signed_duration_since(date).num_milliseconds() as f64;
+    0.5_f64.powf(age / half_life.num_milliseconds() as f64)
+}
+
+/// Compute one bucket's output value. `recency` is the half-life and latest date to decay dates by, if dates are weighted
+/// by age.
+fn bucket_output_value(
+    normalize: NormalizationMode,
+    baseline_dates: Option<usize>,
+    minimum_bucket_activations: u32,
+    recency: Option<(Duration, DateTime<Local>)>,
+    bucket_value: &BucketValue,
+) -> Option<f64> {
+    let online_count = bucket_value.online_count;
+
+    let vrcx_activity_count = bucket_value.total_dates();
+    // discard_inactive_online_counts resets these before they get here
+    debug_assert!(vrcx_activity_count != 0 || online_count == 0.0, "We somehow have vrcx_activity_count={vrcx_activity_count} and online_count={online_count}, which is nonsensical.");
+    if vrcx_activity_count == 0 {
+        return None;
+    }
+
+    let date_weight = |(half_life, latest_date): (Duration, DateTime<Local>)| move |date| recency_decay_weight(half_life, latest_date, date);
+    if u32::try_from(vrcx_activity_count).unwrap() < minimum_bucket_activations {
+        // not enough activity, so return no data
+        None
+    } else if let Some(recency) = recency.filter(|_| normalize == NormalizationMode::Probability) {
+        bucket_value.weighted_online_date_fraction(date_weight(recency)).map(|fraction| 100.0 * fraction)
+    } else if let Some(recency) = recency.filter(|_| matches!(normalize, NormalizationMode::Activity | NormalizationMode::Delta)) {
+        // every date counts toward both the friend count and the number of dates by the same weight, so a stretch of old
+        // dates fades out of the average entirely rather than just diluting it
+        bucket_value.weighted_online_count_mean(date_weight(recency))
+    } else if normalize == NormalizationMode::Probability {
+        // the fraction of observed dates that had at least one friend online, as a percentage
+        Some(100.0 * f64::from_usize(bucket_value.total_online_dates()).unwrap() / f64::from_usize(vrcx_activity_count).unwrap())
+    } else if normalize == NormalizationMode::Coverage {
+        // the same idea as activity normalization below, but a date VRCX only saw part of the bucket for only counts for
+        // that part. Friend counts are weighted by overlap in this mode, so both sides are on the same footing.
+        let coverage = bucket_value.total_coverage();
+        (coverage > 0.0).then(|| online_count / coverage)
+    } else if normalize == NormalizationMode::Activity || normalize == NormalizationMode::Delta {
+        // we're normalizing, so we have to do floating point math
+        // we'll just do this in a completely separate branch than the un-normalized stuff
+
+        /* This next line requires some explanation. TL;DR: it's to account for bias in when data is recorded.
+         *
+         * Imagine you started using VRCX 100 weeks ago (nearly two years). You don't always run VRCX, because you
+         * turn your computer off sometimes. Lets say that on Saturdays you have a 90% chance of having VRCX running,
+         * while on Wednesdays you only have a 5% chance. Lets call a bucket "active" for a day if VRCX was running.
+         * This means a given Saturday bucket would have been active for ~90 days, but a Wednesday bucket would only have
+         * been active for ~5 days.
+         *
+         * Next, imagine you have a friend who has zero reason to their schedule, and has a perfectly equal chance of being online
+         * at any given time. Without accounting for the bias introduced by when you run VRCX, this friend would appear 18x more
+         * active on Sundays than Wednesdays, which is clearly not true. So you'd see say, 180 hits for Sunday and 10 hits for Wednesday.
+         *
+         * The solution is to record the number of days for which a bucket is "active", and divide the friend online count by that activity count.
+         * This normalizes the data. For Sunday, 180 / 90 = 2. For Wednesday, 10 / 5 = 2.
+         *
+         * If VRCX runs around the clock, small differences between buckets' activity counts are just noise, so
+         * normalization_baseline can swap in a single count shared by every bucket.
+         */
+        Some(online_count / f64::from_usize(baseline_dates.unwrap_or(vrcx_activity_count)).unwrap())
+    } else {
+        // we aren't normalizing, so we just return the online_count (which is an integer unless weighting is on)
+        Some(online_count)
+    }
+}
+
+/// The standard deviation of each bucket's friend online count across dates, for gauging how reliable a bucket is. Buckets
+/// active on fewer than `minimum_bucket_activations` dates have no data.
+pub fn standard_deviation_values(minimum_bucket_activations: u32, buckets: &[Vec<BucketValue>]) -> ValueGrid {
+    buckets.iter()
+        .map(|buckets_for_day| buckets_for_day.iter()
+            .map(|bucket_value| {
+                (u32::try_from(bucket_value.total_dates()).unwrap() >= minimum_bucket_activations)
+                    .then(|| bucket_value.online_count_standard_deviation())
+                    .flatten()
+            })
+            .collect())
+        .collect()
+}
+
+/// The reliability score of each bucket, as in [`BucketValue::online_count_reliability`], for ranking buckets by how
+/// dependably busy they are. Buckets active on fewer than `minimum_bucket_activations` dates have no data.
+pub fn reliability_values(minimum_bucket_activations: u32, buckets: &[Vec<BucketValue>]) -> ValueGrid {
+    buckets.iter()
+        .map(|buckets_for_day| buckets_for_day.iter()
+            .map(|bucket_value| {
+                (u32::try_from(bucket_value.total_dates()).unwrap() >= minimum_bucket_activations)
+                    .then(|| bucket_value.online_count_reliability())
+                    .flatten()
+            })
+            .collect())
+        .collect()
+}
+
+/// Smooth values using their `radius` neighboring buckets to either side, treating the week as one continuous loop so
+/// smoothing carries across midnight and from Sunday back to Monday. Each bucket with data contributes in proportion to
+/// its `total_dates()`, so noisy low-sample buckets are pulled toward well-sampled neighbors rather than the other way
+/// around. Buckets without data contribute nothing and stay without data.
+pub fn smooth_output_values(radius: usize, values: &ValueGrid, buckets: &[Vec<BucketValue>]) -> ValueGrid {
+    let buckets_per_day = values.first().map_or(0, Vec::len);
+    let buckets_per_week = values.len() * buckets_per_day;
+    let value_at = |index: usize| {
+        let (day, bucket_index) = (index / buckets_per_day, index % buckets_per_day);
+        values[day][bucket_index].map(|value| (value, f64::from_usize(buckets[day][bucket_index].total_dates()).unwrap()))
+    };
+    // more neighbors than there are buckets would just count some buckets twice
+    let radius = radius.min(buckets_per_week.saturating_sub(1) / 2);
+
+    values.iter().enumerate()
+        .map(|(day, values_for_day)| values_for_day.iter().enumerate()
+            .map(|(bucket_index, value)| {
+                value.map(|_| {
+                    let index = day * buckets_per_day + bucket_index;
+                    let (weighted_sum, total_weight) = (index + buckets_per_week - radius..=index + buckets_per_week + radius)
+                        .filter_map(|neighbor_index| value_at(neighbor_index % buckets_per_week))
+                        .fold((0.0, 0.0), |(weighted_sum, total_weight), (value, weight)| (weighted_sum + value * weight, total_weight + weight));
+                    weighted_sum / total_weight
+                })
+            })
+            .collect())
+        .collect()
+}
+
+/// Subtract one grid from another bucket-by-bucket. A bucket with no data on either side has no data in the result, as
+/// there's nothing meaningful to compare.
+pub fn difference_output_values(a: &ValueGrid, b: &ValueGrid) -> ValueGrid {
+    a.iter().zip(b)
+        .map(|(a, b)| a.iter().zip(b)
+            .map(|(a, b)| Some((*a)? - (*b)?))
+            .collect())
+        .collect()
+}
+
+/// Flag buckets that were active on fewer than `low_confidence_threshold` dates, indexed by `[day][bucket]`. When
+/// comparing, a bucket is flagged if either side is.
+pub fn low_confidence_flags(low_confidence_threshold: u32, buckets: &[Vec<BucketValue>], comparison_buckets: Option<&[Vec<BucketValue>]>) -> Vec<Vec<bool>> {
+    let is_low_confidence = |bucket_value: &BucketValue| u32::try_from(bucket_value.total_dates()).unwrap() < low_confidence_threshold;
+    buckets.iter().enumerate()
+        .map(|(day, buckets_for_day)| buckets_for_day.iter().enumerate()
+            .map(|(bucket_index, bucket_value)| {
+                is_low_confidence(bucket_value) || comparison_buckets.is_some_and(|comparison_buckets| is_low_confidence(&comparison_buckets[day][bucket_index]))
+            })
+            .collect())
+        .collect()
+}
+
+/// how the tables are laid out and their numbers written
+#[derive(Clone, Copy)]
+pub struct TableStyle {
+    pub time_format_12h: bool,
+    pub label_style: LabelStyle,
+    /// a row per weekday and a column per bucket, instead of the other way around
+    pub transpose: bool,
+    pub number_format: NumberFormat,
+    /// what the grid's values are, which decides how they're written
+    pub value_kind: ValueKind,
+    /// a Markdown table instead of TSV
+    pub markdown: bool,
+}
+
+impl TableStyle {
+    fn format_value(self, value: f64) -> String {
+        self.number_format.format(value, self.value_kind)
+    }
+}
+
+/// what a number being written represents
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    /// a sum of friend online counts, which is a whole number unless friends are weighted
+    Count,
+    /// anything calculated from counts, such as an average
+    Measure,
+}
+
+/// how numbers are written
+#[derive(Clone, Copy)]
+pub struct NumberFormat {
+    /// written in place of the `.` in fractional values, for spreadsheets in comma-decimal locales
+    pub decimal_separator: char,
+    /// round fractional values to this many decimal places instead of writing every digit
+    pub decimal_places: Option<usize>,
+}
+
+impl NumberFormat {
+    /// Write a value. Whole counts are always written without decimals, so an unweighted count of 3 stays "3" rather than
+    /// "3.00". Everything else is rounded to `decimal_places` if it's set, or written the same way as `f64`'s `Display`
+    /// otherwise. Either way, `decimal_separator` goes in place of the `.`.
+    pub fn format(self, value: f64, value_kind: ValueKind) -> String {
+        let value = match self.decimal_places {
+            Some(decimal_places) if !(value_kind == ValueKind::Count && value.fract() == 0.0) => format!("{value:.decimal_places$}"),
+            _ => value.to_string(),
+        };
+        if self.decimal_separator == '.' {
+            value
+        } else {
+            value.replace('.', self.decimal_separator.encode_utf8(&mut [0; 4]))
+        }
+    }
+}
+
+/// Flag each weekday VRCX hasn't been active on within `stale_weekday_threshold` of `current_time`, indexed by day. A
+/// weekday VRCX was never active on is stale too.
+pub fn stale_weekdays(stale_weekday_threshold: Duration, current_time: DateTime<Local>, buckets: &[Vec<BucketValue>]) -> Vec<bool> {
+    buckets.iter()
+        .map(|buckets_for_day| {
+            let latest_activity = buckets_for_day.iter().filter_map(BucketValue::last_date).max();
+            latest_activity.is_none_or(|latest_activity| latest_activity < current_time - stale_weekday_threshold)
+        })
+        .collect()
+}
+
+/// Write bucket data as a table. Values flagged in `low_confidence` get a `?` appended, and weekdays flagged in
+/// `stale_weekdays` get a `(stale)` label. Normally each row is a bucket and each column a weekday, but `style.transpose`
+/// swaps them.
+pub fn write_value_grid(
+    out: &mut String,
+    bucket_layout: &BucketLayout,
+    style: TableStyle,
+    no_data_returns_zero: bool,
+    values: &ValueGrid,
+    low_confidence: Option<&[Vec<bool>]>,
+    stale_weekdays: Option<&[bool]>,
+) {
+    let day_labels: Vec<String> = (0..DAYS_PER_WEEK)
+        .map(|day| match stale_weekdays {
+            Some(stale_weekdays) if stale_weekdays[day] => format!("{} (stale)", Weekday::from_usize(day).unwrap()),
+            _ => Weekday::from_usize(day).unwrap().to_string(),
+        })
+        .collect();
+    write_table(out, &bucket_layout.labels(style.label_style, style.time_format_12h), &day_labels, style, |bucket_index, day| {
+        match values[day][bucket_index] {
+            Some(value) if low_confidence.is_some_and(|low_confidence| low_confidence[day][bucket_index]) => format!("{}?", style.format_value(value)),
+            Some(value) => style.format_value(value),
+            None if no_data_returns_zero => "0".to_owned(),
+            None => String::new(),
+        }
+    });
+}
+
+/// Write a line per weekday with a Unicode block sparkline of its values across the day, one character per bucket. Values
+/// are scaled to the whole week, so the days can be compared with each other. The scale starts at zero unless there are
+/// negative values (from comparing friend sets). Buckets with no data are blank.
+pub fn write_sparklines(out: &mut String, values: &ValueGrid) {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let known_values = || values.iter().flatten().flatten().copied();
+    let min = known_values().fold(0.0, f64::min);
+    let max = known_values().fold(0.0, f64::max);
+    let range = max - min;
+    for (day, values_for_day) in values.iter().enumerate() {
+        let sparkline: String = values_for_day.iter()
+            .map(|value| match value {
+                Some(_) if range == 0.0 => BLOCKS[0],
+                Some(value) => BLOCKS[((value - min) / range * (BLOCKS.len() - 1) as f64).round() as usize],
+                None => ' ',
+            })
+            .collect();
+        writeln!(out, "{}\t{sparkline}", Weekday::from_usize(day).unwrap()).unwrap();
+    }
+}
+
+/// Write each weekday's best contiguous window as "Mon: 20:00–21:00", for a daily planner. A window starting at a bucket
+/// takes in following buckets until it's at least `window` long, and its value is the sum of the values in it. Windows
+/// never cross midnight, and buckets without data, such as those below `minimum_bucket_activations`, add nothing. A day
+/// without any data says so, and ties go to the earliest window.
+pub fn write_highlights(out: &mut String, bucket_layout: &BucketLayout, time_format_12h: bool, window: Duration, values: &ValueGrid) {
+    for (day, values_for_day) in values.iter().enumerate() {
+        let mut best: Option<(f64, usize, Duration)> = None;
+        for start_index in 0..values_for_day.len() {
+            let mut end_index = start_index;
+            let mut window_duration = Duration::zero();
+            let mut sum: Option<f64> = None;
+            while end_index < values_for_day.len() && window_duration < window {
+                if let Some(value) = values_for_day[end_index] {
+                    sum = Some(sum.unwrap_or(0.0) + value);
+                }
+                window_duration += bucket_layout.duration(end_index);
+                end_index += 1;
+            }
+            if let Some(sum) = sum {
+                if best.is_none_or(|(best_sum, _, _)| sum > best_sum) {
+                    best = Some((sum, start_index, window_duration));
+                }
+            }
+        }
+        let weekday = Weekday::from_usize(day).unwrap();
+        match best {
+            Some((_, start_index, window_duration)) => {
+                let start_time = bucket_layout.start_time(start_index);
+                let end_time = start_time + window_duration;
+                writeln!(out, "{weekday}: {}–{}", time_label(start_time, time_format_12h), time_label(end_time, time_format_12h)).unwrap();
+            }
+            None => writeln!(out, "{weekday}: no data").unwrap(),
+        }
+    }
+}
+
+/// Write each weekday as tab-separated runs of consecutive buckets, like "Mon 20:00–21:30 2.3", so a fine-grained grid
+/// reads as a handful of time ranges. A run takes in following buckets as long as their values are within `tolerance` of
+/// its first value, and is written with the mean of its values. Buckets with no data make up runs of their own, unless
+/// `no_data_returns_zero` is set. Runs never cross midnight.
+pub fn write_ranges(out: &mut String, bucket_layout: &BucketLayout, style: TableStyle, tolerance: f64, no_data_returns_zero: bool, values: &ValueGrid) {
+    for (day, values_for_day) in values.iter().enumerate() {
+        let weekday = Weekday::from_usize(day).unwrap();
+        let values_for_day: Vec<Option<f64>> = values_for_day.iter()
+            .map(|value| if no_data_returns_zero { Some(value.unwrap_or(0.0)) } else { *value })
+            .collect();
+        let mut start_index = 0;
+        while start_index < values_for_day.len() {
+            let first_value = values_for_day[start_index];
+            let end_index = values_for_day[start_index..].iter()
+                .position(|value| match (first_value, value) {
+                    (Some(first_value), Some(value)) => (value - first_value).abs() > tolerance,
+                    (None, None) => false,
+                    _ => true,
+                })
+                .map_or(values_for_day.len(), |run_length| start_index + run_length);
+            let run = &values_for_day[start_index..end_index];
+            let value = match first_value {
+                Some(_) => style.format_value(run.iter().flatten().sum::<f64>() / f64::from_usize(run.len()).unwrap()),
+                None => "no data".to_owned(),
+            };
+            let start_time = bucket_layout.start_time(start_index);
+            let end_time = bucket_layout.start_time(end_index - 1) + bucket_layout.duration(end_index - 1);
+            writeln!(out, "{weekday}\t{}–{}\t{value}", time_label(start_time, style.time_format_12h), time_label(end_time, style.time_format_12h)).unwrap();
+            start_index = end_index;
+        }
+    }
+}
+
+/// Write each friend's single best bucket as "DisplayName: Saturday 21:00", sorted by display name
+pub fn write_friend_peaks(out: &mut String, bucket_layout: &BucketLayout, time_format_12h: bool, display_names: &DisplayNames, friend_peaks: &[FriendPeak]) {
+    let mut lines: Vec<(&str, String)> = friend_peaks.iter()
+        .map(|friend_peak| {
+            let weekday = WEEKDAY_NAMES[friend_peak.day_index];
+            let time = bucket_layout.label(friend_peak.bucket_index, time_format_12h);
+            (display_names.label(&friend_peak.user_id), format!("{weekday} {time}"))
+        })
+        .collect();
+    lines.sort_unstable();
+    for (display_name, peak) in lines {
+        writeln!(out, "{display_name}: {peak}").unwrap();
+    }
+}
+
+/// Write the raw quantities behind each bucket, so they can be normalized externally. Each weekday gets two columns (or
+/// rows, if transposed): the friend online count, and the number of dates VRCX was active for the bucket.
+pub fn write_raw_buckets(out: &mut String, bucket_layout: &BucketLayout, style: TableStyle, buckets: &[Vec<BucketValue>]) {
+    let quantity_labels: Vec<String> = (0..DAYS_PER_WEEK)
+        .flat_map(|day| {
+            let weekday = Weekday::from_usize(day).unwrap();
+            [format!("{weekday} online_count"), format!("{weekday} total_dates")]
+        })
+        .collect();
+    write_table(out, &bucket_layout.labels(style.label_style, style.time_format_12h), &quantity_labels, style, |bucket_index, quantity| {
+        let bucket_value = &buckets[quantity / 2][bucket_index];
+        if quantity % 2 == 0 {
+            style.number_format.format(bucket_value.online_count, ValueKind::Count)
+        } else {
+            bucket_value.total_dates().to_string()
+        }
+    });
+}
+
+/// Write each weekday collapsed into a handful of named blocks of the day, such as "morning". Each block sums the friend
+/// online counts and VRCX-active date counts of its member buckets, then is normalized as a whole, so a block is only as
+/// noisy as all of its buckets put together. `day_block_start_hours` holds the hour each block starts at; buckets before
+/// the first block start count toward the last block, so night can span midnight.
+pub fn write_day_blocks(
+    out: &mut String,
+    bucket_layout: &BucketLayout,
+    style: TableStyle,
+    normalize: NormalizationMode,
+    day_block_start_hours: &[u32],
+    no_data_returns_zero: bool,
+    buckets: &[Vec<BucketValue>],
+) {
+    let block_count = day_block_start_hours.len();
+    let block_index = |bucket_index: usize| {
+        let start_hour = bucket_layout.start_minutes(bucket_index) / MINUTES_PER_HOUR;
+        day_block_start_hours.iter().rposition(|block_start_hour| *block_start_hour <= start_hour).unwrap_or(block_count - 1)
+    };
+
+    // (online count, online dates, active dates, coverage) for each block
+    let mut block_totals = vec![vec![(0.0, 0, 0, 0.0); block_count]; DAYS_PER_WEEK];
+    for (day, buckets_for_day) in buckets.iter().enumerate() {
+        for (bucket_index, bucket_value) in buckets_for_day.iter().enumerate() {
+            let (online_count, online_dates, active_dates, coverage) = &mut block_totals[day][block_index(bucket_index)];
+            *online_count += bucket_value.online_count;
+            *online_dates += bucket_value.total_online_dates();
+            *active_dates += bucket_value.total_dates();
+            *coverage += bucket_value.total_coverage();
+        }
+    }
+
+    let block_labels: Vec<String> = DAY_BLOCK_NAMES.iter().zip(day_block_start_hours)
+        .map(|(name, start_hour)| format!("{name} {}", time_label(NaiveTime::from_hms_opt(*start_hour, 0, 0).unwrap(), style.time_format_12h)))
+        .collect();
+    let day_labels: Vec<String> = (0..DAYS_PER_WEEK)
+        .map(|day| Weekday::from_usize(day).unwrap().to_string())
+        .collect();
+    let weekday_occurrences = weekday_occurrence_counts(buckets);
+    let mut block_values: ValueGrid = block_totals.into_iter()
+        .enumerate()
+        .map(|(day, totals_for_day)| totals_for_day.into_iter()
+            .map(|(online_count, online_dates, active_dates, coverage)| match normalize {
+                _ if active_dates == 0 => None,
+                NormalizationMode::Coverage => (coverage > 0.0).then(|| online_count / coverage),
+                NormalizationMode::Weekday => Some(online_count / f64::from_usize(weekday_occurrences[day].max(1)).unwrap()),
+                NormalizationMode::None => Some(online_count),
+                NormalizationMode::Activity | NormalizationMode::Delta => Some(online_count / f64::from_usize(active_dates).unwrap()),
+                NormalizationMode::Probability => Some(100.0 * f64::from_usize(online_dates).unwrap() / f64::from_usize(active_dates).unwrap()),
+            })
+            .collect())
+        .collect();
+    if normalize == NormalizationMode::Delta {
+        // deviations from the average block, rather than the average bucket
+        subtract_mean(&mut block_values);
+    }
+    write_table(out, &block_labels, &day_labels, style, |block, day| {
+        match block_values[day][block] {
+            Some(value) => style.format_value(value),
+            None if no_data_returns_zero => "0".to_owned(),
+            None => String::new(),
+        }
+    });
+}
+
+/// Write a TSV table with a row per bucket and the provided columns, or a column per bucket and the provided rows if
+/// `style.transpose` is set. `cell` gets the bucket index and the column index. With `style.markdown` the table is written
+/// in Markdown instead, with every column but the labels right-aligned.
+pub fn write_table(out: &mut String, bucket_labels: &[String], labels: &[String], style: TableStyle, cell: impl Fn(usize, usize) -> String) {
+    let (corner_label, row_labels, column_labels) = if style.transpose {
+        ("day", labels, bucket_labels)
+    } else {
+        ("bucket", bucket_labels, labels)
+    };
+    let (line_start, separator, line_end) = if style.markdown {
+        ("| ", " | ", " |")
+    } else {
+        ("", "\t", "")
+    };
+
+    // header
+    write!(out, "{line_start}{corner_label}").unwrap();
+    for column_label in column_labels {
+        write!(out, "{separator}{column_label}").unwrap();
+    }
+    writeln!(out, "{line_end}").unwrap();
+    if style.markdown {
+        writeln!(out, "| --- |{}", " ---: |".repeat(column_labels.len())).unwrap();
+    }
+
+    for (row, row_label) in row_labels.iter().enumerate() {
+        write!(out, "{line_start}{row_label}").unwrap();
+        for column in 0..column_labels.len() {
+            let (bucket_index, index) = if style.transpose { (column, row) } else { (row, column) };
+            write!(out, "{separator}{}", cell(bucket_index, index)).unwrap();
+        }
+        writeln!(out, "{line_end}").unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+//! `--profile`: time spent in each phase of a run, written as collapsed stacks (one `run;analyze;read events 1234` line
+//! per stack, in microseconds), which `flamegraph.pl`, `inferno-flamegraph`, and speedscope all read. Phases are marked
+//! with [`span`] guards. Without the `profile` feature these compile to nothing, so default builds pay nothing for them.
+
+#[cfg(feature = "profile")]
+pub use enabled::{span, start};
+
+#[cfg(not(feature = "profile"))]
+pub use disabled::span;
+
+#[cfg(feature = "profile")]
+mod enabled {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::fmt::Write;
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    /// the spans currently open, and the time recorded so far
+    struct Profiler {
+        /// each open span's name, and how long the spans nested in it took
+        stack: Vec<(&'static str, Duration)>,
+        /// time spent directly in each stack of spans, not counting spans nested below it
+        self_times: BTreeMap<String, Duration>,
+    }
+
+    thread_local! {
+        /// only set while recording, and only on the main thread
+        static PROFILER: RefCell<Option<Profiler>> = const { RefCell::new(None) };
+    }
+
+    /// Start recording spans on this thread. The profile is written to `path` when the returned guard is dropped.
+    pub fn start(path: String) -> Recording {
+        PROFILER.with_borrow_mut(|profiler| *profiler = Some(Profiler { stack: Vec::new(), self_times: BTreeMap::new() }));
+        Recording { path }
+    }
+
+    /// writes the profile once dropped, at the end of the run
+    pub struct Recording {
+        path: String,
+    }
+
+    impl Drop for Recording {
+        fn drop(&mut self) {
+            let Some(profiler) = PROFILER.with_borrow_mut(Option::take) else {
+                return;
+            };
+            let mut out = String::new();
+            for (stack, self_time) in &profiler.self_times {
+                writeln!(out, "{stack} {}", self_time.as_micros()).unwrap();
+            }
+            // this runs on the way out of `run`, when there's no error left to return it as
+            if let Err(e) = fs::write(&self.path, out) {
+                eprintln!("Error: could not write profile to {}: {e}", self.path);
+            }
+        }
+    }
+
+    /// a phase being timed, which ends when this is dropped
+    pub struct Span {
+        /// `None` if we weren't recording when the span started
+        start: Option<Instant>,
+    }
+
+    /// Time a phase of the run until the returned guard is dropped. Spans opened while this one is open are nested in it.
+    pub fn span(name: &'static str) -> Span {
+        let recording = PROFILER.with_borrow_mut(|profiler| profiler.as_mut().map(|profiler| profiler.stack.push((name, Duration::ZERO))).is_some());
+        Span { start: recording.then(Instant::now) }
+    }
+
+    impl Drop for Span {
+        fn drop(&mut self) {
+            let Some(start) = self.start else {
+                return;
+            };
+            let elapsed = start.elapsed();
+            PROFILER.with_borrow_mut(|profiler| {
+                let Some(profiler) = profiler else {
+                    return;
+                };
+                let stack: Vec<&str> = profiler.stack.iter().map(|(name, _)| *name).collect();
+                let (_, nested_time) = profiler.stack.pop().unwrap();
+                *profiler.self_times.entry(stack.join(";")).or_default() += elapsed.saturating_sub(nested_time);
+                if let Some((_, parent_nested_time)) = profiler.stack.last_mut() {
+                    *parent_nested_time += elapsed;
+                }
+            });
+        }
+    }
+}
+
+#[cfg(not(feature = "profile"))]
+mod disabled {
+    /// stands in for a timed phase when profiling isn't built in
+    pub struct Span;
+
+    #[inline(always)]
+    pub const fn span(_name: &'static str) -> Span {
+        Span
+    }
+}
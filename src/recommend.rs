@@ -0,0 +1,111 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+use std::cmp::Ordering;
+
+use chrono::{Datelike, DateTime, Local, NaiveTime, Weekday};
+
+use crate::config::{RecommendationRank, RecommendationSort};
+use crate::dto::BucketValue;
+use crate::layout::BucketLayout;
+use crate::log::info;
+use crate::output::{NumberFormat, ValueGrid, ValueKind};
+
+/// an upcoming bucket worth getting online for
+pub struct Recommendation {
+    pub start_time: DateTime<Local>,
+    pub value: f64,
+    /// how many dates VRCX was active for this bucket, which is how much the value can be trusted
+    pub activity_count: usize,
+    /// the bucket's mean friend online count divided by its standard error, if it was active on enough dates to tell
+    pub reliability: Option<f64>,
+}
+
+impl Recommendation {
+    /// order by weekday (Monday first) and then time of day, for breaking ties deterministically
+    fn weekday_time_key(&self) -> (u32, NaiveTime) {
+        (self.start_time.weekday().num_days_from_monday(), self.start_time.time())
+    }
+
+    /// best first by whatever the recommendations are ranked by, with ties broken by weekday and then time
+    fn cmp_by_rank(&self, other: &Self, rank: RecommendationRank) -> Ordering {
+        let ordering = match rank {
+            RecommendationRank::Value => other.value.total_cmp(&self.value),
+            RecommendationRank::Quiet => self.value.total_cmp(&other.value),
+            RecommendationRank::Reliability => other.reliability.unwrap_or(f64::NEG_INFINITY).total_cmp(&self.reliability.unwrap_or(f64::NEG_INFINITY)),
+        };
+        ordering.then_with(|| self.weekday_time_key().cmp(&other.weekday_time_key()))
+    }
+
+    /// in `sort` order, where sorting by value lists the best first by `rank`
+    fn cmp_by(&self, other: &Self, sort: RecommendationSort, rank: RecommendationRank) -> Ordering {
+        let ordering = match sort {
+            RecommendationSort::Value if rank == RecommendationRank::Quiet => self.value.total_cmp(&other.value),
+            RecommendationSort::Value => other.value.total_cmp(&self.value),
+            RecommendationSort::Time => self.start_time.cmp(&other.start_time),
+            RecommendationSort::Confidence => other.activity_count.cmp(&self.activity_count),
+        };
+        ordering.then_with(|| self.weekday_time_key().cmp(&other.weekday_time_key()))
+    }
+}
+
+/// Rank the buckets from now until a week from now by `rank`, best first, with ties broken by weekday and then time.
+/// Buckets without data are never recommended, and neither are buckets without a reliability score when ranking by
+/// reliability. If `weekdays` is set, only buckets falling on those weekdays are considered, and if `minimum_value` is set,
+/// only buckets with at least that output value are.
+pub fn recommend_upcoming_buckets(
+    bucket_layout: &BucketLayout,
+    current_time: DateTime<Local>,
+    weekdays: Option<&[Weekday]>,
+    minimum_value: Option<f64>,
+    rank: RecommendationRank,
+    values: &ValueGrid,
+    buckets: &[Vec<BucketValue>],
+) -> Vec<Recommendation> {
+    let buckets_per_week = values.iter().map(Vec::len).sum::<usize>();
+    // the bucket we're currently in is still worth recommending, as there's time left in it
+    let mut start_time = bucket_layout.bucket_start(current_time);
+    let mut recommendations: Vec<Recommendation> = Vec::with_capacity(buckets_per_week);
+    for _ in 0..buckets_per_week {
+        let (day_index, bucket_index) = bucket_layout.position(start_time);
+        let weekday_allowed = weekdays.is_none_or(|weekdays| weekdays.iter().any(|weekday| usize::try_from(weekday.num_days_from_monday()).unwrap() == day_index));
+        if weekday_allowed {
+            if let Some(value) = values[day_index][bucket_index].filter(|value| minimum_value.is_none_or(|minimum_value| *value >= minimum_value)) {
+                let bucket_value = &buckets[day_index][bucket_index];
+                let reliability = bucket_value.online_count_reliability();
+                if rank != RecommendationRank::Reliability || reliability.is_some() {
+                    recommendations.push(Recommendation {
+                        start_time,
+                        value,
+                        activity_count: bucket_value.total_dates(),
+                        reliability,
+                    });
+                }
+            }
+        }
+        start_time = bucket_layout.next_bucket_start(start_time);
+    }
+
+    recommendations.sort_by(|a, b| a.cmp_by_rank(b, rank));
+    recommendations
+}
+
+/// Print the top recommendations to stderr. The best `recommendation_count` by `rank` are the first ones, then listed in
+/// `sort` order, with each value written in `number_format` as a `value_kind`.
+pub fn print_recommendations(recommendation_count: usize, sort: RecommendationSort, rank: RecommendationRank, number_format: NumberFormat, value_kind: ValueKind, recommendations: &[Recommendation]) {
+    if recommendations.is_empty() {
+        info!("No upcoming buckets have enough data, or a high enough value, to recommend.");
+        return;
+    }
+    let mut top_recommendations: Vec<&Recommendation> = recommendations.iter().take(recommendation_count).collect();
+    top_recommendations.sort_by(|a, b| a.cmp_by(b, sort, rank));
+    if rank == RecommendationRank::Quiet {
+        info!("Quietest upcoming times:");
+    } else {
+        info!("Best upcoming times:");
+    }
+    for recommendation in top_recommendations {
+        info!("  {}\t{}", recommendation.start_time.format("%a %Y-%m-%d %H:%M"), number_format.format(recommendation.value, value_kind));
+    }
+}
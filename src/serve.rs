@@ -0,0 +1,160 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+//! A tiny HTTP server exposing the analysis results as JSON, for dashboards and the like. It only does what it has to:
+//! one request per connection, `GET` only, and no keep-alive. The analysis is re-run for every request, so results are
+//! always as fresh as the VRCX data.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::analysis::{analyze, Settings};
+use crate::bundle::grid_json;
+use crate::error::Error;
+use crate::json::JsonValue;
+use crate::log::info;
+
+/// How long a client gets to send its request or take its response. Connections are handled one at a time, so this is
+/// also the longest one idle client can hold up everyone else.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the most a request line and its headers can add up to, so a client can't feed us an endless header
+const MAX_REQUEST_HEAD_BYTES: u64 = 16 * 1024;
+
+/// listen on `address` and answer requests until the process is killed
+pub fn serve(address: &str, settings: &Settings) -> Result<(), Error> {
+    let listener = TcpListener::bind(address)
+        .map_err(|e| Error::InvalidArguments(format!("could not listen on {address}: {e}")))?;
+    info!("Serving results at http://{address}/heatmap");
+    for stream in listener.incoming() {
+        // a misbehaving client shouldn't take the server down, and the timeouts keep it from holding the server up for long
+        if let Err(e) = stream.and_then(|stream| handle_connection(stream, settings)) {
+            info!("Error handling request: {e}");
+        }
+    }
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, settings: &Settings) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream).take(MAX_REQUEST_HEAD_BYTES);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // we don't care about any of the headers, but they need to be read before responding
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    // running out of room cuts the last line short, so it won't end in a newline
+    let head_too_long = reader.limit() == 0 && !header.ends_with('\n');
+
+    let mut request_parts = request_line.split_whitespace();
+    let method = request_parts.next().unwrap_or_default();
+    let path = request_parts.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+
+    let (status, body) = if head_too_long {
+        ("431 Request Header Fields Too Large", error_json("the request headers are too long"))
+    } else if method != "GET" {
+        ("405 Method Not Allowed", error_json("only GET is supported"))
+    } else if path != "/" && path != "/heatmap" {
+        ("404 Not Found", error_json("not found. Try /heatmap"))
+    } else {
+        match heatmap_json(settings) {
+            Ok(body) => ("200 OK", body),
+            Err(e) => ("500 Internal Server Error", error_json(&e.to_string())),
+        }
+    };
+
+    let body = body.to_string();
+    let mut stream = reader.into_inner().into_inner();
+    write!(stream, "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())?;
+    stream.flush()
+}
+
+/// Run the analysis and describe the output grid, as [`grid_json`] lays it out. If a `low_confidence_threshold` is configured,
+/// `low_confidence` is a grid of the same shape flagging shaky buckets.
+fn heatmap_json(settings: &Settings) -> Result<JsonValue, Error> {
+    let analysis = analyze(settings)?;
+    let values = settings.output_values(&analysis.buckets, analysis.comparison_buckets.as_deref());
+
+    let mut members = vec![
+        ("generated_at".to_owned(), JsonValue::String(Utc::now().to_rfc3339())),
+    ];
+    members.extend(grid_json(settings, &values));
+    if let Some(low_confidence) = settings.low_confidence_flags(&analysis.buckets, analysis.comparison_buckets.as_deref()) {
+        let low_confidence = low_confidence.into_iter()
+            .map(|flags_for_day| JsonValue::Array(flags_for_day.into_iter().map(JsonValue::Bool).collect()))
+            .collect();
+        members.push(("low_confidence".to_owned(), JsonValue::Array(low_confidence)));
+    }
+    Ok(JsonValue::Object(members))
+}
+
+fn error_json(message: &str) -> JsonValue {
+    JsonValue::Object(vec![("error".to_owned(), JsonValue::String(message.to_owned()))])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Instant;
+
+    use crate::config::Configuration;
+
+    use super::*;
+
+    /// settings that are never used to read a database, as none of these requests get as far as the analysis
+    fn settings() -> Settings {
+        let config = "your_user_id = \"usr_me\"\nvrcx_db_path = \"VRCX.sqlite3\"\nvrcx_running_detection_threshold_minutes = 30\nbucket_duration_minutes = 60\nnormalize = true";
+        Settings::from_config(Configuration::parse(config).unwrap()).unwrap()
+    }
+
+    /// send `request` over a fresh connection and handle it, keeping the connection open until the response arrives
+    fn respond(request: Vec<u8>) -> (io::Result<()>, String, Duration) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            // the server may stop reading and close before the whole request is sent
+            let _ = stream.write_all(&request);
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response);
+            response
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let start = Instant::now();
+        let result = handle_connection(stream, &settings());
+        let elapsed = start.elapsed();
+        (result, client.join().unwrap(), elapsed)
+    }
+
+    #[test]
+    fn unknown_path_is_not_found() {
+        let (result, response, _) = respond(b"GET /nope HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec());
+        assert!(result.is_ok());
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{response}");
+    }
+
+    #[test]
+    fn endless_headers_are_rejected() {
+        let mut request = b"GET /heatmap HTTP/1.1\r\n".to_vec();
+        request.extend(b"X-Padding: ".iter().chain(&[b'a'; 2 * MAX_REQUEST_HEAD_BYTES as usize]));
+        let (result, response, _) = respond(request);
+        assert!(result.is_ok());
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"), "{response}");
+    }
+
+    #[test]
+    fn idle_client_times_out() {
+        // an unfinished request, as from a client that connects and then goes quiet
+        let (result, _, elapsed) = respond(b"GET /heatmap HTTP/1.1\r\n".to_vec());
+        assert!(result.is_err());
+        assert!(elapsed < CLIENT_TIMEOUT * 2, "took {elapsed:?}");
+    }
+}
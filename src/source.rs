@@ -0,0 +1,498 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs, process};
+
+use chrono::{DateTime, Utc};
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, DropBehavior, OpenFlags, Transaction};
+
+use crate::config::SelfActivityTable;
+use crate::dto::{created_at_from_row, timestamp_from_epoch, EventTypeNames, Row};
+use crate::error::Error;
+
+/// everything we need out of VRCX to run the analysis
+pub struct EventData {
+    /// timestamps of every event VRCX recorded, in ascending order
+    pub all_event_timestamps: Vec<DateTime<Utc>>,
+    /// every friend online/offline event, in ascending order
+    pub online_offline_rows: Vec<Row>,
+    /// timestamps of the events in the `self_activity_table`, in ascending order. This is empty if no table was requested.
+    pub self_activity_timestamps: Vec<DateTime<Utc>>,
+}
+
+/// somewhere VRCX events can be read from
+pub trait EventSource {
+    fn read_events(&mut self) -> Result<EventData, Error>;
+}
+
+/// reads events directly out of the VRCX sqlite database
+pub struct SqliteSource {
+    db: Connection,
+    stripped_user_id: String,
+    print_sql: bool,
+    self_activity_table: Option<SelfActivityTable>,
+    event_type_names: EventTypeNames,
+    /// the snapshot we're reading from, if we copied the database. This is declared after `db` so the connection is
+    /// closed before the snapshot is deleted.
+    _snapshot: Option<Snapshot>,
+}
+
+impl SqliteSource {
+    /// Open the database. If `print_sql` is set, the queries we build are printed to stderr before being run. If
+    /// `self_activity_table` is set, that table's timestamps are read as well, and it must exist. Online/offline event
+    /// types are recognized with `event_type_names`.
+    ///
+    /// If `wal_snapshot_threshold_bytes` is set and the database has a write-ahead log at least that large, the database
+    /// is backed up to a temporary directory, log and all, and the copy is read instead. A large log means VRCX hasn't
+    /// checkpointed in a while, and reading alongside it while VRCX is writing can block or see a stale snapshot.
+    pub fn open(vrcx_db_path: &str, your_user_id: &str, print_sql: bool, wal_snapshot_threshold_bytes: Option<u64>, self_activity_table: Option<SelfActivityTable>, event_type_names: EventTypeNames) -> Result<Self, Error> {
+        // sqlite's own error for a missing file is rather unhelpful, so check for it ourselves
+        if !Path::new(vrcx_db_path).is_file() {
+            return Err(Error::DatabaseNotFound(format!("no file exists at {vrcx_db_path}")));
+        }
+        let wal_path = format!("{vrcx_db_path}-wal");
+        let wal_size = fs::metadata(&wal_path).map_or(0, |metadata| metadata.len());
+        let snapshot = match wal_snapshot_threshold_bytes {
+            Some(threshold) if wal_size != 0 && wal_size >= threshold => Some(Snapshot::copy(vrcx_db_path)?),
+            _ => None,
+        };
+        let db = match &snapshot {
+            Some(snapshot) => Connection::open_with_flags(
+                &snapshot.db_path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX),
+            None => Connection::open_with_flags(
+                vrcx_db_path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_URI
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX),
+        }.map_err(|e| Error::DatabaseNotFound(format!("{vrcx_db_path}: {e}")))?;
+        let stripped_user_id = your_user_id.replace(['-', '_'], "");
+        Ok(Self {
+            db,
+            stripped_user_id,
+            print_sql,
+            self_activity_table,
+            event_type_names,
+            _snapshot: snapshot,
+        })
+    }
+}
+
+/// how many snapshots this process has taken, so each gets its own directory
+static SNAPSHOT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// a private copy of a database, deleted when dropped
+struct Snapshot {
+    directory: PathBuf,
+    db_path: PathBuf,
+}
+
+impl Snapshot {
+    /// Copy a database, including everything still in its write-ahead log, with sqlite's online backup. The whole copy is
+    /// made in a single step, under one read transaction, so it's consistent even if VRCX writes or checkpoints meanwhile.
+    fn copy(vrcx_db_path: &str) -> Result<Self, Error> {
+        // several databases can be open at once, so each snapshot needs a directory of its own
+        let directory = env::temp_dir().join(format!("vrcx-optimal-time-{}-{}", process::id(), SNAPSHOT_COUNT.fetch_add(1, Ordering::Relaxed)));
+        let db_path = directory.join("VRCX.sqlite3");
+        let copy_error = |e: &dyn std::fmt::Display| Error::DatabaseNotFound(format!("could not copy {vrcx_db_path} to a temporary directory: {e}"));
+        fs::create_dir_all(&directory).map_err(|e| copy_error(&e))?;
+        // create the snapshot first, so the directory is cleaned up even if the copy fails partway
+        let snapshot = Self {
+            db_path,
+            directory,
+        };
+        let source = Connection::open_with_flags(
+            vrcx_db_path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        ).map_err(|e| copy_error(&e))?;
+        let mut destination = Connection::open(&snapshot.db_path).map_err(|e| copy_error(&e))?;
+        // a negative page count copies every page in one step
+        let step_result = Backup::new(&source, &mut destination)
+            .and_then(|backup| backup.step(-1))
+            .map_err(|e| copy_error(&e))?;
+        match step_result {
+            StepResult::Done => Ok(snapshot),
+            _ => Err(copy_error(&"the database was locked")),
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        // nothing useful can be done if this fails, and it's only a temp directory
+        let _ = fs::remove_dir_all(&self.directory);
+    }
+}
+
+impl EventSource for SqliteSource {
+    fn read_events(&mut self) -> Result<EventData, Error> {
+        let stripped_user_id = &self.stripped_user_id;
+        let print_sql = self.print_sql;
+        let event_type_names = &self.event_type_names;
+
+        // run a big transactional read
+        let mut transaction = self.db.transaction().map_err(database_error)?;
+        transaction.set_drop_behavior(DropBehavior::Commit);
+
+        // figure out which of the tables we know about this database actually has
+        let schema = Schema::detect(&transaction, stripped_user_id, self.self_activity_table)?;
+
+        // build and run the all events query
+        let all_events_statement = schema.event_tables.iter()
+            .map(|table| format!("select created_at from {table}"))
+            .collect::<Vec<_>>()
+            .join(" union ");
+        let all_events_statement = format!("{all_events_statement} order by created_at asc;");
+        if print_sql {
+            eprintln!("{all_events_statement}");
+        }
+        let mut all_event_timestamps: Vec<DateTime<Utc>> = {
+            let mut all_events_statement = transaction.prepare(&all_events_statement).map_err(prepare_error)?;
+            let all_event_timestamps = all_events_statement.query_map((), created_at_from_row).map_err(database_error)?;
+            all_event_timestamps
+                .collect::<Result<_, _>>()
+                .map_err(database_error)?
+        };
+        // the same text-versus-time ordering problem as the online/offline rows below applies here
+        all_event_timestamps.sort_unstable();
+
+        // build and run the online/offline query. Rows with identical timestamps are kept in the order VRCX inserted them, as
+        // otherwise an Offline could be paired with the Online that actually followed it.
+        let online_offline_table = &schema.online_offline_table;
+        let online_offline_statement = format!("select created_at, user_id, display_name, type from {online_offline_table} order by created_at asc, rowid asc");
+        if print_sql {
+            eprintln!("{online_offline_statement}");
+        }
+        let mut online_offline_rows: Vec<Row> = {
+            let mut online_offline_statement = transaction.prepare(&online_offline_statement).map_err(prepare_error)?;
+            let user_online_offline_events = online_offline_statement.query_map((), |row| Row::from_sql_row(row, event_type_names)).map_err(database_error)?;
+            user_online_offline_events
+                .collect::<Result<_, _>>()
+                .map_err(database_error)?
+        };
+        // sqlite sorts created_at as text, which doesn't always match time order: "10:00:00.5Z" sorts before "10:00:00Z".
+        // Re-sort on the parsed timestamps so sessions never come out negative. This is stable, so the rowid tie-break
+        // survives, and nearly free as the rows are almost always already in order.
+        online_offline_rows.sort_by_key(|row| row.created_at);
+
+        let self_activity_timestamps: Vec<DateTime<Utc>> = match &schema.self_activity_table {
+            Some(self_activity_table) => {
+                let self_activity_statement = format!("select created_at from {self_activity_table} order by created_at asc");
+                if print_sql {
+                    eprintln!("{self_activity_statement}");
+                }
+                let mut self_activity_statement = transaction.prepare(&self_activity_statement).map_err(prepare_error)?;
+                let self_activity_timestamps = self_activity_statement.query_map((), created_at_from_row).map_err(database_error)?;
+                let mut self_activity_timestamps: Vec<DateTime<Utc>> = self_activity_timestamps
+                    .collect::<Result<_, _>>()
+                    .map_err(database_error)?;
+                // same text-versus-time ordering problem as the all events query
+                self_activity_timestamps.sort_unstable();
+                self_activity_timestamps
+            }
+            None => Vec::new(),
+        };
+
+        Ok(EventData {
+            all_event_timestamps,
+            online_offline_rows,
+            self_activity_timestamps,
+        })
+    }
+}
+
+/// every per-user table with a `created_at` column that counts as VRCX activity
+const EVENT_TABLE_SUFFIXES: [&str; 5] = ["feed_avatar", "feed_gps", "feed_online_offline", "feed_status", "friend_log_history"];
+const ONLINE_OFFLINE_TABLE_SUFFIX: &str = "feed_online_offline";
+/// the columns we read from the online/offline table
+const ONLINE_OFFLINE_COLUMNS: [&str; 4] = ["created_at", "user_id", "display_name", "type"];
+
+/// the parts of the VRCX schema present in a database
+struct Schema {
+    /// tables to pull activity timestamps from
+    event_tables: Vec<String>,
+    online_offline_table: String,
+    /// the table to count events from instead of friend sessions, if one was requested
+    self_activity_table: Option<String>,
+}
+
+impl Schema {
+    /// Inspect the database to find which of our tables exist. Not every VRCX version has every table, so activity
+    /// tables that are missing are skipped, but the online/offline table is required and must have the columns we read.
+    /// A requested `self_activity_table` is also required.
+    fn detect(transaction: &Transaction, stripped_user_id: &str, self_activity_table: Option<SelfActivityTable>) -> Result<Self, Error> {
+        let tables: HashSet<String> = {
+            let mut statement = transaction.prepare("select name from sqlite_master where type = 'table'").map_err(database_error)?;
+            let tables = statement.query_map((), |row| row.get(0)).map_err(database_error)?;
+            tables.collect::<Result<_, _>>().map_err(database_error)?
+        };
+
+        let online_offline_table = format!("{stripped_user_id}_{ONLINE_OFFLINE_TABLE_SUFFIX}");
+        if !tables.contains(&online_offline_table) {
+            return Err(Error::TablesMissing(format!("no {online_offline_table} table")));
+        }
+
+        let columns: HashSet<String> = {
+            let mut statement = transaction.prepare("select name from pragma_table_info(?1)").map_err(database_error)?;
+            let columns = statement.query_map([&online_offline_table], |row| row.get(0)).map_err(database_error)?;
+            columns.collect::<Result<_, _>>().map_err(database_error)?
+        };
+        let missing_columns: Vec<&str> = ONLINE_OFFLINE_COLUMNS.into_iter()
+            .filter(|column| !columns.contains(*column))
+            .collect();
+        if !missing_columns.is_empty() {
+            let mut columns: Vec<String> = columns.into_iter().collect();
+            columns.sort_unstable();
+            return Err(Error::UnexpectedSchema(format!("{online_offline_table} is missing the {} column(s). It has: {}", missing_columns.join(", "), columns.join(", "))));
+        }
+
+        let self_activity_table = self_activity_table
+            .map(|self_activity_table| {
+                let table = format!("{stripped_user_id}_{}", self_activity_table.table_suffix());
+                if tables.contains(&table) {
+                    Ok(table)
+                } else {
+                    Err(Error::TablesMissing(format!("no {table} table for self_activity_table")))
+                }
+            })
+            .transpose()?;
+
+        let event_tables = EVENT_TABLE_SUFFIXES.into_iter()
+            .map(|suffix| format!("{stripped_user_id}_{suffix}"))
+            .filter(|table| tables.contains(table))
+            .collect();
+
+        Ok(Self {
+            event_tables,
+            online_offline_table,
+            self_activity_table,
+        })
+    }
+}
+
+/// Map an error preparing one of our statements. The queries are fixed apart from the table names, so a failure here almost
+/// always means the tables for this user id don't exist.
+fn prepare_error(e: rusqlite::Error) -> Error {
+    Error::TablesMissing(e.to_string())
+}
+
+fn database_error(e: rusqlite::Error) -> Error {
+    Error::Input(e.to_string())
+}
+
+/// Reads events from CSV exports. The online/offline file needs `created_at`, `user_id`, `display_name`, and `type` columns,
+/// and the optional all-events file needs a `created_at` column. If no all-events file is available, the online/offline
+/// timestamps are used instead.
+pub struct CsvSource {
+    online_offline_path: String,
+    all_events_path: Option<String>,
+    event_type_names: EventTypeNames,
+}
+
+impl CsvSource {
+    pub const fn new(online_offline_path: String, all_events_path: Option<String>, event_type_names: EventTypeNames) -> Self {
+        Self {
+            online_offline_path,
+            all_events_path,
+            event_type_names,
+        }
+    }
+}
+
+impl EventSource for CsvSource {
+    fn read_events(&mut self) -> Result<EventData, Error> {
+        let online_offline_csv = CsvTable::read(&self.online_offline_path)?;
+        let created_at_column = online_offline_csv.column_index("created_at")?;
+        let user_id_column = online_offline_csv.column_index("user_id")?;
+        let display_name_column = online_offline_csv.column_index("display_name")?;
+        let type_column = online_offline_csv.column_index("type")?;
+        let mut online_offline_rows: Vec<Row> = online_offline_csv.records.into_iter()
+            .map(|mut record| {
+                let event_type = self.event_type_names.event_type(&record[type_column])
+                    .ok_or_else(|| Error::Input(format!("unrecognized online/offline event type \"{}\" in {}", record[type_column], self.online_offline_path)))?;
+                Ok(Row {
+                    created_at: parse_csv_timestamp(&record[created_at_column], &self.online_offline_path)?,
+                    user_id: std::mem::take(&mut record[user_id_column]),
+                    display_name: std::mem::take(&mut record[display_name_column]),
+                    event_type,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        // stable sort, so rows with equal timestamps keep their file order
+        online_offline_rows.sort_by_key(|row| row.created_at);
+
+        let mut all_event_timestamps: Vec<DateTime<Utc>> = if let Some(all_events_path) = &self.all_events_path {
+            let all_events_csv = CsvTable::read(all_events_path)?;
+            let created_at_column = all_events_csv.column_index("created_at")?;
+            all_events_csv.records.iter()
+                .map(|record| parse_csv_timestamp(&record[created_at_column], all_events_path))
+                .collect::<Result<_, Error>>()?
+        } else {
+            online_offline_rows.iter().map(|row| row.created_at).collect()
+        };
+        // match the semantics of the sqlite `union ... order by` query
+        all_event_timestamps.sort_unstable();
+        all_event_timestamps.dedup();
+
+        Ok(EventData {
+            all_event_timestamps,
+            online_offline_rows,
+            self_activity_timestamps: Vec::new(),
+        })
+    }
+}
+
+/// Parse a CSV `created_at`. Like the database, this is either an RFC 3339 time or whole epoch seconds or milliseconds.
+fn parse_csv_timestamp(created_at: &str, path: &str) -> Result<DateTime<Utc>, Error> {
+    let invalid = |message: &dyn std::fmt::Display| Error::Input(format!("invalid created_at \"{created_at}\" in {path}: {message}"));
+    if !created_at.is_empty() && created_at.bytes().all(|byte| byte.is_ascii_digit()) {
+        created_at.parse::<i64>().ok()
+            .and_then(timestamp_from_epoch)
+            .ok_or_else(|| invalid(&"epoch time out of range"))
+    } else {
+        created_at.parse::<DateTime<Utc>>().map_err(|e| invalid(&e))
+    }
+}
+
+/// a parsed CSV file with a header row
+struct CsvTable {
+    path: String,
+    header: Vec<String>,
+    records: Vec<Vec<String>>,
+}
+
+impl CsvTable {
+    fn read(path: &str) -> Result<Self, Error> {
+        let text = fs::read_to_string(path).map_err(|e| Error::DatabaseNotFound(format!("{path}: {e}")))?;
+        // Excel and other Windows tools like to start UTF-8 files with a byte order mark, which would end up in the first
+        // column name
+        let text = text.strip_prefix('\u{feff}').unwrap_or(&text);
+        let mut records = parse_csv(text).into_iter();
+        let header = records.next().ok_or_else(|| Error::Input(format!("{path} is missing a header row")))?;
+        let records: Vec<Vec<String>> = records.collect();
+        for (index, record) in records.iter().enumerate() {
+            if record.len() != header.len() {
+                // header is line 1, so the first record is line 2 (assuming no embedded newlines)
+                return Err(Error::Input(format!("record {} in {path} has {} fields, but the header has {}", index + 2, record.len(), header.len())));
+            }
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            header,
+            records,
+        })
+    }
+
+    fn column_index(&self, name: &str) -> Result<usize, Error> {
+        self.header.iter().position(|column| column == name)
+            .ok_or_else(|| Error::Input(format!("{} is missing a \"{name}\" column", self.path)))
+    }
+}
+
+/// Parse RFC 4180-style CSV text into records. Fields may be quoted, and quoted fields may contain commas, newlines, and
+/// doubled quotes. Blank lines are skipped.
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut records: Vec<Vec<String>> = Vec::new();
+    let mut record: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                _ => field.push(c),
+            }
+        } else {
+            match c {
+                '"' => in_quotes = true,
+                ',' => record.push(std::mem::take(&mut field)),
+                '\r' => {} // tolerate CRLF line endings
+                '\n' => {
+                    if !record.is_empty() || !field.is_empty() {
+                        record.push(std::mem::take(&mut field));
+                        records.push(std::mem::take(&mut record));
+                    }
+                }
+                _ => field.push(c),
+            }
+        }
+    }
+
+    // handle a final record with no trailing newline
+    if !record.is_empty() || !field.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter().map(|row| row.iter().map(|field| (*field).to_owned()).collect()).collect()
+    }
+
+    #[test]
+    fn parse_csv_plain_fields() {
+        assert_eq!(parse_csv("a,b,c\n1,2,3\n"), records(&[&["a", "b", "c"], &["1", "2", "3"]]));
+        // empty fields are kept
+        assert_eq!(parse_csv("a,,c\n,,\n"), records(&[&["a", "", "c"], &["", "", ""]]));
+    }
+
+    #[test]
+    fn parse_csv_quoted_fields() {
+        assert_eq!(parse_csv("\"a,b\",c\n"), records(&[&["a,b", "c"]]));
+        assert_eq!(parse_csv("\"say \"\"hi\"\"\",\"\"\"\"\n"), records(&[&["say \"hi\"", "\""]]));
+        assert_eq!(parse_csv("\"line 1\nline 2\",x\ny,z\n"), records(&[&["line 1\nline 2", "x"], &["y", "z"]]));
+        // CRLF inside quotes is part of the field
+        assert_eq!(parse_csv("\"a\r\nb\"\r\n"), records(&[&["a\r\nb"]]));
+    }
+
+    #[test]
+    fn parse_csv_line_endings() {
+        assert_eq!(parse_csv("a,b\r\n1,2\r\n"), records(&[&["a", "b"], &["1", "2"]]));
+        assert_eq!(parse_csv("\na,b\n\n\r\n1,2\n\n"), records(&[&["a", "b"], &["1", "2"]]));
+        assert_eq!(parse_csv("a,b\n1,2"), records(&[&["a", "b"], &["1", "2"]]));
+        assert_eq!(parse_csv("a,b\n1,"), records(&[&["a", "b"], &["1", ""]]));
+        assert_eq!(parse_csv(""), records(&[]));
+    }
+
+    #[test]
+    fn csv_timestamps_in_every_format() {
+        let expected = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        for created_at in ["2024-01-01T00:00:00Z", "2024-01-01T00:00:00.000Z", "2024-01-01T01:00:00+01:00", "1704067200", "1704067200000"] {
+            assert_eq!(parse_csv_timestamp(created_at, "test.csv").unwrap(), expected, "{created_at}");
+        }
+        for created_at in ["", "-1704067200", "17040672OO", "99999999999999999999"] {
+            assert!(parse_csv_timestamp(created_at, "test.csv").is_err(), "{created_at}");
+        }
+    }
+
+    #[test]
+    fn csv_table_skips_byte_order_mark() {
+        let path = env::temp_dir().join(format!("vrcx-optimal-time-test-{}-bom.csv", process::id()));
+        fs::write(&path, "\u{feff}created_at,user_id\r\n2024-01-01T00:00:00Z,usr_a\r\n").unwrap();
+        let table = CsvTable::read(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let table = table.unwrap();
+        assert_eq!(table.column_index("created_at").unwrap(), 0);
+        assert_eq!(table.records, records(&[&["2024-01-01T00:00:00Z", "usr_a"]]));
+    }
+}
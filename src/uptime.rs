@@ -0,0 +1,248 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::dto::{TimeSpan, VrcxStartStopEvent, VrcxStartStopEventType};
+
+/// how many sessions took each path through [`VrcxUptime::clamp_range`], plus how many were discarded before getting there
+#[derive(Default)]
+pub struct ClampStats {
+    /// the session was longer than `maximum_session_hours`, so it was discarded without being clamped
+    pub too_long: usize,
+    /// VRCX was running for the entire session
+    pub full_overlap: usize,
+    /// VRCX started partway through the session, so its start was clamped
+    pub front_clamp: usize,
+    /// VRCX stopped partway through the session, so its end was clamped
+    pub tail_clamp: usize,
+    /// the session spanned a gap in VRCX uptime, so it was split into several ranges
+    pub split: usize,
+    /// neither end of the session fell within VRCX uptime, so it was dropped
+    pub dropped: usize,
+    /// the total length of the dropped sessions
+    pub dropped_duration: Duration,
+    /// the session had no online event, so its start was inferred from when VRCX started
+    pub inferred_start: usize,
+}
+
+impl ClampStats {
+    /// how many sessions made it to [`VrcxUptime::clamp_range`], whether or not they survived it
+    pub const fn clamped_session_count(&self) -> usize {
+        self.full_overlap + self.front_clamp + self.tail_clamp + self.split + self.dropped
+    }
+}
+
+/// the time ranges VRCX is believed to have been running during, derived from the VRCX start/stop events
+pub struct VrcxUptime {
+    /// non-overlapping, positive-duration intervals in ascending order
+    intervals: Vec<TimeSpan>,
+}
+
+impl VrcxUptime {
+    /// pair up alternating start/stop events into uptime intervals
+    pub fn from_events(vrcx_start_stop_events: &[VrcxStartStopEvent]) -> Self {
+        let mut intervals: Vec<TimeSpan> = Vec::with_capacity(vrcx_start_stop_events.len() / 2);
+        let mut start = None;
+        for event in vrcx_start_stop_events {
+            match event.event {
+                VrcxStartStopEventType::Start => {
+                    debug_assert!(start.is_none(), "two VRCX start events in a row");
+                    start = Some(event.timestamp);
+                }
+                VrcxStartStopEventType::Stop => {
+                    let start = start.take().expect("VRCX stop event without a preceding start event");
+                    let interval = TimeSpan::new(start, event.timestamp);
+                    if !interval.is_negative_or_zero() {
+                        intervals.push(interval);
+                    }
+                }
+            }
+        }
+        debug_assert!(start.is_none(), "VRCX start event without a following stop event");
+        Self {
+            intervals,
+        }
+    }
+
+    /// Combine the uptime of several machines, so VRCX counts as running whenever it was running on any of them.
+    /// Overlapping and touching intervals are merged.
+    pub fn union(uptimes: Vec<VrcxUptime>) -> Self {
+        let mut all_intervals: Vec<TimeSpan> = uptimes.into_iter().flat_map(|uptime| uptime.intervals).collect();
+        all_intervals.sort_by_key(|interval| interval.start);
+        let mut intervals: Vec<TimeSpan> = Vec::with_capacity(all_intervals.len());
+        for interval in all_intervals {
+            match intervals.last_mut() {
+                Some(last) if interval.start <= last.stop => last.stop = last.stop.max(interval.stop),
+                _ => intervals.push(interval),
+            }
+        }
+        Self {
+            intervals,
+        }
+    }
+
+    pub fn intervals(&self) -> &[TimeSpan] {
+        &self.intervals
+    }
+
+    /// Clamps a time range to when VRCX was running.
+    /// If VRCX was running for the entire range, returns the input range.
+    /// Otherwise, returns the sub-ranges during which VRCX was known to be running.
+    /// If neither end of the range falls within VRCX uptime we have no idea what happened, so this returns Err.
+    /// The outcome is tallied in `clamp_stats`.
+    pub fn clamp_range(&self, time_span: TimeSpan, clamp_stats: &mut ClampStats) -> Result<Vec<TimeSpan>, ()> {
+        let overlapping = self.overlapping_intervals(time_span);
+
+        // [..., vrcx_stop, event_start, ..., event_stop, vrcx_start, ...]
+        let starts_during_uptime = overlapping.first().is_some_and(|interval| interval.start <= time_span.start);
+        let stops_during_uptime = overlapping.last().is_some_and(|interval| time_span.stop <= interval.stop);
+        if !starts_during_uptime && !stops_during_uptime {
+            clamp_stats.dropped += 1;
+            clamp_stats.dropped_duration += time_span.duration();
+            return Err(());
+        }
+
+        if overlapping.len() > 1 {
+            clamp_stats.split += 1;
+        } else if !starts_during_uptime {
+            clamp_stats.front_clamp += 1;
+        } else if !stops_during_uptime {
+            clamp_stats.tail_clamp += 1;
+        } else {
+            clamp_stats.full_overlap += 1;
+        }
+
+        Ok(overlapping.iter()
+            .map(|interval| TimeSpan::new(interval.start.max(time_span.start), interval.stop.min(time_span.stop)))
+            .collect())
+    }
+
+    /// Cross-check [`Self::clamp_range`] against a brute-force scan of every uptime interval, panicking with the span and
+    /// the nearby uptime intervals if they disagree. This is slow, and only meant for `--self-check`.
+    pub fn self_check_clamp_range(&self, time_span: TimeSpan) {
+        let clamped = self.clamp_range(time_span, &mut ClampStats::default()).ok();
+        let brute_force = self.brute_force_clamp_range(time_span);
+        if clamped != brute_force {
+            let describe = |ranges: &Option<Vec<TimeSpan>>| match ranges {
+                Some(ranges) => ranges.iter().map(TimeSpan::to_string).collect::<Vec<_>>().join(", "),
+                None => "dropped".to_owned(),
+            };
+            // include one interval to either side, so gaps at the edges of the span are visible too
+            let first = self.intervals.partition_point(|interval| interval.stop < time_span.start).saturating_sub(1);
+            let last = (self.intervals.partition_point(|interval| interval.start <= time_span.stop) + 1).min(self.intervals.len());
+            let window = self.intervals[first..last.max(first)].iter().map(TimeSpan::to_string).collect::<Vec<_>>().join("\n  ");
+            panic!("Self-check failed for session {time_span}.\nClamped to: {}\nBrute force gave: {}\nNearby VRCX uptime:\n  {window}", describe(&clamped), describe(&brute_force));
+        }
+    }
+
+    /// the obviously-correct O(n) version of [`Self::clamp_range`], without the stats
+    fn brute_force_clamp_range(&self, time_span: TimeSpan) -> Option<Vec<TimeSpan>> {
+        let mut starts_during_uptime = false;
+        let mut stops_during_uptime = false;
+        let mut ranges: Vec<TimeSpan> = Vec::new();
+        for interval in &self.intervals {
+            let intersection = TimeSpan::new(interval.start.max(time_span.start), interval.stop.min(time_span.stop));
+            if intersection.is_negative_or_zero() {
+                continue;
+            }
+            starts_during_uptime |= interval.start <= time_span.start;
+            stops_during_uptime |= time_span.stop <= interval.stop;
+            ranges.push(intersection);
+        }
+        (starts_during_uptime || stops_during_uptime).then_some(ranges)
+    }
+
+    /// whether VRCX was running at an instant, counting the ends of each interval
+    pub fn contains(&self, timestamp: DateTime<Utc>) -> bool {
+        let index = self.intervals.partition_point(|interval| interval.stop < timestamp);
+        self.intervals.get(index).is_some_and(|interval| interval.start <= timestamp)
+    }
+
+    /// when the uptime interval containing a timestamp started, if VRCX was running at the time
+    pub fn interval_start(&self, timestamp: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let index = self.intervals.partition_point(|interval| interval.stop < timestamp);
+        self.intervals.get(index).filter(|interval| interval.start <= timestamp).map(|interval| interval.start)
+    }
+
+    /// the uptime intervals that overlap a time range by a positive duration
+    fn overlapping_intervals(&self, time_span: TimeSpan) -> &[TimeSpan] {
+        // first interval that ends after the range starts
+        let first = self.intervals.partition_point(|interval| interval.stop <= time_span.start);
+        // first interval that starts at or after the range stops
+        let last = self.intervals.partition_point(|interval| interval.start < time_span.stop);
+        &self.intervals[first..last.max(first)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    /// xorshift64*, which is plenty random for generating test cases while keeping any failure reproducible
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        /// a number from 0 up to but excluding `bound`
+        fn below(&mut self, bound: u64) -> i64 {
+            i64::try_from(self.next() % bound).unwrap()
+        }
+    }
+
+    /// Random uptime for up to three machines. Times are whole minutes in a short window, so sessions often start or stop
+    /// exactly on an interval boundary, and machines' intervals often overlap or touch.
+    fn random_uptime(rng: &mut Rng, minute: impl Fn(i64) -> DateTime<Utc>) -> VrcxUptime {
+        let machine_count = 1 + rng.below(3);
+        let uptimes = (0..machine_count)
+            .map(|_| {
+                let mut timestamps: Vec<i64> = (0..2 * rng.below(8)).map(|_| rng.below(600)).collect();
+                timestamps.sort_unstable();
+                let events: Vec<VrcxStartStopEvent> = timestamps.chunks(2)
+                    .flat_map(|pair| [VrcxStartStopEvent::start(minute(pair[0])), VrcxStartStopEvent::stop(minute(pair[1]))])
+                    .collect();
+                VrcxUptime::from_events(&events)
+            })
+            .collect();
+        VrcxUptime::union(uptimes)
+    }
+
+    #[test]
+    fn clamp_range_matches_brute_force() {
+        let base = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let minute = |minutes: i64| base + Duration::minutes(minutes);
+        let describe = |ranges: &Option<Vec<TimeSpan>>| match ranges {
+            Some(ranges) => ranges.iter().map(TimeSpan::to_string).collect::<Vec<_>>().join(", "),
+            None => "dropped".to_owned(),
+        };
+        let mut rng = Rng(0x5eed_1234_abcd_0001);
+        for case in 0..2000 {
+            let uptime = random_uptime(&mut rng, minute);
+            assert!(uptime.intervals.windows(2).all(|pair| pair[0].stop < pair[1].start), "case {case}: union left overlapping or touching intervals");
+            for _ in 0..20 {
+                // sessions are only ever clamped if they last a positive time
+                let start = rng.below(650) - 25;
+                let time_span = TimeSpan::new(minute(start), minute(start + 1 + rng.below(200)));
+                let mut clamp_stats = ClampStats::default();
+                let clamped = uptime.clamp_range(time_span, &mut clamp_stats).ok();
+                let brute_force = uptime.brute_force_clamp_range(time_span);
+                assert!(clamped == brute_force, "case {case}: {time_span} was clamped to {}, but brute force gave {}", describe(&clamped), describe(&brute_force));
+                assert_eq!(clamp_stats.clamped_session_count(), 1, "case {case}: {time_span} was tallied more than once");
+                if let Some(ranges) = clamped {
+                    // every range must be usable as is, or the bucketing reports an anomaly
+                    assert!(ranges.iter().all(|range| !range.is_negative_or_zero() && time_span.start <= range.start && range.stop <= time_span.stop), "case {case}: {time_span} was clamped to {}", describe(&Some(ranges.clone())));
+                    assert!(ranges.windows(2).all(|pair| pair[0].stop < pair[1].start), "case {case}: {time_span} was clamped to overlapping ranges");
+                }
+            }
+        }
+    }
+}
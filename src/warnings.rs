@@ -0,0 +1,87 @@
+// Copyright 2024 Michael Ripley
+// This file is part of vrcx-optimal-time.
+// vrcx-optimal-time is licensed under the MIT license (see LICENSE file for details).
+
+//! Warnings about anything questionable in a run, collected as they come up and printed together at the end so they don't
+//! get lost among the statistics. With `warnings_format = "json"` they're printed as one JSON object instead, for scripts.
+
+use crate::config::WarningsFormat;
+use crate::json::JsonValue;
+use crate::log::info;
+
+/// what a warning is about, so scripts can pick out the ones they care about without parsing the message
+#[derive(Clone, Copy)]
+pub enum WarningKind {
+    /// a friend filter matched none of the online/offline events
+    UnmatchedFriendIds,
+    /// friend_ids display names that couldn't be turned into a single user id
+    FriendNames,
+    /// events are dated in the future
+    FutureEvents,
+    /// vrcx_running_detection_threshold_minutes is shorter than the typical gap between events
+    LowDetectionThreshold,
+    /// a large share of friend sessions fell outside VRCX uptime
+    DroppedSessions,
+    /// buckets active on a single date are being normalized
+    SingleActivationBuckets,
+    /// weekdays without any recent VRCX activity
+    StaleWeekdays,
+    /// data that made no sense was skipped
+    DataAnomalies,
+    /// no bucket has any data at all
+    EmptyGrid,
+}
+
+impl WarningKind {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::UnmatchedFriendIds => "unmatched_friend_ids",
+            Self::FriendNames => "friend_names",
+            Self::FutureEvents => "future_events",
+            Self::LowDetectionThreshold => "low_detection_threshold",
+            Self::DroppedSessions => "dropped_sessions",
+            Self::SingleActivationBuckets => "single_activation_buckets",
+            Self::StaleWeekdays => "stale_weekdays",
+            Self::DataAnomalies => "data_anomalies",
+            Self::EmptyGrid => "empty_grid",
+        }
+    }
+}
+
+struct Warning {
+    kind: WarningKind,
+    message: String,
+}
+
+/// the warnings raised so far, in the order they were raised
+#[derive(Default)]
+pub struct Warnings {
+    warnings: Vec<Warning>,
+}
+
+impl Warnings {
+    pub fn push(&mut self, kind: WarningKind, message: String) {
+        self.warnings.push(Warning { kind, message });
+    }
+
+    /// Print every warning to stderr. Text output prints nothing if there were no warnings, but JSON output always prints
+    /// an object, so scripts can tell a clean run from one where warnings weren't printed.
+    pub fn print(&self, format: WarningsFormat) {
+        match format {
+            WarningsFormat::Text => {
+                for warning in &self.warnings {
+                    info!("Warning: {}", warning.message);
+                }
+            }
+            WarningsFormat::Json => {
+                let warnings = self.warnings.iter()
+                    .map(|warning| JsonValue::Object(vec![
+                        ("kind".to_owned(), JsonValue::String(warning.kind.name().to_owned())),
+                        ("message".to_owned(), JsonValue::String(warning.message.clone())),
+                    ]))
+                    .collect();
+                info!("{}", JsonValue::Object(vec![("warnings".to_owned(), JsonValue::Array(warnings))]));
+            }
+        }
+    }
+}